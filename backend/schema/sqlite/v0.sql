@@ -0,0 +1,51 @@
+-- Users, with relays and federation ids as JSON arrays
+CREATE TABLE IF NOT EXISTS users (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  name TEXT NOT NULL UNIQUE,
+  replit_id TEXT NOT NULL UNIQUE,
+  replit_profile_pic TEXT NOT NULL,
+  pubkey TEXT NOT NULL UNIQUE,
+  last_tweak INTEGER NOT NULL,
+  relays TEXT NOT NULL,
+  federation_ids TEXT NOT NULL,
+  connection_code_uuid TEXT NOT NULL,
+  success_action TEXT
+);
+-- Invoices, timestamps as fixed width RFC 3339 text in UTC
+CREATE TABLE IF NOT EXISTS invoices (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  federation_id TEXT NOT NULL,
+  op_id TEXT NOT NULL UNIQUE,
+  user_id INTEGER NOT NULL REFERENCES users(id),
+  user_pubkey TEXT NOT NULL,
+  bolt11 TEXT NOT NULL,
+  amount INTEGER NOT NULL,
+  state INTEGER NOT NULL,
+  tweak INTEGER NOT NULL,
+  nonce TEXT,
+  created_at TEXT NOT NULL,
+  fiat_amount INTEGER,
+  fiat_currency TEXT,
+  fiat_rate REAL,
+  preimage TEXT,
+  claimed_at TEXT,
+  comment TEXT,
+  payer TEXT,
+  settled_at TEXT,
+  payment_hash TEXT,
+  stage INTEGER
+);
+CREATE INDEX IF NOT EXISTS idx_invoice_state ON invoices(state);
+CREATE INDEX IF NOT EXISTS idx_invoice_user_id ON invoices(user_id);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_invoice_pending_nonce ON invoices(user_id, amount, nonce) WHERE nonce IS NOT NULL AND state = 0;
+CREATE INDEX IF NOT EXISTS idx_invoice_unclaimed ON invoices(user_pubkey) WHERE state = 1 AND claimed_at IS NULL;
+CREATE INDEX IF NOT EXISTS idx_invoice_payment_hash ON invoices(payment_hash);
+-- Receive stages per invoice
+CREATE TABLE IF NOT EXISTS invoice_events (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  invoice_id INTEGER NOT NULL REFERENCES invoices(id),
+  stage INTEGER NOT NULL,
+  reason TEXT,
+  created_at TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS idx_invoice_event_invoice_id ON invoice_events(invoice_id)
@@ -0,0 +1,9 @@
+-- replit_id is a string everywhere the backend reads or writes it. Only
+-- retyped once, so later startups don't rewrite the table again.
+DO $$
+BEGIN
+    IF (SELECT data_type FROM information_schema.columns
+        WHERE table_name = 'users' AND column_name = 'replit_id') <> 'character varying' THEN
+        ALTER TABLE users ALTER COLUMN replit_id TYPE VARCHAR(255);
+    END IF;
+END $$
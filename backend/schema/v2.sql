@@ -0,0 +1,5 @@
+-- Track the wallet supplied nonce (or zap request id) of each callback
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS nonce VARCHAR(255);
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT now();
+-- At most one pending invoice per (user, amount, nonce)
+CREATE UNIQUE INDEX IF NOT EXISTS idx_invoice_pending_nonce ON invoices(user_id, amount, nonce) WHERE nonce IS NOT NULL AND state = 0;
@@ -0,0 +1,4 @@
+-- Fiat denominated invoices, with the rate locked at creation
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS fiat_amount BIGINT;
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS fiat_currency VARCHAR(8);
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS fiat_rate DOUBLE PRECISION;
@@ -0,0 +1,3 @@
+-- Per user LUD-09 success action, and the invoice preimage LUD-10 aes actions are encrypted with
+ALTER TABLE users ADD COLUMN IF NOT EXISTS success_action JSONB;
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS preimage VARCHAR(64);
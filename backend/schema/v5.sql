@@ -0,0 +1,17 @@
+-- Single use LNURL-withdraw links paid out of the server's ecash
+CREATE TABLE IF NOT EXISTS withdrawals (
+    id VARCHAR(36) PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    federation_id VARCHAR(255) NOT NULL,
+    k1 VARCHAR(64) NOT NULL,
+    description TEXT NOT NULL,
+    min_withdrawable BIGINT NOT NULL,
+    max_withdrawable BIGINT NOT NULL,
+    state INTEGER NOT NULL DEFAULT 0,
+    bolt11 TEXT,
+    op_id VARCHAR(255),
+    internal BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS idx_withdrawal_user_id ON withdrawals(user_id);
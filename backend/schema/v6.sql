@@ -0,0 +1,3 @@
+-- Settled invoices the recipient's wallet has swept
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS claimed_at TIMESTAMPTZ;
+CREATE INDEX IF NOT EXISTS idx_invoice_unclaimed ON invoices(user_pubkey) WHERE state = 1 AND claimed_at IS NULL;
@@ -0,0 +1,4 @@
+-- Payment details carried in recipient notifications
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS comment TEXT;
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS payer VARCHAR(64);
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS settled_at TIMESTAMPTZ;
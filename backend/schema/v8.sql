@@ -0,0 +1,14 @@
+-- Nostr Wallet Connect clients, identified by the pubkey of their connection secret
+CREATE TABLE IF NOT EXISTS nwc_connections (
+    id SERIAL PRIMARY KEY,
+    user_id INTEGER NOT NULL REFERENCES users(id),
+    pubkey VARCHAR(64) NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    revoked_at TIMESTAMPTZ
+);
+CREATE INDEX IF NOT EXISTS idx_nwc_connection_user_id ON nwc_connections(user_id);
+
+-- NWC clients look invoices up by payment hash
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS payment_hash VARCHAR(64);
+CREATE INDEX IF NOT EXISTS idx_invoice_payment_hash ON invoices(payment_hash);
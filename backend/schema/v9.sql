@@ -0,0 +1,14 @@
+-- Every receive state an invoice went through, for debugging stuck payments
+CREATE TABLE IF NOT EXISTS invoice_events (
+    id SERIAL PRIMARY KEY,
+    invoice_id INTEGER NOT NULL REFERENCES invoices(id),
+    stage INTEGER NOT NULL,
+    reason TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+CREATE INDEX IF NOT EXISTS idx_invoice_event_invoice_id ON invoice_events(invoice_id);
+
+-- Latest recorded stage, backfilled for invoices finished before events existed
+ALTER TABLE invoices ADD COLUMN IF NOT EXISTS stage INTEGER;
+UPDATE invoices SET stage = 4 WHERE stage IS NULL AND state = 1;
+UPDATE invoices SET stage = 5 WHERE stage IS NULL AND state = 2
@@ -1,47 +1,765 @@
-use multimint::fedimint_core::invite_code::InviteCode;
 use std::env;
+use std::fmt;
+use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
+
+use clap::Parser;
+use multimint::fedimint_core::invite_code::InviteCode;
+use nostr_sdk::nips::nip46::NostrConnectURI;
+use nostr_sdk::nips::nip49::EncryptedSecretKey;
+use nostr_sdk::{FromBech32, SecretKey, ToBech32};
+use serde::Deserialize;
 use tracing::info;
+use url::Url;
+
+use crate::keystore::{self, KeystoreSecrets};
+use crate::rate_limit::{Limit, RateLimitBackend, RateLimitSettings};
+use crate::secret::SecretString;
+
+#[cfg(test)]
+mod tests;
+
+/// Environment variables read before `bind_addr` and `public_base_url`
+/// existed, with the keys that replaced them.
+const REPLACED_ENV_VARS: &[(&str, &str)] = &[
+    ("DOMAIN", "public_base_url (PUBLIC_BASE_URL)"),
+    ("PORT", "bind_addr (BIND_ADDR)"),
+];
+
+#[derive(Parser, Debug, Default)]
+#[command(version, about)]
+pub struct Cli {
+    /// Path to a TOML config file
+    #[arg(long, env = "REPLEX_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Validate the configuration and exit
+    #[arg(long)]
+    pub check_config: bool,
+    /// Encrypt the configured secrets into `keystore_path` and exit
+    #[arg(long)]
+    pub write_keystore: bool,
+    /// With --write-keystore, replace an existing keystore instead of
+    /// merging into it
+    #[arg(long, requires = "write_keystore")]
+    pub force: bool,
+    /// Rebuild an empty `fm_db_path` from the mnemonic and invite codes,
+    /// wait for the federations to restore its e-cash, reconcile the
+    /// invoices table against it and exit
+    #[arg(long)]
+    pub recover: bool,
+    /// Issue invoices from a fake lightning node instead of joining the
+    /// federations; they are settled or cancelled through the admin API
+    #[arg(long)]
+    pub dev: bool,
+    #[arg(long)]
+    pub bind_addr: Option<SocketAddr>,
+    #[arg(long)]
+    pub public_base_url: Option<String>,
+    #[arg(long)]
+    pub fm_db_path: Option<PathBuf>,
+    #[arg(long)]
+    pub database_url: Option<String>,
+    #[arg(long, value_delimiter = ',')]
+    pub nostr_relays: Option<Vec<String>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError {
+    pub key: String,
+    pub message: String,
+}
+
+impl ConfigError {
+    fn new(key: impl Into<String>, message: impl fmt::Display) -> Self {
+        Self {
+            key: key.into(),
+            message: message.to_string(),
+        }
+    }
+
+    fn missing(key: &str) -> Self {
+        Self::new(key, "is required")
+    }
+}
 
-lazy_static::lazy_static! {
-    pub static ref CONFIG: Config = Config::load().expect("Failed to load config");
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.message)
+    }
 }
 
+#[derive(Debug, Default)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "invalid configuration:")?;
+        for error in &self.0 {
+            writeln!(f, "  {error}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+impl From<ConfigError> for ConfigErrors {
+    fn from(error: ConfigError) -> Self {
+        Self(vec![error])
+    }
+}
+
+/// One configuration layer. Later layers override earlier ones field by field:
+/// file, then environment, then CLI flags.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawConfig {
+    pub bind_addr: Option<SocketAddr>,
+    pub public_base_url: Option<String>,
+    pub trust_forwarded_headers: Option<bool>,
+    pub shutdown_timeout_secs: Option<u64>,
+    pub callback_idempotency_window_secs: Option<u64>,
+    pub rate_limit_ip_burst: Option<u32>,
+    pub rate_limit_ip_per_minute: Option<u32>,
+    pub rate_limit_username_burst: Option<u32>,
+    pub rate_limit_username_per_minute: Option<u32>,
+    pub rate_limit_backend: Option<RateLimitBackend>,
+    pub price_feed_path: Option<PathBuf>,
+    pub min_sendable_msats: Option<u64>,
+    pub max_sendable_msats: Option<u64>,
+    pub withdraw_max_msats: Option<u64>,
+    pub withdraw_max_open_links: Option<u32>,
+    pub reconcile_interval_secs: Option<u64>,
+    pub admin_token: Option<SecretString>,
+    pub admin_token_file: Option<PathBuf>,
+    pub fm_db_path: Option<PathBuf>,
+    pub federation_invite_codes: Option<Vec<String>>,
+    pub database_url: Option<SecretString>,
+    pub database_url_file: Option<PathBuf>,
+    pub mnemonic: Option<SecretString>,
+    pub mnemonic_file: Option<PathBuf>,
+    pub nostr_nsec: Option<SecretString>,
+    pub nostr_nsec_file: Option<PathBuf>,
+    pub nostr_bunker_uri: Option<SecretString>,
+    pub keystore_path: Option<PathBuf>,
+    pub keystore_passphrase: Option<SecretString>,
+    pub keystore_passphrase_file: Option<PathBuf>,
+    pub nostr_relays: Option<Vec<String>>,
+    pub publish_payment_state: Option<bool>,
+    pub nostr_max_user_relays: Option<usize>,
+    pub dev: Option<bool>,
+}
+
+impl RawConfig {
+    pub fn from_file(path: &PathBuf) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| ConfigError::new(path.display().to_string(), e))?;
+        toml::from_str(&contents).map_err(|e| ConfigError::new(path.display().to_string(), e))
+    }
+
+    pub fn from_env() -> Result<Self, ConfigErrors> {
+        let replaced = replaced_env_vars(|var| env::var_os(var).is_some());
+        if !replaced.is_empty() {
+            return Err(ConfigErrors(replaced));
+        }
+
+        Ok(Self {
+            bind_addr: env_parse("BIND_ADDR", "bind_addr")?,
+            public_base_url: env::var("PUBLIC_BASE_URL").ok(),
+            trust_forwarded_headers: env_parse(
+                "TRUST_FORWARDED_HEADERS",
+                "trust_forwarded_headers",
+            )?,
+            shutdown_timeout_secs: env_parse("SHUTDOWN_TIMEOUT_SECS", "shutdown_timeout_secs")?,
+            callback_idempotency_window_secs: env_parse(
+                "CALLBACK_IDEMPOTENCY_WINDOW_SECS",
+                "callback_idempotency_window_secs",
+            )?,
+            rate_limit_ip_burst: env_parse("RATE_LIMIT_IP_BURST", "rate_limit_ip_burst")?,
+            rate_limit_ip_per_minute: env_parse(
+                "RATE_LIMIT_IP_PER_MINUTE",
+                "rate_limit_ip_per_minute",
+            )?,
+            rate_limit_username_burst: env_parse(
+                "RATE_LIMIT_USERNAME_BURST",
+                "rate_limit_username_burst",
+            )?,
+            rate_limit_username_per_minute: env_parse(
+                "RATE_LIMIT_USERNAME_PER_MINUTE",
+                "rate_limit_username_per_minute",
+            )?,
+            rate_limit_backend: env_parse("RATE_LIMIT_BACKEND", "rate_limit_backend")?,
+            price_feed_path: env::var("PRICE_FEED_PATH").ok().map(PathBuf::from),
+            min_sendable_msats: env_parse("MIN_SENDABLE_MSATS", "min_sendable_msats")?,
+            max_sendable_msats: env_parse("MAX_SENDABLE_MSATS", "max_sendable_msats")?,
+            withdraw_max_msats: env_parse("WITHDRAW_MAX_MSATS", "withdraw_max_msats")?,
+            withdraw_max_open_links: env_parse(
+                "WITHDRAW_MAX_OPEN_LINKS",
+                "withdraw_max_open_links",
+            )?,
+            reconcile_interval_secs: env_parse(
+                "RECONCILE_INTERVAL_SECS",
+                "reconcile_interval_secs",
+            )?,
+            admin_token: env_secret("ADMIN_TOKEN"),
+            admin_token_file: env::var("ADMIN_TOKEN_FILE").ok().map(PathBuf::from),
+            fm_db_path: env::var("FM_DB_PATH").ok().map(PathBuf::from),
+            federation_invite_codes: env::var("FEDERATION_INVITE_CODES").ok().map(split_list),
+            database_url: env_secret("DATABASE_URL"),
+            database_url_file: env::var("DATABASE_URL_FILE").ok().map(PathBuf::from),
+            mnemonic: env_secret("MULTIMINT_MNEMONIC_ENV"),
+            mnemonic_file: env::var("MULTIMINT_MNEMONIC_FILE").ok().map(PathBuf::from),
+            nostr_nsec: env_secret("NOSTR_NSEC"),
+            nostr_nsec_file: env::var("NOSTR_NSEC_FILE").ok().map(PathBuf::from),
+            nostr_bunker_uri: env_secret("NOSTR_BUNKER_URI"),
+            keystore_path: env::var("KEYSTORE_PATH").ok().map(PathBuf::from),
+            keystore_passphrase: env_secret("KEYSTORE_PASSPHRASE"),
+            keystore_passphrase_file: env::var("KEYSTORE_PASSPHRASE_FILE").ok().map(PathBuf::from),
+            nostr_relays: env::var("NOSTR_RELAYS").ok().map(split_list),
+            publish_payment_state: env_parse("PUBLISH_PAYMENT_STATE", "publish_payment_state")?,
+            nostr_max_user_relays: env_parse("NOSTR_MAX_USER_RELAYS", "nostr_max_user_relays")?,
+            dev: env_parse("DEV_MODE", "dev")?,
+        })
+    }
+
+    pub fn from_cli(cli: &Cli) -> Self {
+        Self {
+            bind_addr: cli.bind_addr,
+            public_base_url: cli.public_base_url.clone(),
+            fm_db_path: cli.fm_db_path.clone(),
+            database_url: cli.database_url.clone().map(SecretString::new),
+            nostr_relays: cli.nostr_relays.clone(),
+            dev: cli.dev.then_some(true),
+            ..Default::default()
+        }
+    }
+
+    pub fn merge(self, other: RawConfig) -> Self {
+        Self {
+            bind_addr: other.bind_addr.or(self.bind_addr),
+            public_base_url: other.public_base_url.or(self.public_base_url),
+            trust_forwarded_headers: other
+                .trust_forwarded_headers
+                .or(self.trust_forwarded_headers),
+            shutdown_timeout_secs: other.shutdown_timeout_secs.or(self.shutdown_timeout_secs),
+            callback_idempotency_window_secs: other
+                .callback_idempotency_window_secs
+                .or(self.callback_idempotency_window_secs),
+            rate_limit_ip_burst: other.rate_limit_ip_burst.or(self.rate_limit_ip_burst),
+            rate_limit_ip_per_minute: other
+                .rate_limit_ip_per_minute
+                .or(self.rate_limit_ip_per_minute),
+            rate_limit_username_burst: other
+                .rate_limit_username_burst
+                .or(self.rate_limit_username_burst),
+            rate_limit_username_per_minute: other
+                .rate_limit_username_per_minute
+                .or(self.rate_limit_username_per_minute),
+            rate_limit_backend: other.rate_limit_backend.or(self.rate_limit_backend),
+            price_feed_path: other.price_feed_path.or(self.price_feed_path),
+            min_sendable_msats: other.min_sendable_msats.or(self.min_sendable_msats),
+            max_sendable_msats: other.max_sendable_msats.or(self.max_sendable_msats),
+            withdraw_max_msats: other.withdraw_max_msats.or(self.withdraw_max_msats),
+            withdraw_max_open_links: other
+                .withdraw_max_open_links
+                .or(self.withdraw_max_open_links),
+            reconcile_interval_secs: other
+                .reconcile_interval_secs
+                .or(self.reconcile_interval_secs),
+            admin_token: other.admin_token.or(self.admin_token),
+            admin_token_file: other.admin_token_file.or(self.admin_token_file),
+            fm_db_path: other.fm_db_path.or(self.fm_db_path),
+            federation_invite_codes: other
+                .federation_invite_codes
+                .or(self.federation_invite_codes),
+            database_url: other.database_url.or(self.database_url),
+            database_url_file: other.database_url_file.or(self.database_url_file),
+            mnemonic: other.mnemonic.or(self.mnemonic),
+            mnemonic_file: other.mnemonic_file.or(self.mnemonic_file),
+            nostr_nsec: other.nostr_nsec.or(self.nostr_nsec),
+            nostr_nsec_file: other.nostr_nsec_file.or(self.nostr_nsec_file),
+            nostr_bunker_uri: other.nostr_bunker_uri.or(self.nostr_bunker_uri),
+            keystore_path: other.keystore_path.or(self.keystore_path),
+            keystore_passphrase: other.keystore_passphrase.or(self.keystore_passphrase),
+            keystore_passphrase_file: other
+                .keystore_passphrase_file
+                .or(self.keystore_passphrase_file),
+            nostr_relays: other.nostr_relays.or(self.nostr_relays),
+            publish_payment_state: other.publish_payment_state.or(self.publish_payment_state),
+            nostr_max_user_relays: other.nostr_max_user_relays.or(self.nostr_max_user_relays),
+            dev: other.dev.or(self.dev),
+        }
+    }
+
+    /// Secrets set inline or through their `*_file` keys, ignoring the keystore.
+    fn plain_secrets(&self, errors: &mut Vec<ConfigError>) -> KeystoreSecrets {
+        KeystoreSecrets {
+            nostr_nsec: resolve_secret(
+                "nostr_nsec",
+                &self.nostr_nsec,
+                &self.nostr_nsec_file,
+                errors,
+            ),
+            mnemonic: resolve_secret("mnemonic", &self.mnemonic, &self.mnemonic_file, errors),
+            database_url: resolve_secret(
+                "database_url",
+                &self.database_url,
+                &self.database_url_file,
+                errors,
+            ),
+        }
+    }
+
+    fn keystore_passphrase(&self, errors: &mut Vec<ConfigError>) -> Option<SecretString> {
+        resolve_secret(
+            "keystore_passphrase",
+            &self.keystore_passphrase,
+            &self.keystore_passphrase_file,
+            errors,
+        )
+    }
+
+    /// Encrypts the plainly configured secrets into `keystore_path`, so they
+    /// can be removed from the environment and config file. Secrets already
+    /// in the keystore are kept unless `force` replaces it.
+    pub fn write_keystore(&self, force: bool) -> Result<PathBuf, ConfigErrors> {
+        let mut errors = Vec::new();
+        let secrets = self.plain_secrets(&mut errors);
+        let passphrase = self.keystore_passphrase(&mut errors);
+        if passphrase.is_none() {
+            errors.push(ConfigError::missing("keystore_passphrase"));
+        }
+        if self.keystore_path.is_none() {
+            errors.push(ConfigError::missing("keystore_path"));
+        }
+        let (Some(path), Some(passphrase)) = (&self.keystore_path, passphrase) else {
+            return Err(ConfigErrors(errors));
+        };
+        if !errors.is_empty() {
+            return Err(ConfigErrors(errors));
+        }
+
+        keystore::write(path, secrets, &passphrase, force)
+            .map_err(|e| ConfigError::new("keystore_path", format!("{e:#}")))?;
+        Ok(path.clone())
+    }
+
+    /// Checks every key and reports all problems at once, each naming its key.
+    pub fn validate(self) -> Result<Config, ConfigErrors> {
+        let mut errors = Vec::new();
+
+        // Inline and file secrets take precedence over the keystore's.
+        let mut secrets = self.plain_secrets(&mut errors);
+        let passphrase = self.keystore_passphrase(&mut errors);
+        if let Some(path) = &self.keystore_path {
+            match &passphrase {
+                Some(passphrase) => match keystore::read(path, passphrase) {
+                    Ok(stored) => secrets = secrets.or(stored),
+                    Err(e) => errors.push(ConfigError::new("keystore_path", format!("{e:#}"))),
+                },
+                None => errors.push(ConfigError::missing("keystore_passphrase")),
+            }
+        }
+
+        let public_base_url = self
+            .public_base_url
+            .unwrap_or_else(|| "http://localhost:3000".to_string());
+        let public_base_url = match Url::parse(&public_base_url) {
+            Ok(url) if !matches!(url.scheme(), "http" | "https") => {
+                errors.push(ConfigError::new(
+                    "public_base_url",
+                    format!("expected http:// or https://, got {:?}", url.scheme()),
+                ));
+                url
+            }
+            Ok(url) if url.query().is_some() || url.fragment().is_some() => {
+                errors.push(ConfigError::new(
+                    "public_base_url",
+                    "must not contain a query or fragment",
+                ));
+                url
+            }
+            Ok(url) => url,
+            Err(e) => {
+                errors.push(ConfigError::new("public_base_url", e));
+                Url::parse("http://localhost:3000").expect("valid default url")
+            }
+        };
+
+        let mut limit = |key: &str, value: Option<u32>, default: u32| {
+            let value = value.unwrap_or(default);
+            if value == 0 {
+                errors.push(ConfigError::new(key, "must be at least 1"));
+            }
+            value
+        };
+        let rate_limit = RateLimitSettings {
+            per_ip: Limit {
+                burst: limit("rate_limit_ip_burst", self.rate_limit_ip_burst, 20),
+                per_minute: limit(
+                    "rate_limit_ip_per_minute",
+                    self.rate_limit_ip_per_minute,
+                    10,
+                ),
+            },
+            per_username: Limit {
+                burst: limit(
+                    "rate_limit_username_burst",
+                    self.rate_limit_username_burst,
+                    30,
+                ),
+                per_minute: limit(
+                    "rate_limit_username_per_minute",
+                    self.rate_limit_username_per_minute,
+                    20,
+                ),
+            },
+            backend: self.rate_limit_backend.unwrap_or(RateLimitBackend::Memory),
+        };
+
+        let min_sendable_msats = self.min_sendable_msats.unwrap_or(1_000);
+        let max_sendable_msats = self.max_sendable_msats.unwrap_or(100_000_000);
+        if min_sendable_msats == 0 {
+            errors.push(ConfigError::new("min_sendable_msats", "must be at least 1"));
+        }
+        if max_sendable_msats < min_sendable_msats {
+            errors.push(ConfigError::new(
+                "max_sendable_msats",
+                format!("must be at least min_sendable_msats ({min_sendable_msats})"),
+            ));
+        }
+
+        let admin_token = resolve_secret(
+            "admin_token",
+            &self.admin_token,
+            &self.admin_token_file,
+            &mut errors,
+        );
+        if admin_token.as_ref().is_some_and(SecretString::is_empty) {
+            errors.push(ConfigError::new("admin_token", "must not be empty"));
+        }
+
+        // Dev mode joins no federation, so it needs no invite codes or mnemonic.
+        let dev = self.dev.unwrap_or(false);
+
+        let fm_db_path = self.fm_db_path.unwrap_or_else(|| {
+            errors.push(ConfigError::missing("fm_db_path"));
+            PathBuf::new()
+        });
+
+        let mut federation_invite_codes = Vec::new();
+        match self.federation_invite_codes {
+            Some(codes) if !codes.is_empty() => {
+                for (i, code) in codes.iter().enumerate() {
+                    match InviteCode::from_str(code) {
+                        Ok(code) => federation_invite_codes.push(code),
+                        Err(e) => errors
+                            .push(ConfigError::new(format!("federation_invite_codes[{i}]"), e)),
+                    }
+                }
+            }
+            _ if dev => {}
+            _ => errors.push(ConfigError::missing("federation_invite_codes")),
+        }
+
+        let database_url = match secrets.database_url {
+            Some(url) => {
+                match Url::parse(url.expose()) {
+                    Ok(parsed) if matches!(parsed.scheme(), "postgres" | "postgresql") => {}
+                    Ok(parsed) if parsed.scheme() == "sqlite" => {
+                        if !cfg!(feature = "sqlite") {
+                            errors.push(ConfigError::new(
+                                "database_url",
+                                "sqlite needs a build with the `sqlite` feature",
+                            ));
+                        }
+                        if rate_limit.backend == RateLimitBackend::Postgres {
+                            errors.push(ConfigError::new(
+                                "rate_limit_backend",
+                                "postgres needs a postgres database_url",
+                            ));
+                        }
+                    }
+                    Ok(parsed) => errors.push(ConfigError::new(
+                        "database_url",
+                        format!("unsupported scheme {:?}", parsed.scheme()),
+                    )),
+                    Err(e) => errors.push(ConfigError::new("database_url", e)),
+                }
+                url
+            }
+            None => {
+                errors.push(ConfigError::missing("database_url"));
+                SecretString::default()
+            }
+        };
+
+        let mnemonic = match secrets.mnemonic {
+            Some(mnemonic) if !mnemonic.is_empty() => {
+                if let Err(e) = bip39::Mnemonic::parse_normalized(mnemonic.expose()) {
+                    errors.push(ConfigError::new("mnemonic", e));
+                }
+                mnemonic
+            }
+            _ if dev => SecretString::default(),
+            _ => {
+                errors.push(ConfigError::missing("mnemonic"));
+                SecretString::default()
+            }
+        };
+
+        let nostr_signer = match (secrets.nostr_nsec, self.nostr_bunker_uri) {
+            (Some(_), Some(_)) => {
+                errors.push(ConfigError::new(
+                    "nostr_bunker_uri",
+                    "set either nostr_nsec or nostr_bunker_uri, not both",
+                ));
+                NostrSignerConfig::Keys(SecretString::default())
+            }
+            (Some(nsec), None) => match parse_nsec(&nsec, passphrase.as_ref()) {
+                Ok(nsec) => NostrSignerConfig::Keys(nsec),
+                Err(e) => {
+                    errors.push(ConfigError::new("nostr_nsec", e));
+                    NostrSignerConfig::Keys(SecretString::default())
+                }
+            },
+            (None, Some(uri)) => {
+                match NostrConnectURI::parse(uri.expose()) {
+                    Ok(NostrConnectURI::Bunker { .. }) => {}
+                    Ok(_) => errors.push(ConfigError::new(
+                        "nostr_bunker_uri",
+                        "expected a bunker:// uri",
+                    )),
+                    Err(e) => errors.push(ConfigError::new("nostr_bunker_uri", e)),
+                }
+                NostrSignerConfig::Bunker(uri)
+            }
+            (None, None) => {
+                errors.push(ConfigError::missing("nostr_nsec"));
+                NostrSignerConfig::Keys(SecretString::default())
+            }
+        };
+
+        let nostr_relays = match self.nostr_relays {
+            Some(relays) if !relays.is_empty() => {
+                for (i, relay) in relays.iter().enumerate() {
+                    match Url::parse(relay) {
+                        Ok(url) if matches!(url.scheme(), "ws" | "wss") => {}
+                        Ok(url) => errors.push(ConfigError::new(
+                            format!("nostr_relays[{i}]"),
+                            format!("expected ws:// or wss://, got {:?}", url.scheme()),
+                        )),
+                        Err(e) => errors.push(ConfigError::new(format!("nostr_relays[{i}]"), e)),
+                    }
+                }
+                relays
+            }
+            _ => {
+                errors.push(ConfigError::missing("nostr_relays"));
+                Vec::new()
+            }
+        };
+
+        if !errors.is_empty() {
+            return Err(ConfigErrors(errors));
+        }
+
+        Ok(Config {
+            bind_addr: self
+                .bind_addr
+                .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 3000))),
+            public_base_url,
+            trust_forwarded_headers: self.trust_forwarded_headers.unwrap_or(false),
+            shutdown_timeout: Duration::from_secs(self.shutdown_timeout_secs.unwrap_or(30)),
+            callback_idempotency_window: Duration::from_secs(
+                self.callback_idempotency_window_secs.unwrap_or(600),
+            ),
+            rate_limit,
+            price_feed_path: self.price_feed_path,
+            min_sendable_msats,
+            max_sendable_msats,
+            withdraw_max_msats: self.withdraw_max_msats.unwrap_or(0),
+            withdraw_max_open_links: self.withdraw_max_open_links.unwrap_or(5),
+            reconcile_interval: Duration::from_secs(self.reconcile_interval_secs.unwrap_or(600)),
+            admin_token,
+            fm_db_path,
+            federation_invite_codes,
+            database_url,
+            mnemonic,
+            nostr_signer,
+            nostr_relays,
+            publish_payment_state: self.publish_payment_state.unwrap_or(false),
+            nostr_max_user_relays: self.nostr_max_user_relays.unwrap_or(50),
+            dev,
+        })
+    }
+}
+
+fn env_parse<T>(var: &str, key: &str) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match env::var(var) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| ConfigError::new(format!("{key} ({var})"), format!("{e}: {value:?}"))),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Rather than silently ignore a deployment's old settings, errors for
+/// each replaced variable that `is_set`.
+fn replaced_env_vars(is_set: impl Fn(&str) -> bool) -> Vec<ConfigError> {
+    REPLACED_ENV_VARS
+        .iter()
+        .filter(|(var, _)| is_set(var))
+        .map(|(var, key)| ConfigError::new(*var, format!("is no longer read, set {key} instead")))
+        .collect()
+}
+
+fn env_secret(var: &str) -> Option<SecretString> {
+    env::var(var).ok().map(SecretString::new)
+}
+
+/// A secret is set either inline or as a path to a file holding it.
+fn resolve_secret(
+    key: &str,
+    value: &Option<SecretString>,
+    file: &Option<PathBuf>,
+    errors: &mut Vec<ConfigError>,
+) -> Option<SecretString> {
+    match (value, file) {
+        (Some(_), Some(_)) => {
+            errors.push(ConfigError::new(
+                format!("{key}_file"),
+                format!("set either {key} or {key}_file, not both"),
+            ));
+            None
+        }
+        (Some(value), None) => Some(value.clone()),
+        (None, Some(path)) => match SecretString::from_file(path) {
+            Ok(value) => Some(value),
+            Err(e) => {
+                errors.push(ConfigError::new(
+                    format!("{key}_file"),
+                    format!("{}: {e}", path.display()),
+                ));
+                None
+            }
+        },
+        (None, None) => None,
+    }
+}
+
+/// Accepts a plain `nsec` or a NIP-49 `ncryptsec` unlocked with the keystore
+/// passphrase, returning the plain `nsec`.
+fn parse_nsec(
+    nsec: &SecretString,
+    passphrase: Option<&SecretString>,
+) -> Result<SecretString, String> {
+    if !nsec.expose().starts_with("ncryptsec1") {
+        SecretKey::from_bech32(nsec.expose()).map_err(|e| e.to_string())?;
+        return Ok(nsec.clone());
+    }
+    let passphrase = passphrase.ok_or("an ncryptsec needs keystore_passphrase")?;
+    let encrypted = EncryptedSecretKey::from_bech32(nsec.expose()).map_err(|e| e.to_string())?;
+    let secret_key = encrypted
+        .to_secret_key(passphrase.expose())
+        .map_err(|e| format!("failed to decrypt ncryptsec: {e}"))?;
+    secret_key
+        .to_bech32()
+        .map(SecretString::new)
+        .map_err(|e| e.to_string())
+}
+
+fn split_list(value: String) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// Where the server's nostr events and encryption are signed.
+#[derive(Clone, Debug)]
+pub enum NostrSignerConfig {
+    /// A local key, held zeroized in memory.
+    Keys(SecretString),
+    /// A NIP-46 remote signer, so the key never enters the process.
+    Bunker(SecretString),
+}
+
+/// Secrets are [`SecretString`]s, so `Debug` output is safe to log.
+#[derive(Clone, Debug)]
 pub struct Config {
-    pub domain: String,
-    pub port: u16,
+    /// Local socket the HTTP server listens on.
+    pub bind_addr: SocketAddr,
+    /// Externally reachable URL that every emitted link is built from.
+    pub public_base_url: Url,
+    /// Honor `X-Forwarded-*` headers from a reverse proxy when building links.
+    pub trust_forwarded_headers: bool,
+    /// Upper bound on draining requests and background tasks at shutdown.
+    pub shutdown_timeout: Duration,
+    /// Repeated callbacks with the same nonce within this window reuse the pending invoice.
+    pub callback_idempotency_window: Duration,
+    pub rate_limit: RateLimitSettings,
+    /// Static exchange rates; currency amounts are disabled when unset.
+    pub price_feed_path: Option<PathBuf>,
+    /// Bounds advertised as `minSendable`/`maxSendable` and enforced on pay
+    /// callbacks and NWC invoices, after any fiat conversion.
+    pub min_sendable_msats: u64,
+    pub max_sendable_msats: u64,
+    /// Largest amount a single LNURL-withdraw link may pay out of the
+    /// server's ecash; 0 disables withdraw links.
+    pub withdraw_max_msats: u64,
+    /// Links a user may have open at once, not yet used by a wallet.
+    pub withdraw_max_open_links: u32,
+    /// How often invoices are reconciled against the federation clients'
+    /// operation logs; zero leaves it to the admin endpoint.
+    pub reconcile_interval: Duration,
+    /// Bearer token for the `/admin` endpoints, which are disabled when unset.
+    pub admin_token: Option<SecretString>,
     pub fm_db_path: PathBuf,
     pub federation_invite_codes: Vec<InviteCode>,
-    pub pg_db: String,
-    pub mnemonic: String,
-    pub nostr_nsec: String,
+    /// Postgres, or with the `sqlite` feature a `sqlite:` URL that keeps
+    /// users and invoices in SQLite.
+    pub database_url: SecretString,
+    /// BIP-39 mnemonic the federation clients are joined with; empty in dev
+    /// mode when unset.
+    pub mnemonic: SecretString,
+    pub nostr_signer: NostrSignerConfig,
     pub nostr_relays: Vec<String>,
+    /// Keep a NIP-78 replaceable event per user with their recent payments,
+    /// for wallets to resync from.
+    pub publish_payment_state: bool,
+    /// Cap on user relays connected alongside the server relays; the least
+    /// recently used are disconnected beyond it.
+    pub nostr_max_user_relays: usize,
+    /// Use the fake lightning backend instead of the federation clients.
+    pub dev: bool,
 }
 
 impl Config {
-    pub fn load() -> Result<Self, env::VarError> {
+    pub fn load(cli: &Cli) -> Result<Self, ConfigErrors> {
         dotenv::dotenv().ok();
 
-        let config = Self {
-            domain: env::var("DOMAIN").unwrap_or_else(|_| "localhost".to_string()),
-            port: env::var("PORT")
-                .unwrap_or_else(|_| "3000".to_string())
-                .parse()
-                .expect("Invalid port"),
-            fm_db_path: env::var("FM_DB_PATH")?.parse().expect("Invalid FM_DB_PATH"),
-            federation_invite_codes: env::var("FEDERATION_INVITE_CODES")?
-                .split(',')
-                .map(|s| InviteCode::from_str(s).unwrap())
-                .collect(),
-            pg_db: env::var("DATABASE_URL")?,
-            mnemonic: env::var("MULTIMINT_MNEMONIC_ENV")?,
-            nostr_nsec: env::var("NOSTR_NSEC")?,
-            nostr_relays: env::var("NOSTR_RELAYS")?
-                .split(',')
-                .map(String::from)
-                .collect(),
-        };
+        let mut raw = RawConfig::default();
+        if let Some(path) = &cli.config {
+            raw = raw.merge(RawConfig::from_file(path)?);
+        }
+        let raw = raw
+            .merge(RawConfig::from_env()?)
+            .merge(RawConfig::from_cli(cli));
+        if cli.write_keystore {
+            let path = raw.write_keystore(cli.force)?;
+            info!("Wrote keystore to {}", path.display());
+        }
+        let config = raw.validate()?;
 
         info!("Loaded config");
         Ok(config)
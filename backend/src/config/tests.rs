@@ -0,0 +1,156 @@
+//! Layering of config sources and the errors validation reports.
+
+use anyhow::Result;
+
+use super::*;
+
+/// The smallest configuration that validates, in dev mode.
+fn valid() -> RawConfig {
+    RawConfig {
+        public_base_url: Some("https://replex.test".to_string()),
+        fm_db_path: Some(PathBuf::from("./fm_db")),
+        database_url: Some(SecretString::new("postgres://localhost/replex".to_string())),
+        nostr_nsec: Some(SecretString::new(
+            SecretKey::generate().to_bech32().expect("bech32 nsec"),
+        )),
+        nostr_relays: Some(vec!["wss://relay.example.com".to_string()]),
+        dev: Some(true),
+        ..Default::default()
+    }
+}
+
+fn error_keys(raw: RawConfig) -> Vec<String> {
+    match raw.validate() {
+        Ok(_) => Vec::new(),
+        Err(ConfigErrors(errors)) => errors.into_iter().map(|e| e.key).collect(),
+    }
+}
+
+#[test]
+fn later_layers_override_earlier_ones_key_by_key() -> Result<()> {
+    let file: RawConfig = toml::from_str(
+        r#"
+        bind_addr = "127.0.0.1:4000"
+        public_base_url = "https://file.example"
+        nostr_relays = ["wss://file.example"]
+        shutdown_timeout_secs = 5
+        "#,
+    )?;
+    let env = RawConfig {
+        bind_addr: Some("127.0.0.1:5000".parse()?),
+        public_base_url: Some("https://env.example".to_string()),
+        ..Default::default()
+    };
+    let cli = RawConfig::from_cli(&Cli {
+        bind_addr: Some("0.0.0.0:6000".parse()?),
+        ..Default::default()
+    });
+
+    let raw = RawConfig::default().merge(file).merge(env).merge(cli);
+    assert_eq!(raw.bind_addr, Some("0.0.0.0:6000".parse()?));
+    assert_eq!(raw.public_base_url.as_deref(), Some("https://env.example"));
+    assert_eq!(
+        raw.nostr_relays,
+        Some(vec!["wss://file.example".to_string()])
+    );
+    assert_eq!(raw.shutdown_timeout_secs, Some(5));
+
+    // flags that weren't passed leave earlier layers alone
+    let raw = RawConfig {
+        dev: Some(true),
+        ..Default::default()
+    }
+    .merge(RawConfig::from_cli(&Cli::default()));
+    assert_eq!(raw.dev, Some(true));
+    Ok(())
+}
+
+#[test]
+fn defaults_fill_unset_keys() -> Result<()> {
+    let config = valid().validate()?;
+    assert_eq!(config.bind_addr, "127.0.0.1:3000".parse()?);
+    assert_eq!(config.public_base_url.as_str(), "https://replex.test/");
+    assert_eq!(config.rate_limit.per_ip.burst, 20);
+    assert_eq!(config.min_sendable_msats, 1_000);
+    assert_eq!(config.max_sendable_msats, 100_000_000);
+    assert!(config.federation_invite_codes.is_empty());
+    Ok(())
+}
+
+#[test]
+fn validation_reports_every_bad_key() {
+    let raw = RawConfig {
+        public_base_url: Some("ftp://replex.test".to_string()),
+        rate_limit_ip_burst: Some(0),
+        max_sendable_msats: Some(10),
+        fm_db_path: None,
+        nostr_relays: Some(vec![
+            "wss://relay.example.com".to_string(),
+            "https://relay.example.com".to_string(),
+        ]),
+        ..valid()
+    };
+    assert_eq!(
+        error_keys(raw),
+        [
+            "public_base_url",
+            "rate_limit_ip_burst",
+            "max_sendable_msats",
+            "fm_db_path",
+            "nostr_relays[1]",
+        ]
+    );
+
+    let mut outside_dev = valid();
+    outside_dev.dev = None;
+    assert_eq!(
+        error_keys(outside_dev),
+        ["federation_invite_codes", "mnemonic"]
+    );
+}
+
+#[test]
+fn errors_are_shown_with_their_keys() {
+    let raw = RawConfig {
+        nostr_bunker_uri: Some(SecretString::new("bunker://unused".to_string())),
+        ..valid()
+    };
+    let errors = raw.validate().err().expect("both signers set");
+    let shown = errors.to_string();
+    assert!(
+        shown.contains("nostr_bunker_uri: set either nostr_nsec or nostr_bunker_uri, not both"),
+        "{shown}"
+    );
+}
+
+#[test]
+fn file_errors_name_the_file_and_key() -> Result<()> {
+    let path = std::env::temp_dir().join(format!(
+        "replex-config-{}.toml",
+        hex::encode(rand::random::<[u8; 8]>())
+    ));
+    std::fs::write(&path, "domain = \"replex.test\"\n")?;
+    let result = RawConfig::from_file(&path);
+    std::fs::remove_file(&path)?;
+
+    let error = result.err().context("unknown key accepted")?;
+    assert_eq!(error.key, path.display().to_string());
+    assert!(error.message.contains("domain"), "{error}");
+    Ok(())
+}
+
+#[test]
+fn replaced_env_vars_point_to_their_keys() {
+    assert!(replaced_env_vars(|_| false).is_empty());
+
+    let errors = replaced_env_vars(|var| var == "PORT");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].key, "PORT");
+    assert!(errors[0].message.contains("bind_addr"), "{}", errors[0]);
+
+    let keys: Vec<String> = replaced_env_vars(|_| true)
+        .into_iter()
+        .map(|e| e.key)
+        .collect();
+    assert_eq!(keys, ["DOMAIN", "PORT"]);
+}
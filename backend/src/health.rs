@@ -0,0 +1,317 @@
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use deadpool_postgres::Status;
+use multimint::{fedimint_core::config::FederationId, fedimint_ln_client::LightningClientModule};
+use nostr_sdk::RelayStatus;
+use serde::Serialize;
+use tokio::sync::RwLock;
+use tracing::{info, warn};
+
+#[cfg(feature = "sqlite")]
+use crate::model::sqlite::SqliteDb;
+use crate::model::Db;
+use crate::nostr::relay_pool::RelayDeliveryStats;
+use crate::state::AppState;
+
+#[cfg(test)]
+mod tests;
+
+pub const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ComponentStatus {
+    Healthy,
+    Degraded,
+    Unhealthy,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ComponentHealth {
+    pub status: ComponentStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    pub checked_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FederationHealth {
+    pub status: ComponentStatus,
+    pub guardians_reachable: bool,
+    pub gateway_count: usize,
+    pub gateways_refreshed_at: Option<u64>,
+    pub last_invoice_at: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub checked_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct HealthReport {
+    pub postgres: Option<ComponentHealth>,
+    /// Set instead of `postgres` when users and invoices are kept in SQLite.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sqlite: Option<ComponentHealth>,
+    pub nostr_relays: BTreeMap<String, ComponentHealth>,
+    /// Notification delivery results per relay, including user relays.
+    pub nostr_delivery: BTreeMap<String, RelayDeliveryStats>,
+    pub federations: BTreeMap<String, FederationHealth>,
+}
+
+impl HealthReport {
+    /// Ready needs the database and a usable federation, except that dev mode
+    /// issues invoices from the fake backend and may have no federations.
+    pub fn status(&self, dev: bool) -> ComponentStatus {
+        let database = self
+            .postgres
+            .as_ref()
+            .or(self.sqlite.as_ref())
+            .map_or(ComponentStatus::Unhealthy, |p| p.status);
+        if database == ComponentStatus::Unhealthy {
+            return ComponentStatus::Unhealthy;
+        }
+
+        let any_federation_healthy = self
+            .federations
+            .values()
+            .any(|f| f.status != ComponentStatus::Unhealthy);
+        let needs_federation = !(dev && self.federations.is_empty());
+        if needs_federation && !any_federation_healthy {
+            return ComponentStatus::Unhealthy;
+        }
+
+        let all_healthy = database == ComponentStatus::Healthy
+            && self
+                .federations
+                .values()
+                .all(|f| f.status == ComponentStatus::Healthy)
+            && self
+                .nostr_relays
+                .values()
+                .any(|r| r.status == ComponentStatus::Healthy);
+        if all_healthy {
+            ComponentStatus::Healthy
+        } else {
+            ComponentStatus::Degraded
+        }
+    }
+}
+
+/// Latest results of the background health probes, shared between the
+/// monitor task, the `/health/ready` handler and the invoice router.
+#[derive(Clone, Default)]
+pub struct HealthMonitor {
+    report: Arc<RwLock<HealthReport>>,
+    /// Kept apart from the report so a probe finishing doesn't drop invoices
+    /// created while it ran, or before the federation was first probed.
+    last_invoices: Arc<RwLock<BTreeMap<String, u64>>>,
+}
+
+impl HealthMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn report(&self) -> HealthReport {
+        self.report.read().await.clone()
+    }
+
+    /// Federations that have not been probed yet are considered usable.
+    pub async fn is_federation_usable(&self, federation_id: &FederationId) -> bool {
+        self.report
+            .read()
+            .await
+            .federations
+            .get(&federation_id.to_string())
+            .map_or(true, |f| f.status != ComponentStatus::Unhealthy)
+    }
+
+    pub async fn record_invoice_created(&self, federation_id: &FederationId) {
+        let federation_id = federation_id.to_string();
+        let now = now();
+        self.last_invoices
+            .write()
+            .await
+            .insert(federation_id.clone(), now);
+        if let Some(federation) = self
+            .report
+            .write()
+            .await
+            .federations
+            .get_mut(&federation_id)
+        {
+            federation.last_invoice_at = Some(now);
+        }
+    }
+
+    pub async fn check(&self, state: &AppState) {
+        let postgres = match &state.db {
+            Some(db) => Some(check_postgres(db).await),
+            None => None,
+        };
+        #[cfg(feature = "sqlite")]
+        let sqlite = match &state.sqlite {
+            Some(db) => Some(check_sqlite(db).await),
+            None => None,
+        };
+        #[cfg(not(feature = "sqlite"))]
+        let sqlite = None;
+        let nostr_relays = check_nostr_relays(state).await;
+        let nostr_delivery = state.nostr.pool.stats().await;
+
+        let clients = state.mm.clients.lock().await.clone();
+        let mut federations = BTreeMap::new();
+        for (federation_id, client) in clients {
+            let ln = client.get_first_module::<LightningClientModule>();
+            let previous = self
+                .report
+                .read()
+                .await
+                .federations
+                .get(&federation_id.to_string())
+                .cloned();
+            let health = check_federation(&ln, previous).await;
+            if health.status != ComponentStatus::Healthy {
+                warn!(
+                    "Federation {} is {:?}: {:?}",
+                    federation_id, health.status, health.error
+                );
+            }
+            federations.insert(federation_id.to_string(), health);
+        }
+
+        // Hold the report while merging so an invoice recorded meanwhile
+        // lands after this write rather than under it.
+        let mut report = self.report.write().await;
+        let last_invoices = self.last_invoices.read().await;
+        for (federation_id, health) in &mut federations {
+            health.last_invoice_at = last_invoices.get(federation_id).copied();
+        }
+        *report = HealthReport {
+            postgres,
+            sqlite,
+            nostr_relays,
+            nostr_delivery,
+            federations,
+        };
+    }
+}
+
+pub fn spawn_health_monitor(state: AppState) {
+    let tasks = state.tasks.clone();
+    tasks.spawn(async move {
+        let mut interval = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = state.shutdown.cancelled() => break,
+                _ = interval.tick() => state.health.check(&state).await,
+            }
+        }
+    });
+    info!("Started health monitor");
+}
+
+async fn check_postgres(db: &Db) -> ComponentHealth {
+    let result = tokio::time::timeout(PROBE_TIMEOUT, db.query_value::<i32>("SELECT 1", &[])).await;
+    pool_health(result.map(|r| r.map(|_| ())), db.pool_status())
+}
+
+#[cfg(feature = "sqlite")]
+async fn check_sqlite(db: &SqliteDb) -> ComponentHealth {
+    let result = tokio::time::timeout(PROBE_TIMEOUT, db.ping()).await;
+    pool_health(result, db.pool_status())
+}
+
+fn pool_health(
+    result: Result<anyhow::Result<()>, tokio::time::error::Elapsed>,
+    pool: Status,
+) -> ComponentHealth {
+    let (status, message) = match result {
+        Ok(Ok(())) => (
+            ComponentStatus::Healthy,
+            format!(
+                "pool size {}/{}, {} available, {} waiting",
+                pool.size, pool.max_size, pool.available, pool.waiting
+            ),
+        ),
+        Ok(Err(e)) => (ComponentStatus::Unhealthy, e.to_string()),
+        Err(_) => (ComponentStatus::Unhealthy, "timed out".to_string()),
+    };
+    ComponentHealth {
+        status,
+        message: Some(message),
+        checked_at: now(),
+    }
+}
+
+async fn check_nostr_relays(state: &AppState) -> BTreeMap<String, ComponentHealth> {
+    let mut relays = BTreeMap::new();
+    for (url, relay) in state.nostr.client.relays().await {
+        let relay_status = relay.status().await;
+        let status = match relay_status {
+            RelayStatus::Connected => ComponentStatus::Healthy,
+            RelayStatus::Connecting | RelayStatus::Pending | RelayStatus::Initialized => {
+                ComponentStatus::Degraded
+            }
+            _ => ComponentStatus::Unhealthy,
+        };
+        relays.insert(
+            url.to_string(),
+            ComponentHealth {
+                status,
+                message: Some(relay_status.to_string()),
+                checked_at: now(),
+            },
+        );
+    }
+    relays
+}
+
+/// Refreshing the gateway cache round-trips to the guardians, so a successful
+/// refresh doubles as the guardian connectivity check.
+async fn check_federation(
+    ln: &LightningClientModule,
+    previous: Option<FederationHealth>,
+) -> FederationHealth {
+    let refresh = tokio::time::timeout(PROBE_TIMEOUT, ln.update_gateway_cache()).await;
+    let (guardians_reachable, error) = match refresh {
+        Ok(Ok(())) => (true, None),
+        Ok(Err(e)) => (false, Some(format!("gateway refresh failed: {e}"))),
+        Err(_) => (false, Some("guardians did not respond in time".to_string())),
+    };
+
+    let gateway_count = ln.list_gateways().await.len();
+    let gateways_refreshed_at = if guardians_reachable {
+        Some(now())
+    } else {
+        previous.as_ref().and_then(|p| p.gateways_refreshed_at)
+    };
+
+    let status = match (guardians_reachable, gateway_count) {
+        (_, 0) => ComponentStatus::Unhealthy,
+        (true, _) => ComponentStatus::Healthy,
+        (false, _) => ComponentStatus::Degraded,
+    };
+
+    FederationHealth {
+        status,
+        guardians_reachable,
+        gateway_count,
+        gateways_refreshed_at,
+        last_invoice_at: None,
+        error: error.or_else(|| (gateway_count == 0).then(|| "no gateways available".to_string())),
+        checked_at: now(),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -0,0 +1,63 @@
+//! Readiness from the component statuses.
+
+use super::*;
+
+fn component(status: ComponentStatus) -> ComponentHealth {
+    ComponentHealth {
+        status,
+        message: None,
+        checked_at: now(),
+    }
+}
+
+fn federation(status: ComponentStatus) -> FederationHealth {
+    FederationHealth {
+        status,
+        guardians_reachable: status != ComponentStatus::Unhealthy,
+        gateway_count: 1,
+        gateways_refreshed_at: Some(now()),
+        last_invoice_at: None,
+        error: None,
+        checked_at: now(),
+    }
+}
+
+/// A healthy database and relay, without federations.
+fn report() -> HealthReport {
+    HealthReport {
+        postgres: Some(component(ComponentStatus::Healthy)),
+        nostr_relays: BTreeMap::from([(
+            "wss://relay.example.com".to_string(),
+            component(ComponentStatus::Healthy),
+        )]),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn dev_mode_is_ready_without_federations() {
+    assert_eq!(report().status(true), ComponentStatus::Healthy);
+    assert_eq!(report().status(false), ComponentStatus::Unhealthy);
+
+    let mut no_database = report();
+    no_database.postgres = Some(component(ComponentStatus::Unhealthy));
+    assert_eq!(no_database.status(true), ComponentStatus::Unhealthy);
+}
+
+#[test]
+fn configured_federations_count_in_dev_mode_too() {
+    let mut report = report();
+    report
+        .federations
+        .insert("down".to_string(), federation(ComponentStatus::Unhealthy));
+    assert_eq!(report.status(true), ComponentStatus::Unhealthy);
+
+    report
+        .federations
+        .insert("up".to_string(), federation(ComponentStatus::Healthy));
+    assert_eq!(report.status(true), ComponentStatus::Degraded);
+    assert_eq!(report.status(false), ComponentStatus::Degraded);
+
+    report.federations.remove("down");
+    assert_eq!(report.status(false), ComponentStatus::Healthy);
+}
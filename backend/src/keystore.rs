@@ -0,0 +1,157 @@
+//! Passphrase-encrypted file holding the server's secrets, so they don't need
+//! to live in plaintext env vars or config files. The key is derived with
+//! scrypt and the secrets sealed with XChaCha20-Poly1305, the construction
+//! NIP-49 uses for single nostr keys.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::XChaCha20Poly1305;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::secret::SecretString;
+
+#[cfg(test)]
+mod tests;
+
+const KEYSTORE_VERSION: u8 = 1;
+/// scrypt cost, 2^16 iterations as NIP-49 recommends.
+const LOG_N: u8 = 16;
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct KeystoreSecrets {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nostr_nsec: Option<SecretString>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mnemonic: Option<SecretString>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub database_url: Option<SecretString>,
+}
+
+impl KeystoreSecrets {
+    /// Each secret from `self`, or from `other` where `self` has none.
+    pub fn or(self, other: KeystoreSecrets) -> Self {
+        Self {
+            nostr_nsec: self.nostr_nsec.or(other.nostr_nsec),
+            mnemonic: self.mnemonic.or(other.mnemonic),
+            database_url: self.database_url.or(other.database_url),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct KeystoreFile {
+    version: u8,
+    log_n: u8,
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+fn derive_key(passphrase: &SecretString, salt: &[u8], log_n: u8) -> Result<Zeroizing<[u8; 32]>> {
+    let params = scrypt::Params::new(log_n, 8, 1, 32).map_err(|e| anyhow!("{e}"))?;
+    let mut key = Zeroizing::new([0u8; 32]);
+    scrypt::scrypt(passphrase.expose().as_bytes(), salt, &params, &mut *key)
+        .map_err(|e| anyhow!("{e}"))?;
+    Ok(key)
+}
+
+pub fn encrypt(secrets: &KeystoreSecrets, passphrase: &SecretString) -> Result<String> {
+    if passphrase.is_empty() {
+        bail!("Keystore passphrase must not be empty");
+    }
+    let plaintext = Zeroizing::new(toml::to_string(secrets)?);
+    let salt: [u8; 16] = rand::random();
+    let nonce: [u8; 24] = rand::random();
+    let key = derive_key(passphrase, &salt, LOG_N)?;
+    let ciphertext = XChaCha20Poly1305::new((&*key).into())
+        .encrypt((&nonce).into(), plaintext.as_bytes())
+        .map_err(|_| anyhow!("Failed to encrypt keystore"))?;
+
+    Ok(serde_json::to_string_pretty(&KeystoreFile {
+        version: KEYSTORE_VERSION,
+        log_n: LOG_N,
+        salt: BASE64.encode(salt),
+        nonce: BASE64.encode(nonce),
+        ciphertext: BASE64.encode(ciphertext),
+    })?)
+}
+
+pub fn decrypt(contents: &str, passphrase: &SecretString) -> Result<KeystoreSecrets> {
+    let file: KeystoreFile = serde_json::from_str(contents).context("Malformed keystore")?;
+    if file.version != KEYSTORE_VERSION {
+        bail!("Unsupported keystore version {}", file.version);
+    }
+    let nonce: [u8; 24] = BASE64
+        .decode(&file.nonce)?
+        .try_into()
+        .map_err(|_| anyhow!("Malformed keystore nonce"))?;
+    let key = derive_key(passphrase, &BASE64.decode(&file.salt)?, file.log_n)?;
+    let plaintext = Zeroizing::new(
+        XChaCha20Poly1305::new((&*key).into())
+            .decrypt((&nonce).into(), BASE64.decode(&file.ciphertext)?.as_slice())
+            .map_err(|_| anyhow!("Wrong passphrase or corrupted keystore"))?,
+    );
+    let plaintext = std::str::from_utf8(&plaintext).context("Malformed keystore contents")?;
+    Ok(toml::from_str(plaintext)?)
+}
+
+pub fn read(path: &Path, passphrase: &SecretString) -> Result<KeystoreSecrets> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read keystore {}", path.display()))?;
+    decrypt(&contents, passphrase)
+}
+
+/// Writes `secrets` to `path`, keeping any secret an existing keystore has
+/// that `secrets` doesn't. The existing keystore must open with `passphrase`
+/// unless `force` replaces it outright.
+///
+/// The file is created owner-only under a temporary name and renamed into
+/// place, so the secrets are never readable by others and a failed write
+/// leaves the old keystore intact.
+pub fn write(
+    path: &Path,
+    secrets: KeystoreSecrets,
+    passphrase: &SecretString,
+    force: bool,
+) -> Result<()> {
+    let secrets = if path.exists() && !force {
+        let stored = read(path, passphrase)
+            .context("Can't merge into the existing keystore, use --force to replace it")?;
+        secrets.or(stored)
+    } else {
+        secrets
+    };
+    let contents = encrypt(&secrets, passphrase)?;
+
+    let file_name = path
+        .file_name()
+        .with_context(|| format!("Keystore path {} is not a file", path.display()))?;
+    let temp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        hex::encode(rand::random::<[u8; 8]>())
+    ));
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let written = options.open(&temp_path).and_then(|mut file| {
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&temp_path, path)
+    });
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    written.with_context(|| format!("Failed to write keystore {}", path.display()))
+}
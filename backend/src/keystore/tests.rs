@@ -0,0 +1,126 @@
+//! Keystore files written to a temporary directory and read back.
+
+use std::path::PathBuf;
+
+use super::*;
+
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new() -> Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "replex-keystore-{}",
+            hex::encode(rand::random::<[u8; 8]>())
+        ));
+        std::fs::create_dir_all(&path)?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn secret(value: &str) -> Option<SecretString> {
+    Some(SecretString::new(value.to_string()))
+}
+
+fn expose(secret: &Option<SecretString>) -> Option<&str> {
+    secret.as_ref().map(SecretString::expose)
+}
+
+#[test]
+fn round_trip() -> Result<()> {
+    let dir = TempDir::new()?;
+    let path = dir.0.join("keystore.json");
+    let passphrase = SecretString::new("correct horse".to_string());
+    let secrets = KeystoreSecrets {
+        nostr_nsec: secret("nsec1test"),
+        mnemonic: secret("abandon abandon about"),
+        database_url: None,
+    };
+
+    write(&path, secrets, &passphrase, false)?;
+    let stored = read(&path, &passphrase)?;
+    assert_eq!(expose(&stored.nostr_nsec), Some("nsec1test"));
+    assert_eq!(expose(&stored.mnemonic), Some("abandon abandon about"));
+    assert_eq!(expose(&stored.database_url), None);
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(&path)?.permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+    // only the keystore itself is left behind
+    assert_eq!(std::fs::read_dir(&dir.0)?.count(), 1);
+    Ok(())
+}
+
+#[test]
+fn wrong_passphrase_is_rejected() -> Result<()> {
+    let dir = TempDir::new()?;
+    let path = dir.0.join("keystore.json");
+    let passphrase = SecretString::new("correct horse".to_string());
+    let wrong = SecretString::new("battery staple".to_string());
+    let secrets = KeystoreSecrets {
+        mnemonic: secret("abandon abandon about"),
+        ..Default::default()
+    };
+    write(&path, secrets, &passphrase, false)?;
+
+    let err = read(&path, &wrong).unwrap_err();
+    assert!(
+        err.to_string().contains("Wrong passphrase"),
+        "unexpected error: {err:#}"
+    );
+    Ok(())
+}
+
+#[test]
+fn rewriting_merges_unless_forced() -> Result<()> {
+    let dir = TempDir::new()?;
+    let path = dir.0.join("keystore.json");
+    let passphrase = SecretString::new("correct horse".to_string());
+    let other = SecretString::new("battery staple".to_string());
+    write(
+        &path,
+        KeystoreSecrets {
+            mnemonic: secret("abandon abandon about"),
+            database_url: secret("postgres://old"),
+            ..Default::default()
+        },
+        &passphrase,
+        false,
+    )?;
+
+    // given secrets replace stored ones, the rest are kept
+    write(
+        &path,
+        KeystoreSecrets {
+            database_url: secret("postgres://new"),
+            ..Default::default()
+        },
+        &passphrase,
+        false,
+    )?;
+    let stored = read(&path, &passphrase)?;
+    assert_eq!(expose(&stored.mnemonic), Some("abandon abandon about"));
+    assert_eq!(expose(&stored.database_url), Some("postgres://new"));
+
+    // a keystore that can't be opened is left alone
+    let replacement = || KeystoreSecrets {
+        nostr_nsec: secret("nsec1test"),
+        ..Default::default()
+    };
+    assert!(write(&path, replacement(), &other, false).is_err());
+    assert!(read(&path, &passphrase).is_ok());
+
+    write(&path, replacement(), &other, true)?;
+    let stored = read(&path, &other)?;
+    assert_eq!(expose(&stored.nostr_nsec), Some("nsec1test"));
+    assert_eq!(expose(&stored.mnemonic), None);
+    Ok(())
+}
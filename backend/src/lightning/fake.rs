@@ -0,0 +1,189 @@
+//! A lightning node that never touches the network, for dev mode and tests.
+//! Invoices are real bolt11s signed by a fixed key, derived from the user's
+//! key and tweak so the same request always yields the same invoice. They
+//! stay pending until [`FakeLightning::settle`] or [`FakeLightning::cancel`].
+//! Receives only live in memory; pending invoices are registered again with
+//! [`FakeLightning::restore`] when they're resubscribed after a restart.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use multimint::fedimint_core::bitcoin_hashes::{sha256, Hash};
+use multimint::fedimint_core::config::FederationId;
+use multimint::fedimint_core::core::OperationId;
+use multimint::fedimint_core::secp256k1::{PublicKey, Secp256k1, SecretKey};
+use multimint::fedimint_ln_client::receive::LightningReceiveError;
+use multimint::fedimint_ln_client::LnReceiveState;
+use multimint::fedimint_ln_common::lightning_invoice::{Currency, InvoiceBuilder, PaymentSecret};
+use tokio::sync::watch;
+
+use super::{CreatedInvoice, GatewayInfo, InvoiceParams, LightningBackend};
+
+/// Secret key of the node every fake invoice is signed by.
+const NODE_KEY: [u8; 32] = [0x42; 32];
+const INVOICE_EXPIRY: Duration = Duration::from_secs(3600);
+const MIN_FINAL_CLTV_EXPIRY_DELTA: u64 = 18;
+
+/// Issues invoices in any federation. Clones share their invoices.
+#[derive(Clone, Default)]
+pub struct FakeLightning {
+    /// Every update of each receive so far, so late subscribers replay them.
+    receives: Arc<Mutex<HashMap<OperationId, watch::Sender<Vec<LnReceiveState>>>>>,
+}
+
+impl FakeLightning {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pays a pending invoice, going through the stages a fedimint receive does.
+    pub fn settle(&self, op_id: OperationId) -> Result<()> {
+        self.finish(
+            op_id,
+            vec![
+                LnReceiveState::Funded,
+                LnReceiveState::AwaitingFunds,
+                LnReceiveState::Claimed,
+            ],
+        )
+    }
+
+    pub fn cancel(&self, op_id: OperationId) -> Result<()> {
+        self.finish(
+            op_id,
+            vec![LnReceiveState::Canceled {
+                reason: LightningReceiveError::Rejected,
+            }],
+        )
+    }
+
+    /// Registers a receive created before a restart as waiting for payment.
+    /// Known receives are left as they are.
+    pub fn restore(&self, op_id: OperationId, bolt11: &str) {
+        self.receives
+            .lock()
+            .unwrap()
+            .entry(op_id)
+            .or_insert_with(|| watch::channel(waiting(bolt11.to_string())).0);
+    }
+
+    fn finish(&self, op_id: OperationId, updates: Vec<LnReceiveState>) -> Result<()> {
+        let receives = self.receives.lock().unwrap();
+        let receive = receives.get(&op_id).context("Unknown operation")?;
+        if let Some(last) = receive.borrow().last().filter(|state| is_final(state)) {
+            bail!("Invoice is already {last:?}");
+        }
+        receive.send_modify(|history| history.extend(updates));
+        Ok(())
+    }
+
+    fn node_key() -> SecretKey {
+        SecretKey::from_slice(&NODE_KEY).expect("valid secret key")
+    }
+}
+
+#[async_trait]
+impl LightningBackend for FakeLightning {
+    async fn has_federation(&self, _federation_id: &FederationId) -> bool {
+        true
+    }
+
+    async fn list_gateways(&self, _federation_id: &FederationId) -> Result<Vec<GatewayInfo>> {
+        let node_pub_key = PublicKey::from_secret_key(&Secp256k1::new(), &Self::node_key());
+        Ok(vec![GatewayInfo {
+            gateway_id: node_pub_key.to_string(),
+            node_pub_key: node_pub_key.to_string(),
+            alias: "fake".to_string(),
+        }])
+    }
+
+    async fn create_invoice(
+        &self,
+        federation_id: &FederationId,
+        params: InvoiceParams,
+    ) -> Result<CreatedInvoice> {
+        let seed = format!("{federation_id}/{}/{}", params.user_key, params.tweak);
+        let preimage = sha256::Hash::hash(seed.as_bytes()).to_byte_array();
+        let payment_hash = sha256::Hash::hash(&preimage);
+        let payment_secret = sha256::Hash::hash(&payment_hash.to_byte_array()).to_byte_array();
+        let op_id = OperationId(payment_hash.to_byte_array());
+
+        let mut receives = self.receives.lock().unwrap();
+        if receives.contains_key(&op_id) {
+            // Like a fedimint client asked to reuse a tweak.
+            bail!("Invoice already exists");
+        }
+
+        let secp = Secp256k1::new();
+        let invoice = InvoiceBuilder::new(Currency::Regtest)
+            .description(params.description)
+            .payment_hash(payment_hash)
+            .payment_secret(PaymentSecret(payment_secret))
+            .current_timestamp()
+            .min_final_cltv_expiry_delta(MIN_FINAL_CLTV_EXPIRY_DELTA)
+            .amount_milli_satoshis(params.amount.msats)
+            .expiry_time(INVOICE_EXPIRY)
+            .build_signed(|hash| secp.sign_ecdsa_recoverable(hash, &Self::node_key()))?;
+
+        let (sender, _) = watch::channel(waiting(invoice.to_string()));
+        receives.insert(op_id, sender);
+        Ok(CreatedInvoice {
+            op_id,
+            invoice,
+            preimage,
+        })
+    }
+
+    async fn subscribe_receive(
+        &self,
+        _federation_id: &FederationId,
+        op_id: OperationId,
+    ) -> Result<BoxStream<'static, LnReceiveState>> {
+        let updates = self
+            .receives
+            .lock()
+            .unwrap()
+            .get(&op_id)
+            .context("Unknown operation")?
+            .subscribe();
+        let stream = futures::stream::unfold((updates, 0), |(mut updates, seen)| async move {
+            loop {
+                let next = {
+                    let history = updates.borrow_and_update();
+                    if seen > 0 && is_final(&history[seen - 1]) {
+                        return None;
+                    }
+                    history.get(seen).cloned()
+                };
+                match next {
+                    Some(state) => return Some((state, (updates, seen + 1))),
+                    None => updates.changed().await.ok()?,
+                }
+            }
+        });
+        Ok(stream.boxed())
+    }
+}
+
+/// The updates a fedimint receive has made once its invoice is out.
+fn waiting(invoice: String) -> Vec<LnReceiveState> {
+    vec![
+        LnReceiveState::Created,
+        LnReceiveState::WaitingForPayment {
+            invoice,
+            timeout: INVOICE_EXPIRY,
+        },
+    ]
+}
+
+fn is_final(state: &LnReceiveState) -> bool {
+    matches!(
+        state,
+        LnReceiveState::Claimed | LnReceiveState::Canceled { .. }
+    )
+}
@@ -0,0 +1,99 @@
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use multimint::fedimint_client::ClientHandleArc;
+use multimint::fedimint_core::config::FederationId;
+use multimint::fedimint_core::core::OperationId;
+use multimint::fedimint_ln_client::{LightningClientModule, LnReceiveState};
+use multimint::fedimint_ln_common::lightning_invoice::{Bolt11InvoiceDescription, Description};
+use multimint::MultiMint;
+
+use super::{CreatedInvoice, GatewayInfo, InvoiceParams, LightningBackend};
+
+/// Receives through the registered federation clients.
+#[derive(Clone)]
+pub struct FedimintLightning(MultiMint);
+
+impl FedimintLightning {
+    pub fn new(mm: MultiMint) -> Self {
+        Self(mm)
+    }
+
+    async fn client(&self, federation_id: &FederationId) -> Result<ClientHandleArc> {
+        self.0
+            .clients
+            .lock()
+            .await
+            .get(federation_id)
+            .cloned()
+            .context("Client not found")
+    }
+}
+
+#[async_trait]
+impl LightningBackend for FedimintLightning {
+    async fn has_federation(&self, federation_id: &FederationId) -> bool {
+        self.0.clients.lock().await.contains_key(federation_id)
+    }
+
+    async fn list_gateways(&self, federation_id: &FederationId) -> Result<Vec<GatewayInfo>> {
+        let client = self.client(federation_id).await?;
+        let ln = client.get_first_module::<LightningClientModule>();
+        Ok(ln
+            .list_gateways()
+            .await
+            .into_iter()
+            .map(|gateway| GatewayInfo {
+                gateway_id: gateway.info.gateway_id.to_string(),
+                node_pub_key: gateway.info.node_pub_key.to_string(),
+                alias: gateway.info.lightning_alias,
+            })
+            .collect())
+    }
+
+    async fn create_invoice(
+        &self,
+        federation_id: &FederationId,
+        params: InvoiceParams,
+    ) -> Result<CreatedInvoice> {
+        let client = self.client(federation_id).await?;
+        let ln = client.get_first_module::<LightningClientModule>();
+        let gateway = ln
+            .list_gateways()
+            .await
+            .into_iter()
+            .next()
+            .context("No gateway available")?;
+        let (op_id, invoice, preimage) = ln
+            .create_bolt11_invoice_for_user_tweaked(
+                params.amount,
+                Bolt11InvoiceDescription::Direct(&Description::new(params.description)?),
+                None,
+                params.user_key,
+                params.tweak,
+                (),
+                Some(gateway.info),
+            )
+            .await?;
+        Ok(CreatedInvoice {
+            op_id,
+            invoice,
+            preimage,
+        })
+    }
+
+    async fn subscribe_receive(
+        &self,
+        federation_id: &FederationId,
+        op_id: OperationId,
+    ) -> Result<BoxStream<'static, LnReceiveState>> {
+        let client = self.client(federation_id).await?;
+        let ln = client.get_first_module::<LightningClientModule>();
+        let updates = ln
+            .subscribe_ln_receive(op_id)
+            .await
+            .context("Failed to subscribe to invoice")?;
+        Ok(updates.into_stream().boxed())
+    }
+}
@@ -0,0 +1,68 @@
+//! Receiving over lightning. The server issues invoices, follows their
+//! receive state and lists gateways through [`LightningBackend`], so it can
+//! run against the fedimint clients or, in dev mode and tests, a fake node.
+
+pub mod fake;
+pub mod fedimint;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use multimint::fedimint_core::config::FederationId;
+use multimint::fedimint_core::core::OperationId;
+use multimint::fedimint_core::secp256k1::PublicKey;
+use multimint::fedimint_core::Amount;
+use multimint::fedimint_ln_client::LnReceiveState;
+use multimint::fedimint_ln_common::lightning_invoice::Bolt11Invoice;
+use serde::Serialize;
+
+pub use fake::FakeLightning;
+pub use fedimint::FedimintLightning;
+
+/// An invoice paying into a user's tweaked key.
+#[derive(Debug, Clone)]
+pub struct InvoiceParams {
+    pub amount: Amount,
+    pub description: String,
+    /// The user's key, which the received ecash is locked to after tweaking.
+    pub user_key: PublicKey,
+    pub tweak: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct CreatedInvoice {
+    pub op_id: OperationId,
+    pub invoice: Bolt11Invoice,
+    pub preimage: [u8; 32],
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GatewayInfo {
+    pub gateway_id: String,
+    pub node_pub_key: String,
+    pub alias: String,
+}
+
+#[async_trait]
+pub trait LightningBackend: Send + Sync {
+    /// Whether invoices can be issued in `federation_id`.
+    async fn has_federation(&self, federation_id: &FederationId) -> bool;
+
+    async fn list_gateways(&self, federation_id: &FederationId) -> Result<Vec<GatewayInfo>>;
+
+    /// Fails with an "already exists" error when `params.tweak` was used
+    /// before for the same key.
+    async fn create_invoice(
+        &self,
+        federation_id: &FederationId,
+        params: InvoiceParams,
+    ) -> Result<CreatedInvoice>;
+
+    /// Updates of a receive, from its current state until it is claimed or
+    /// canceled.
+    async fn subscribe_receive(
+        &self,
+        federation_id: &FederationId,
+        op_id: OperationId,
+    ) -> Result<BoxStream<'static, LnReceiveState>>;
+}
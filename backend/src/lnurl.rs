@@ -0,0 +1,44 @@
+use anyhow::Result;
+use axum::http::header;
+use axum::response::{IntoResponse, Response};
+use bech32::{ToBase32, Variant};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use serde::Serialize;
+use url::Url;
+
+/// Encodes a url as a LUD-01 `LNURL1...` string. Uppercase keeps QR codes in
+/// the denser alphanumeric mode, and wallets accept either case.
+pub fn encode(url: &Url) -> Result<String> {
+    let encoded = bech32::encode(
+        "lnurl",
+        url.as_str().as_bytes().to_base32(),
+        Variant::Bech32,
+    )?;
+    Ok(encoded.to_uppercase())
+}
+
+/// A bech32 LNURL together with the url it decodes to.
+#[derive(Serialize, Debug)]
+pub struct EncodedLnurl {
+    pub lnurl: String,
+    pub url: Url,
+}
+
+impl EncodedLnurl {
+    pub fn new(url: Url) -> Result<Self> {
+        Ok(Self {
+            lnurl: encode(&url)?,
+            url,
+        })
+    }
+
+    /// Renders the LNURL as an SVG QR code response.
+    pub fn qr_svg(&self) -> Result<Response> {
+        let svg = QrCode::new(self.lnurl.as_bytes())?
+            .render::<svg::Color>()
+            .min_dimensions(256, 256)
+            .build();
+        Ok(([(header::CONTENT_TYPE, "image/svg+xml")], svg).into_response())
+    }
+}
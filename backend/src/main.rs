@@ -1,14 +1,27 @@
 pub mod config;
 pub mod error;
+pub mod health;
+pub mod keystore;
+pub mod lightning;
+pub mod lnurl;
+pub mod metrics;
 pub mod model;
 pub mod nostr;
+pub mod price_feed;
+pub mod rate_limit;
+pub mod reconcile;
+pub mod recovery;
 pub mod router;
+pub mod secret;
 pub mod serde_helpers;
+pub mod shutdown;
 pub mod state;
 
-use config::CONFIG;
+use std::net::SocketAddr;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use clap::Parser;
+use config::{Cli, Config};
 use state::AppState;
 use tracing::{error, info};
 
@@ -18,23 +31,70 @@ async fn main() -> Result<()> {
         .with_max_level(tracing::Level::INFO)
         .init();
 
-    let state = AppState::new().await?;
+    let cli = Cli::parse();
+    let config = Config::load(&cli)?;
+    if cli.check_config {
+        println!("Config OK");
+        return Ok(());
+    }
+    if cli.write_keystore {
+        println!("Keystore written");
+        return Ok(());
+    }
+    if cli.recover {
+        let report = recovery::recover(config).await?;
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    let state = AppState::new(config).await?;
 
     let app = router::create_router(state.clone()).await?;
 
     // spawn a task to check for previous pending invoices
-    tokio::spawn(async move {
-        if let Err(e) = state.handle_pending_invoices().await {
+    let pending_state = state.clone();
+    state.tasks.spawn(async move {
+        if let Err(e) = pending_state.handle_pending_invoices().await {
             error!("Error handling pending invoices: {e}")
         }
     });
     info!("Started pending invoice handler");
 
-    let listener = tokio::net::TcpListener::bind(format!("{}:{}", CONFIG.domain, CONFIG.port))
+    let pending_state = state.clone();
+    state.tasks.spawn(async move {
+        if let Err(e) = pending_state.handle_pending_withdrawals().await {
+            error!("Error handling pending withdrawals: {e}")
+        }
+    });
+
+    health::spawn_health_monitor(state.clone());
+    nostr::nwc::spawn_nwc_service(state.clone());
+    reconcile::spawn_reconciler(state.clone());
+    rate_limit::spawn_pruner(state.clone());
+
+    let listener = tokio::net::TcpListener::bind(state.config.bind_addr)
         .await
-        .unwrap();
-    info!("Listening on {}", CONFIG.port);
-    axum::serve(listener, app).await.unwrap();
+        .with_context(|| format!("Failed to bind {}", state.config.bind_addr))?;
+    info!(
+        "Listening on {}, public url {}",
+        state.config.bind_addr, state.config.public_base_url
+    );
+
+    let server = axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(state.shutdown.clone().cancelled_owned());
+    let server = tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("Server error: {e}");
+        }
+    });
+
+    shutdown::wait_for_signal().await;
+    info!("Shutting down");
+    state.shutdown.cancel();
+    shutdown::drain(state.clone(), server, state.config.shutdown_timeout).await?;
 
     Ok(())
 }
@@ -0,0 +1,83 @@
+use anyhow::Result;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, TextEncoder,
+};
+
+use deadpool_postgres::Status;
+
+lazy_static::lazy_static! {
+    pub static ref CALLBACKS_SERVED: IntCounterVec = register_int_counter_vec!(
+        "replex_lnurlp_callbacks_total",
+        "LNURL-pay callbacks served, by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref INVOICES_CREATED: IntCounterVec = register_int_counter_vec!(
+        "replex_invoices_created_total",
+        "Invoices created, by federation",
+        &["federation_id"]
+    )
+    .unwrap();
+    pub static ref INVOICES_SETTLED: IntCounterVec = register_int_counter_vec!(
+        "replex_invoices_settled_total",
+        "Invoices settled, by federation",
+        &["federation_id"]
+    )
+    .unwrap();
+    pub static ref INVOICES_CANCELLED: IntCounterVec = register_int_counter_vec!(
+        "replex_invoices_cancelled_total",
+        "Invoices cancelled, by federation",
+        &["federation_id"]
+    )
+    .unwrap();
+    pub static ref SETTLE_LATENCY: HistogramVec = register_histogram_vec!(
+        "replex_invoice_settle_latency_seconds",
+        "Time from creating an invoice until it is claimed",
+        &["federation_id"],
+        vec![1.0, 5.0, 15.0, 30.0, 60.0, 300.0, 900.0, 3600.0, 86400.0]
+    )
+    .unwrap();
+    pub static ref TWEAK_COLLISIONS: IntCounter = register_int_counter!(
+        "replex_tweak_collisions_total",
+        "Invoice creations retried because the tweak was already used"
+    )
+    .unwrap();
+    pub static ref NOSTR_DM_FAILURES: IntCounter = register_int_counter!(
+        "replex_nostr_dm_failures_total",
+        "Settlement notifications that could not be sent over nostr"
+    )
+    .unwrap();
+    pub static ref ACTIVE_SUBSCRIPTIONS: IntGauge = register_int_gauge!(
+        "replex_active_invoice_subscriptions",
+        "Invoice subscriptions currently being monitored"
+    )
+    .unwrap();
+    pub static ref DB_POOL: IntGaugeVec = register_int_gauge_vec!(
+        "replex_db_pool_connections",
+        "Database connection pool usage",
+        &["kind"]
+    )
+    .unwrap();
+}
+
+/// Pool usage is sampled at scrape time rather than tracked on every checkout.
+pub fn render(pool: Option<Status>) -> Result<String> {
+    if let Some(pool) = pool {
+        DB_POOL
+            .with_label_values(&["max"])
+            .set(pool.max_size as i64);
+        DB_POOL.with_label_values(&["size"]).set(pool.size as i64);
+        DB_POOL
+            .with_label_values(&["available"])
+            .set(pool.available as i64);
+        DB_POOL
+            .with_label_values(&["waiting"])
+            .set(pool.waiting as i64);
+    }
+
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer)?)
+}
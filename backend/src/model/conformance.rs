@@ -0,0 +1,522 @@
+//! Behaviour every [`UserRepo`] and [`InvoiceRepo`] implementation must share,
+//! run against the in-memory store, an in-memory SQLite database with the
+//! `sqlite` feature and, with `--ignored` and `TEST_DATABASE_URL` pointing at
+//! a scratch database, against Postgres. Each case makes its own user with
+//! random identifiers, so runs don't collide in a shared database. The
+//! [`RateLimitRepo`] upsert only exists for Postgres and is checked there.
+
+use anyhow::{ensure, Context, Result};
+
+use super::invoice_events::InvoiceStage;
+use super::invoices::{InvoiceForCreate, InvoiceState};
+use super::memory::MemoryStore;
+use super::repo::{InvoiceRepo, RateLimitRepo, UserRepo};
+use super::users::success_action::SuccessActionTemplate;
+use super::users::{User, UserForCreate, UserForUpdate};
+use super::Db;
+
+fn random_hex(len: usize) -> String {
+    let mut hex = String::new();
+    while hex.len() < len {
+        hex.push_str(&hex::encode(rand::random::<[u8; 32]>()));
+    }
+    hex.truncate(len);
+    hex
+}
+
+async fn new_user(users: &dyn UserRepo) -> Result<User> {
+    let user = UserForCreate::new(
+        format!("user_{}", random_hex(12)),
+        rand::random::<u32>().to_string(),
+        "https://example.com/pic.png".to_string(),
+        random_hex(64),
+        vec!["wss://relay.example.com".to_string()],
+        vec![random_hex(64)],
+    );
+    users.create(user).await
+}
+
+fn new_invoice(user: &User, tweak: i64) -> InvoiceForCreate {
+    InvoiceForCreate::builder()
+        .op_id(random_hex(64))
+        .federation_id(user.federation_ids[0].clone())
+        .user_id(user.id)
+        .user_pubkey(user.pubkey.clone())
+        .amount(21_000)
+        .bolt11(format!("lnbc210n1{}", random_hex(40)))
+        .tweak(tweak)
+        .state(InvoiceState::Pending)
+        .payment_hash(random_hex(64))
+        .build()
+        .expect("all required fields set")
+}
+
+async fn users_round_trip(users: &dyn UserRepo, _: &dyn InvoiceRepo) -> Result<()> {
+    let user = new_user(users).await?;
+    let by_id = users.get(user.id).await?.context("user by id")?;
+    ensure!(by_id.name == user.name && by_id.replit_id == user.replit_id);
+    let by_name = users
+        .get_by_name(&user.name)
+        .await?
+        .context("user by name")?;
+    ensure!(by_name.id == user.id);
+    ensure!(by_name.connection_code_uuid == user.connection_code_uuid);
+    let by_pubkey = users
+        .get_by_pubkey(&user.pubkey)
+        .await?
+        .context("user by pubkey")?;
+    ensure!(by_pubkey.id == user.id);
+    ensure!(users.get_by_pubkey(&random_hex(64)).await?.is_none());
+
+    let relays = vec!["wss://other.example.com".to_string()];
+    let update = UserForUpdate::builder().relays(relays.clone()).build();
+    let updated = users.update(user.id, update).await?;
+    ensure!(updated.relays == relays && updated.name == user.name);
+
+    users.update_tweak(user.id, 7).await?;
+    ensure!(users.get(user.id).await?.context("user")?.last_tweak == 7);
+    // a callback committing late doesn't move it back
+    users.update_tweak(user.id, 6).await?;
+    ensure!(users.get(user.id).await?.context("user")?.last_tweak == 7);
+
+    let action = SuccessActionTemplate::Message {
+        message: "thanks".to_string(),
+    };
+    let updated = users.update_success_action(&user, Some(&action)).await?;
+    ensure!(updated.success_action == Some(action));
+    let cleared = users.update_success_action(&user, None).await?;
+    ensure!(cleared.success_action.is_none());
+    Ok(())
+}
+
+async fn unique_users(users: &dyn UserRepo, _: &dyn InvoiceRepo) -> Result<()> {
+    let user = new_user(users).await?;
+    let mut duplicate = UserForCreate::new(
+        user.name.clone(),
+        rand::random::<u32>().to_string(),
+        String::new(),
+        random_hex(64),
+        vec![],
+        vec![],
+    );
+    ensure!(
+        users.create(duplicate.clone()).await.is_err(),
+        "duplicate name"
+    );
+    duplicate.name = format!("user_{}", random_hex(12));
+    duplicate.pubkey = user.pubkey.clone();
+    ensure!(users.create(duplicate).await.is_err(), "duplicate pubkey");
+    ensure!(users.get(-1).await?.is_none());
+    Ok(())
+}
+
+async fn invoice_lifecycle(users: &dyn UserRepo, invoices: &dyn InvoiceRepo) -> Result<()> {
+    let user = new_user(users).await?;
+    let invoice = invoices.create(new_invoice(&user, 1)).await?;
+    ensure!(invoice.state == InvoiceState::Pending && invoice.settled_at.is_none());
+    ensure!(invoice.created_at.is_some() && invoice.stage.is_none());
+    ensure!(invoices.create(new_invoice(&user, 2)).await?.id != invoice.id);
+
+    let by_op_id = invoices
+        .get_by_op_id(&invoice.op_id)
+        .await?
+        .context("by op id")?;
+    ensure!(by_op_id.id == invoice.id);
+    let payment_hash = invoice.payment_hash.as_deref().context("payment hash")?;
+    ensure!(invoices
+        .get_by_payment_hash(user.id, payment_hash)
+        .await?
+        .is_some());
+    ensure!(invoices
+        .get_by_bolt11(user.id, &invoice.bolt11)
+        .await?
+        .is_some());
+    ensure!(invoices
+        .get_by_bolt11(user.id + 1, &invoice.bolt11)
+        .await?
+        .is_none());
+
+    ensure!(invoices.get_unclaimed(&user.pubkey).await?.is_empty());
+    invoices
+        .update_state(invoice.id, InvoiceState::Settled)
+        .await?;
+    let settled = invoices
+        .get_by_op_id(&invoice.op_id)
+        .await?
+        .context("settled")?;
+    ensure!(settled.state == InvoiceState::Settled && settled.settled_at.is_some());
+
+    let recent = invoices.get_recent_settled(&user.pubkey, 10).await?;
+    ensure!(recent.len() == 1 && recent[0].id == invoice.id);
+    let listed = invoices
+        .list_for_user(user.id, None, None, false, 10, 0)
+        .await?;
+    ensure!(
+        listed.len() == 1,
+        "only settled invoices without include_unpaid"
+    );
+    let listed = invoices
+        .list_for_user(user.id, None, None, true, 10, 0)
+        .await?;
+    ensure!(listed.len() == 2);
+    ensure!(listed[0].created_at >= listed[1].created_at);
+
+    let unclaimed = invoices.get_unclaimed(&user.pubkey).await?;
+    ensure!(unclaimed.len() == 1 && unclaimed[0].id == invoice.id);
+    let op_ids = vec![invoice.op_id.clone()];
+    ensure!(invoices.mark_claimed(&user.pubkey, &op_ids).await?.len() == 1);
+    ensure!(invoices
+        .mark_claimed(&user.pubkey, &op_ids)
+        .await?
+        .is_empty());
+    ensure!(invoices.get_unclaimed(&user.pubkey).await?.is_empty());
+    Ok(())
+}
+
+async fn unique_invoices(users: &dyn UserRepo, invoices: &dyn InvoiceRepo) -> Result<()> {
+    let user = new_user(users).await?;
+    let invoice = new_invoice(&user, 1);
+    invoices.create(invoice.clone()).await?;
+    ensure!(invoices.create(invoice).await.is_err(), "duplicate op_id");
+
+    let mut orphan = new_invoice(&user, 2);
+    orphan.user_id = -1;
+    ensure!(invoices.create(orphan).await.is_err(), "missing user");
+    Ok(())
+}
+
+async fn create_with_tweak(users: &dyn UserRepo, invoices: &dyn InvoiceRepo) -> Result<()> {
+    let user = new_user(users).await?;
+    let invoice = invoices.create_with_tweak(new_invoice(&user, 5)).await?;
+    ensure!(invoice.tweak == 5);
+    ensure!(users.get(user.id).await?.context("user")?.last_tweak == 5);
+
+    // a failed insert leaves the tweak where it was
+    let mut duplicate = new_invoice(&user, 6);
+    duplicate.op_id = invoice.op_id;
+    ensure!(invoices.create_with_tweak(duplicate).await.is_err());
+    ensure!(users.get(user.id).await?.context("user")?.last_tweak == 5);
+    Ok(())
+}
+
+async fn pending_pages(users: &dyn UserRepo, invoices: &dyn InvoiceRepo) -> Result<()> {
+    let user = new_user(users).await?;
+    let mut created = Vec::new();
+    for tweak in 0..5 {
+        created.push(invoices.create(new_invoice(&user, tweak)).await?.id);
+    }
+    invoices
+        .update_state(created[2], InvoiceState::Cancelled)
+        .await?;
+
+    let mut seen = Vec::new();
+    let mut after_id = created[0] - 1;
+    loop {
+        let page = invoices
+            .get_page_by_state(InvoiceState::Pending, after_id, 2)
+            .await?;
+        ensure!(page.len() <= 2);
+        ensure!(page.windows(2).all(|pair| pair[0].id < pair[1].id));
+        let Some(last) = page.last() else {
+            break;
+        };
+        after_id = last.id;
+        seen.extend(page.iter().filter(|i| i.user_id == user.id).map(|i| i.id));
+    }
+    let expected: Vec<i32> = created
+        .iter()
+        .copied()
+        .filter(|id| *id != created[2])
+        .collect();
+    ensure!(seen == expected, "expected {expected:?}, paged {seen:?}");
+
+    let pending = invoices.get_by_state(InvoiceState::Pending).await?;
+    ensure!(pending.iter().any(|i| i.id == created[0]));
+    ensure!(!pending.iter().any(|i| i.id == created[2]));
+    Ok(())
+}
+
+async fn nonces(users: &dyn UserRepo, invoices: &dyn InvoiceRepo) -> Result<()> {
+    let user = new_user(users).await?;
+    let nonce = random_hex(16);
+    let mut invoice = new_invoice(&user, 1);
+    invoice.nonce = Some(nonce.clone());
+    let created = invoices.create(invoice.clone()).await?;
+
+    invoice.op_id = random_hex(64);
+    ensure!(
+        invoices.create(invoice.clone()).await.is_err(),
+        "a pending nonce is unique per user and amount"
+    );
+
+    let found = invoices
+        .get_pending_by_nonce(user.id, &nonce, 600.0)
+        .await?;
+    ensure!(found.len() == 1 && found[0].id == created.id);
+
+    invoices.release_stale_nonce(user.id, &nonce, 600.0).await?;
+    ensure!(
+        invoices
+            .get_pending_by_nonce(user.id, &nonce, 600.0)
+            .await?
+            .len()
+            == 1
+    );
+    invoices.release_stale_nonce(user.id, &nonce, 0.0).await?;
+    ensure!(invoices
+        .get_pending_by_nonce(user.id, &nonce, 600.0)
+        .await?
+        .is_empty());
+    invoices.create(invoice).await?;
+    Ok(())
+}
+
+async fn events(users: &dyn UserRepo, invoices: &dyn InvoiceRepo) -> Result<()> {
+    let user = new_user(users).await?;
+    let invoice = invoices.create(new_invoice(&user, 1)).await?;
+    ensure!(
+        invoices
+            .record_event(invoice.id, InvoiceStage::Created, None, None)
+            .await?
+    );
+    ensure!(
+        invoices
+            .record_event(
+                invoice.id,
+                InvoiceStage::WaitingForPayment,
+                Some("waiting"),
+                None,
+            )
+            .await?
+    );
+    // replayed stages are ignored
+    ensure!(
+        !invoices
+            .record_event(invoice.id, InvoiceStage::Created, None, None)
+            .await?
+    );
+    let current = invoices
+        .get_by_op_id(&invoice.op_id)
+        .await?
+        .context("invoice")?;
+    ensure!(current.stage == Some(InvoiceStage::WaitingForPayment));
+    ensure!(current.state == InvoiceState::Pending);
+
+    ensure!(
+        invoices
+            .record_event(
+                invoice.id,
+                InvoiceStage::Claimed,
+                None,
+                Some(InvoiceState::Settled),
+            )
+            .await?
+    );
+    // a second subscriber to the same operation doesn't settle it again
+    ensure!(
+        !invoices
+            .record_event(
+                invoice.id,
+                InvoiceStage::Claimed,
+                None,
+                Some(InvoiceState::Settled),
+            )
+            .await?
+    );
+    let current = invoices
+        .get_by_op_id(&invoice.op_id)
+        .await?
+        .context("invoice")?;
+    ensure!(current.stage == Some(InvoiceStage::Claimed));
+    ensure!(current.state == InvoiceState::Settled && current.settled_at.is_some());
+
+    // a late outcome doesn't replace the one recorded
+    ensure!(
+        !invoices
+            .record_event(
+                invoice.id,
+                InvoiceStage::Canceled,
+                None,
+                Some(InvoiceState::Cancelled),
+            )
+            .await?
+    );
+    let current = invoices
+        .get_by_op_id(&invoice.op_id)
+        .await?
+        .context("invoice")?;
+    ensure!(current.stage == Some(InvoiceStage::Claimed));
+    ensure!(current.state == InvoiceState::Settled);
+
+    let events = invoices.get_events(invoice.id).await?;
+    let stages: Vec<InvoiceStage> = events.iter().map(|event| event.stage).collect();
+    ensure!(
+        stages
+            == [
+                InvoiceStage::Created,
+                InvoiceStage::WaitingForPayment,
+                InvoiceStage::Claimed
+            ],
+        "unexpected stages {stages:?}"
+    );
+    ensure!(events[1].reason.as_deref() == Some("waiting"));
+    ensure!(invoices
+        .get_events(invoice.id + 1_000_000)
+        .await?
+        .is_empty());
+    Ok(())
+}
+
+async fn transitions(users: &dyn UserRepo, invoices: &dyn InvoiceRepo) -> Result<()> {
+    let user = new_user(users).await?;
+    let invoice = invoices.create(new_invoice(&user, 1)).await?;
+    invoices
+        .record_event(
+            invoice.id,
+            InvoiceStage::Canceled,
+            None,
+            Some(InvoiceState::Cancelled),
+        )
+        .await?;
+
+    // a correction replaces even a final stage, and is recorded
+    ensure!(
+        invoices
+            .transition_state(
+                invoice.id,
+                InvoiceState::Cancelled,
+                InvoiceState::Settled,
+                InvoiceStage::Claimed,
+                Some("corrected"),
+            )
+            .await?
+    );
+    // the second of two racing corrections finds it already moved
+    ensure!(
+        !invoices
+            .transition_state(
+                invoice.id,
+                InvoiceState::Cancelled,
+                InvoiceState::Settled,
+                InvoiceStage::Claimed,
+                Some("corrected"),
+            )
+            .await?
+    );
+    let current = invoices
+        .get_by_op_id(&invoice.op_id)
+        .await?
+        .context("invoice")?;
+    ensure!(current.state == InvoiceState::Settled && current.settled_at.is_some());
+    ensure!(current.stage == Some(InvoiceStage::Claimed));
+    let stages: Vec<InvoiceStage> = invoices
+        .get_events(invoice.id)
+        .await?
+        .iter()
+        .map(|event| event.stage)
+        .collect();
+    ensure!(
+        stages == [InvoiceStage::Canceled, InvoiceStage::Claimed],
+        "unexpected stages {stages:?}"
+    );
+    Ok(())
+}
+
+/// The shared buckets hold `burst` tokens, refill over time and start full
+/// again once pruned.
+async fn rate_limit_buckets(limits: &dyn RateLimitRepo) -> Result<()> {
+    let key = format!("test:{}", random_hex(16));
+    for _ in 0..2 {
+        ensure!(limits.take_token(&key, 2.0, 0.001).await?);
+    }
+    ensure!(!limits.take_token(&key, 2.0, 0.001).await?);
+
+    // refilled at 20 tokens a second, but capped at the burst
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    ensure!(limits.take_token(&key, 2.0, 20.0).await?);
+    ensure!(limits.take_token(&key, 2.0, 0.001).await?);
+    ensure!(!limits.take_token(&key, 2.0, 0.001).await?);
+
+    let idle = format!("test:{}", random_hex(16));
+    ensure!(limits.take_token(&idle, 1.0, 0.001).await?);
+    ensure!(!limits.take_token(&idle, 1.0, 0.001).await?);
+    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    ensure!(limits.prune(0.01).await? >= 1);
+    ensure!(limits.take_token(&idle, 1.0, 0.001).await?);
+    Ok(())
+}
+
+/// A migrated Postgres from `TEST_DATABASE_URL`.
+async fn test_db() -> Db {
+    let url = std::env::var("TEST_DATABASE_URL").expect("TEST_DATABASE_URL must be set");
+    let db = Db::new(url).await.expect("connect to TEST_DATABASE_URL");
+    db.setup_schema().await.expect("migrate test database");
+    db
+}
+
+/// A migrated SQLite database that lives as long as its pool.
+#[cfg(feature = "sqlite")]
+async fn test_sqlite() -> super::sqlite::SqliteDb {
+    let url = format!(
+        "sqlite:file:replex-{}?mode=memory&cache=shared",
+        random_hex(16)
+    );
+    let db = super::sqlite::SqliteDb::new(&url).expect("open SQLite");
+    db.setup_schema().await.expect("migrate SQLite");
+    db
+}
+
+macro_rules! conformance {
+    ($($case:ident),* $(,)?) => {
+        mod memory {
+            $(
+                #[tokio::test]
+                async fn $case() {
+                    let store = super::MemoryStore::default();
+                    super::$case(&store, &store).await.unwrap();
+                }
+            )*
+        }
+
+        #[cfg(feature = "sqlite")]
+        mod sqlite {
+            $(
+                #[tokio::test]
+                async fn $case() {
+                    let db = super::test_sqlite().await;
+                    super::$case(&db.users(), &db.invoices()).await.unwrap();
+                }
+            )*
+        }
+
+        mod postgres {
+            $(
+                #[tokio::test]
+                #[ignore = "needs TEST_DATABASE_URL, run with --ignored"]
+                async fn $case() {
+                    let db = super::test_db().await;
+                    super::$case(&db.users(), &db.invoices()).await.unwrap();
+                }
+            )*
+        }
+    };
+}
+
+conformance!(
+    users_round_trip,
+    unique_users,
+    invoice_lifecycle,
+    unique_invoices,
+    create_with_tweak,
+    pending_pages,
+    nonces,
+    events,
+    transitions,
+);
+
+#[tokio::test]
+#[ignore = "needs TEST_DATABASE_URL, run with --ignored"]
+async fn postgres_rate_limit_buckets() {
+    let db = test_db().await;
+    rate_limit_buckets(&db.rate_limits()).await.unwrap();
+}
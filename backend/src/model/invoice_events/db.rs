@@ -0,0 +1,43 @@
+use crate::model::Db;
+use anyhow::Result;
+
+use super::{InvoiceEvent, InvoiceStage};
+
+#[derive(Clone)]
+pub struct InvoiceEventDb(pub Db);
+
+impl InvoiceEventDb {
+    /// Records `stage` and makes it the invoice's current one, unless the
+    /// invoice is already there, further along, or finished (stage 4 and up).
+    /// Returns the new event.
+    pub async fn record(
+        &self,
+        invoice_id: i32,
+        stage: InvoiceStage,
+        reason: Option<&str>,
+    ) -> Result<Option<InvoiceEvent>> {
+        let sql = "WITH advanced AS (UPDATE invoices SET stage = $2 WHERE id = $1 AND (stage IS NULL OR (stage < $2 AND stage < 4)) RETURNING id) INSERT INTO invoice_events (invoice_id, stage, reason) SELECT id, $2, $3 FROM advanced RETURNING *";
+        self.0
+            .query_opt::<InvoiceEvent>(sql, &[&invoice_id, &stage, &reason])
+            .await
+    }
+
+    /// Records `stage` and makes it the invoice's current one whatever it
+    /// was, for corrections from the operation's outcome.
+    pub async fn correct(
+        &self,
+        invoice_id: i32,
+        stage: InvoiceStage,
+        reason: Option<&str>,
+    ) -> Result<InvoiceEvent> {
+        let sql = "WITH corrected AS (UPDATE invoices SET stage = $2 WHERE id = $1 RETURNING id) INSERT INTO invoice_events (invoice_id, stage, reason) SELECT id, $2, $3 FROM corrected RETURNING *";
+        self.0
+            .query_one::<InvoiceEvent>(sql, &[&invoice_id, &stage, &reason])
+            .await
+    }
+
+    pub async fn get_by_invoice(&self, invoice_id: i32) -> Result<Vec<InvoiceEvent>> {
+        let sql = "SELECT * FROM invoice_events WHERE invoice_id = $1 ORDER BY created_at, id";
+        self.0.query(sql, &[&invoice_id]).await
+    }
+}
@@ -0,0 +1,126 @@
+pub mod db;
+
+use std::cmp::Ordering;
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use multimint::fedimint_ln_client::LnReceiveState;
+use postgres_from_row::FromRow;
+use postgres_types::{FromSql, ToSql};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::Row;
+
+/// Where an invoice is in the federation's receive flow. Ordered, so a
+/// resubscription replaying earlier states never moves an invoice back. The
+/// two outcomes are final and incomparable: only a reconciler correction
+/// replaces one with the other.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+#[repr(i32)]
+pub enum InvoiceStage {
+    Created = 0,
+    WaitingForPayment = 1,
+    /// The gateway paid into the incoming contract.
+    Funded = 2,
+    /// Claiming the contract's ecash from the federation.
+    AwaitingFunds = 3,
+    Claimed = 4,
+    Canceled = 5,
+}
+
+impl InvoiceStage {
+    pub fn from_receive_state(state: &LnReceiveState) -> Self {
+        match state {
+            LnReceiveState::Created => Self::Created,
+            LnReceiveState::WaitingForPayment { .. } => Self::WaitingForPayment,
+            LnReceiveState::Funded => Self::Funded,
+            LnReceiveState::AwaitingFunds => Self::AwaitingFunds,
+            LnReceiveState::Claimed => Self::Claimed,
+            LnReceiveState::Canceled { .. } => Self::Canceled,
+        }
+    }
+
+    pub fn is_terminal(self) -> bool {
+        matches!(self, Self::Claimed | Self::Canceled)
+    }
+}
+
+impl PartialOrd for InvoiceStage {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self != other && self.is_terminal() && other.is_terminal() {
+            return None;
+        }
+        (*self as i32).partial_cmp(&(*other as i32))
+    }
+}
+
+impl FromSql<'_> for InvoiceStage {
+    fn accepts(ty: &postgres_types::Type) -> bool {
+        ty.name() == "int4"
+    }
+
+    fn from_sql(
+        ty: &postgres_types::Type,
+        raw: &[u8],
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        let value = i32::from_sql(ty, raw)?;
+        match value {
+            0 => Ok(InvoiceStage::Created),
+            1 => Ok(InvoiceStage::WaitingForPayment),
+            2 => Ok(InvoiceStage::Funded),
+            3 => Ok(InvoiceStage::AwaitingFunds),
+            4 => Ok(InvoiceStage::Claimed),
+            5 => Ok(InvoiceStage::Canceled),
+            _ => Err(format!("Invalid invoice stage: {}", value).into()),
+        }
+    }
+}
+
+impl ToSql for InvoiceStage {
+    fn to_sql(
+        &self,
+        ty: &postgres_types::Type,
+        out: &mut bytes::BytesMut,
+    ) -> Result<tokio_postgres::types::IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        (*self as i32).to_sql(ty, out)
+    }
+
+    fn accepts(ty: &postgres_types::Type) -> bool {
+        ty.name() == "int4"
+    }
+
+    fn to_sql_checked(
+        &self,
+        ty: &postgres_types::Type,
+        out: &mut bytes::BytesMut,
+    ) -> Result<tokio_postgres::types::IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        (*self as i32).to_sql_checked(ty, out)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct InvoiceEvent {
+    pub id: i32,
+    pub invoice_id: i32,
+    pub stage: InvoiceStage,
+    /// The raw state as reported by the federation client, or why the
+    /// server moved the invoice itself.
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl FromRow for InvoiceEvent {
+    fn from_row(row: &Row) -> Self {
+        Self::try_from_row(row).expect("Decoding row failed")
+    }
+
+    fn try_from_row(row: &Row) -> Result<Self, tokio_postgres::Error> {
+        Ok(InvoiceEvent {
+            id: row.get("id"),
+            invoice_id: row.get("invoice_id"),
+            stage: row.get("stage"),
+            reason: row.get("reason"),
+            created_at: row.get("created_at"),
+        })
+    }
+}
@@ -1,14 +1,19 @@
+use crate::model::invoice_events::{InvoiceEvent, InvoiceStage};
+use crate::model::repo::{InvoiceRepo, UserRepo};
 use crate::model::Db;
 use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
 
 use super::{Invoice, InvoiceForCreate, InvoiceState};
 
 #[derive(Clone)]
 pub struct InvoiceDb(pub Db);
 
-impl InvoiceDb {
-    pub async fn create(&self, invoice: InvoiceForCreate) -> Result<Invoice> {
-        let sql = "INSERT INTO invoices (op_id, federation_id, user_id, user_pubkey, amount, bolt11, tweak, state) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING *";
+#[async_trait]
+impl InvoiceRepo for InvoiceDb {
+    async fn create(&self, invoice: InvoiceForCreate) -> Result<Invoice> {
+        let sql = "INSERT INTO invoices (op_id, federation_id, user_id, user_pubkey, amount, bolt11, tweak, state, nonce, fiat_amount, fiat_currency, fiat_rate, preimage, comment, payer, payment_hash) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16) RETURNING *";
         self.0
             .query_one::<Invoice>(
                 sql,
@@ -21,25 +26,193 @@ impl InvoiceDb {
                     &invoice.bolt11,
                     &invoice.tweak,
                     &invoice.state,
+                    &invoice.nonce,
+                    &invoice.fiat_amount,
+                    &invoice.fiat_currency,
+                    &invoice.fiat_rate,
+                    &invoice.preimage,
+                    &invoice.comment,
+                    &invoice.payer,
+                    &invoice.payment_hash,
                 ],
             )
             .await
     }
 
-    // Id on invoice is the operation id from the fedimint client
-    pub async fn get_by_op_id(&self, op_id: &str) -> Result<Option<Invoice>> {
+    async fn create_with_tweak(&self, invoice: InvoiceForCreate) -> Result<Invoice> {
+        self.0
+            .transaction(|tx| {
+                let invoice = invoice.clone();
+                async move {
+                    tx.users()
+                        .update_tweak(invoice.user_id, invoice.tweak)
+                        .await?;
+                    tx.invoices().create(invoice).await
+                }
+            })
+            .await
+    }
+
+    async fn get_by_op_id(&self, op_id: &str) -> Result<Option<Invoice>> {
         let sql = "SELECT * FROM invoices WHERE op_id = $1";
         self.0.query_opt::<Invoice>(sql, &[&op_id]).await
     }
 
-    pub async fn update_state(&self, id: i32, state: InvoiceState) -> Result<()> {
-        let sql = "UPDATE invoices SET state = $1 WHERE id = $2";
+    async fn update_state(&self, id: i32, state: InvoiceState) -> Result<()> {
+        let sql = "UPDATE invoices SET state = $1, settled_at = CASE WHEN $1 = 1 THEN now() ELSE settled_at END WHERE id = $2";
         let _ = self.0.execute(sql, &[&state, &id]).await?;
         Ok(())
     }
 
-    pub async fn get_by_state(&self, state: InvoiceState) -> Result<Vec<Invoice>> {
+    async fn transition_state(
+        &self,
+        invoice_id: i32,
+        from: InvoiceState,
+        to: InvoiceState,
+        stage: InvoiceStage,
+        reason: Option<&str>,
+    ) -> Result<bool> {
+        self.0
+            .transaction(|tx| async move {
+                let sql = "UPDATE invoices SET state = $3, settled_at = CASE WHEN $3 = 1 THEN now() ELSE settled_at END WHERE id = $1 AND state = $2";
+                let moved = tx.invoices().0.execute(sql, &[&invoice_id, &from, &to]).await? > 0;
+                if moved {
+                    tx.invoice_events()
+                        .correct(invoice_id, stage, reason)
+                        .await?;
+                }
+                Ok(moved)
+            })
+            .await
+    }
+
+    async fn get_by_state(&self, state: InvoiceState) -> Result<Vec<Invoice>> {
         let sql = "SELECT * FROM invoices WHERE state = $1";
         self.0.query(sql, &[&state]).await
     }
+
+    async fn get_pending_by_nonce(
+        &self,
+        user_id: i32,
+        nonce: &str,
+        window_secs: f64,
+    ) -> Result<Vec<Invoice>> {
+        let sql = "SELECT * FROM invoices WHERE user_id = $1 AND nonce = $2 AND state = $3 AND created_at > now() - make_interval(secs => $4)";
+        self.0
+            .query(
+                sql,
+                &[&user_id, &nonce, &InvoiceState::Pending, &window_secs],
+            )
+            .await
+    }
+
+    async fn release_stale_nonce(&self, user_id: i32, nonce: &str, window_secs: f64) -> Result<()> {
+        let sql = "UPDATE invoices SET nonce = NULL WHERE user_id = $1 AND nonce = $2 AND state = $3 AND created_at <= now() - make_interval(secs => $4)";
+        self.0
+            .execute(
+                sql,
+                &[&user_id, &nonce, &InvoiceState::Pending, &window_secs],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn get_page_by_state(
+        &self,
+        state: InvoiceState,
+        after_id: i32,
+        limit: i64,
+    ) -> Result<Vec<Invoice>> {
+        let sql = "SELECT * FROM invoices WHERE state = $1 AND id > $2 ORDER BY id LIMIT $3";
+        self.0.query(sql, &[&state, &after_id, &limit]).await
+    }
+
+    async fn get_recent_settled(&self, user_pubkey: &str, limit: i64) -> Result<Vec<Invoice>> {
+        let sql = "SELECT * FROM invoices WHERE user_pubkey = $1 AND state = $2 ORDER BY settled_at DESC NULLS LAST, id DESC LIMIT $3";
+        self.0
+            .query(sql, &[&user_pubkey, &InvoiceState::Settled, &limit])
+            .await
+    }
+
+    async fn get_unclaimed(&self, user_pubkey: &str) -> Result<Vec<Invoice>> {
+        let sql = "SELECT * FROM invoices WHERE user_pubkey = $1 AND state = $2 AND claimed_at IS NULL ORDER BY id";
+        self.0
+            .query(sql, &[&user_pubkey, &InvoiceState::Settled])
+            .await
+    }
+
+    async fn mark_claimed(&self, user_pubkey: &str, op_ids: &[String]) -> Result<Vec<Invoice>> {
+        let sql = "UPDATE invoices SET claimed_at = now() WHERE user_pubkey = $1 AND op_id = ANY($2) AND state = $3 AND claimed_at IS NULL RETURNING *";
+        self.0
+            .query(sql, &[&user_pubkey, &op_ids, &InvoiceState::Settled])
+            .await
+    }
+
+    async fn get_by_payment_hash(
+        &self,
+        user_id: i32,
+        payment_hash: &str,
+    ) -> Result<Option<Invoice>> {
+        let sql = "SELECT * FROM invoices WHERE user_id = $1 AND payment_hash = $2";
+        self.0
+            .query_opt::<Invoice>(sql, &[&user_id, &payment_hash])
+            .await
+    }
+
+    async fn get_by_bolt11(&self, user_id: i32, bolt11: &str) -> Result<Option<Invoice>> {
+        let sql = "SELECT * FROM invoices WHERE user_id = $1 AND bolt11 = $2";
+        self.0.query_opt::<Invoice>(sql, &[&user_id, &bolt11]).await
+    }
+
+    async fn list_for_user(
+        &self,
+        user_id: i32,
+        from: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+        include_unpaid: bool,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Invoice>> {
+        let sql = "SELECT * FROM invoices WHERE user_id = $1 AND ($2::timestamptz IS NULL OR created_at >= $2) AND ($3::timestamptz IS NULL OR created_at <= $3) AND ($4 OR state = $5) ORDER BY created_at DESC, id DESC LIMIT $6 OFFSET $7";
+        self.0
+            .query(
+                sql,
+                &[
+                    &user_id,
+                    &from,
+                    &until,
+                    &include_unpaid,
+                    &InvoiceState::Settled,
+                    &limit,
+                    &offset,
+                ],
+            )
+            .await
+    }
+
+    async fn record_event(
+        &self,
+        invoice_id: i32,
+        stage: InvoiceStage,
+        reason: Option<&str>,
+        state: Option<InvoiceState>,
+    ) -> Result<bool> {
+        self.0
+            .transaction(|tx| async move {
+                let recorded = tx
+                    .invoice_events()
+                    .record(invoice_id, stage, reason)
+                    .await?
+                    .is_some();
+                if let (true, Some(state)) = (recorded, state) {
+                    tx.invoices().update_state(invoice_id, state).await?;
+                }
+                Ok(recorded)
+            })
+            .await
+    }
+
+    async fn get_events(&self, invoice_id: i32) -> Result<Vec<InvoiceEvent>> {
+        self.0.invoice_events().get_by_invoice(invoice_id).await
+    }
 }
@@ -1,11 +1,14 @@
 pub mod db;
 
 use anyhow::Result;
+use chrono::{DateTime, Utc};
 use postgres_from_row::FromRow;
 use postgres_types::{FromSql, ToSql};
 use serde::{Deserialize, Serialize};
 use tokio_postgres::Row;
 
+use super::invoice_events::InvoiceStage;
+
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
 #[repr(i32)]
 pub enum InvoiceState {
@@ -65,6 +68,14 @@ pub struct InvoiceForCreate {
     pub bolt11: String,
     pub tweak: i64,
     pub state: InvoiceState,
+    pub nonce: Option<String>,
+    pub fiat_amount: Option<i64>,
+    pub fiat_currency: Option<String>,
+    pub fiat_rate: Option<f64>,
+    pub preimage: Option<String>,
+    pub comment: Option<String>,
+    pub payer: Option<String>,
+    pub payment_hash: Option<String>,
 }
 
 impl InvoiceForCreate {
@@ -83,6 +94,14 @@ pub struct InvoiceForCreateBuilder {
     bolt11: Option<String>,
     tweak: Option<i64>,
     state: Option<InvoiceState>,
+    nonce: Option<String>,
+    fiat_amount: Option<i64>,
+    fiat_currency: Option<String>,
+    fiat_rate: Option<f64>,
+    preimage: Option<String>,
+    comment: Option<String>,
+    payer: Option<String>,
+    payment_hash: Option<String>,
 }
 
 impl InvoiceForCreateBuilder {
@@ -126,6 +145,46 @@ impl InvoiceForCreateBuilder {
         self
     }
 
+    pub fn nonce(mut self, nonce: String) -> Self {
+        self.nonce = Some(nonce);
+        self
+    }
+
+    pub fn fiat_amount(mut self, fiat_amount: i64) -> Self {
+        self.fiat_amount = Some(fiat_amount);
+        self
+    }
+
+    pub fn fiat_currency(mut self, fiat_currency: String) -> Self {
+        self.fiat_currency = Some(fiat_currency);
+        self
+    }
+
+    pub fn fiat_rate(mut self, fiat_rate: f64) -> Self {
+        self.fiat_rate = Some(fiat_rate);
+        self
+    }
+
+    pub fn preimage(mut self, preimage: String) -> Self {
+        self.preimage = Some(preimage);
+        self
+    }
+
+    pub fn comment(mut self, comment: String) -> Self {
+        self.comment = Some(comment);
+        self
+    }
+
+    pub fn payer(mut self, payer: String) -> Self {
+        self.payer = Some(payer);
+        self
+    }
+
+    pub fn payment_hash(mut self, payment_hash: String) -> Self {
+        self.payment_hash = Some(payment_hash);
+        self
+    }
+
     pub fn build(self) -> anyhow::Result<InvoiceForCreate> {
         Ok(InvoiceForCreate {
             op_id: self
@@ -152,6 +211,14 @@ impl InvoiceForCreateBuilder {
             state: self
                 .state
                 .ok_or_else(|| anyhow::anyhow!("state is required"))?,
+            nonce: self.nonce,
+            fiat_amount: self.fiat_amount,
+            fiat_currency: self.fiat_currency,
+            fiat_rate: self.fiat_rate,
+            preimage: self.preimage,
+            comment: self.comment,
+            payer: self.payer,
+            payment_hash: self.payment_hash,
         })
     }
 }
@@ -167,6 +234,23 @@ pub struct Invoice {
     pub amount: i64,
     pub state: InvoiceState,
     pub tweak: i64,
+    pub nonce: Option<String>,
+    /// Amount in the smallest unit of `fiat_currency` for currency-denominated invoices.
+    pub fiat_amount: Option<i64>,
+    pub fiat_currency: Option<String>,
+    /// Millisatoshis per smallest unit of `fiat_currency`, locked at creation.
+    pub fiat_rate: Option<f64>,
+    /// Hex encoded; never serialized, it unlocks `aes` success actions.
+    #[serde(skip_serializing)]
+    pub preimage: Option<String>,
+    pub comment: Option<String>,
+    /// Hex pubkey of the zap request author, when paid as a zap.
+    pub payer: Option<String>,
+    pub settled_at: Option<DateTime<Utc>>,
+    pub payment_hash: Option<String>,
+    pub created_at: Option<DateTime<Utc>>,
+    /// Latest recorded receive stage, finer grained than `state`.
+    pub stage: Option<InvoiceStage>,
 }
 
 impl FromRow for Invoice {
@@ -185,6 +269,17 @@ impl FromRow for Invoice {
             amount: row.get("amount"),
             state: row.get("state"),
             tweak: row.get("tweak"),
+            nonce: row.get("nonce"),
+            fiat_amount: row.get("fiat_amount"),
+            fiat_currency: row.get("fiat_currency"),
+            fiat_rate: row.get("fiat_rate"),
+            preimage: row.get("preimage"),
+            comment: row.get("comment"),
+            payer: row.get("payer"),
+            settled_at: row.get("settled_at"),
+            payment_hash: row.get("payment_hash"),
+            created_at: row.get("created_at"),
+            stage: row.get("stage"),
         })
     }
 }
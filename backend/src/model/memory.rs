@@ -0,0 +1,489 @@
+//! Users and invoices held in memory, for tests that shouldn't need Postgres.
+//! Mirrors the tables' unique constraints and foreign keys.
+
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+
+use super::invoice_events::{InvoiceEvent, InvoiceStage};
+use super::invoices::{Invoice, InvoiceForCreate, InvoiceState};
+use super::repo::{InvoiceRepo, UserRepo};
+use super::users::success_action::SuccessActionTemplate;
+use super::users::{User, UserForCreate, UserForUpdate};
+
+struct StoredInvoice {
+    invoice: Invoice,
+    claimed_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Default)]
+struct Tables {
+    users: Vec<User>,
+    invoices: Vec<StoredInvoice>,
+    events: Vec<InvoiceEvent>,
+}
+
+impl Tables {
+    fn user_mut(&mut self, id: i32) -> Result<&mut User> {
+        self.users
+            .iter_mut()
+            .find(|user| user.id == id)
+            .ok_or_else(|| anyhow!("no user with id {id}"))
+    }
+
+    fn invoice_mut(&mut self, id: i32) -> Option<&mut StoredInvoice> {
+        self.invoices
+            .iter_mut()
+            .find(|stored| stored.invoice.id == id)
+    }
+
+    fn check_unique_user(&self, id: i32, name: &str, replit_id: &str, pubkey: &str) -> Result<()> {
+        for user in self.users.iter().filter(|user| user.id != id) {
+            if user.name == name || user.replit_id == replit_id || user.pubkey == pubkey {
+                bail!("duplicate key value violates unique constraint on users");
+            }
+        }
+        Ok(())
+    }
+
+    fn insert_invoice(&mut self, invoice: InvoiceForCreate) -> Result<Invoice> {
+        if !self.users.iter().any(|user| user.id == invoice.user_id) {
+            bail!("invoice references missing user {}", invoice.user_id);
+        }
+        for stored in &self.invoices {
+            let existing = &stored.invoice;
+            if existing.op_id == invoice.op_id {
+                bail!("duplicate key value violates unique constraint on invoices.op_id");
+            }
+            if invoice.nonce.is_some()
+                && invoice.state == InvoiceState::Pending
+                && existing.state == InvoiceState::Pending
+                && existing.user_id == invoice.user_id
+                && existing.amount == invoice.amount
+                && existing.nonce == invoice.nonce
+            {
+                bail!("duplicate key value violates unique constraint idx_invoice_pending_nonce");
+            }
+        }
+
+        let invoice = Invoice {
+            id: self.invoices.len() as i32 + 1,
+            federation_id: invoice.federation_id,
+            op_id: invoice.op_id,
+            user_id: invoice.user_id,
+            user_pubkey: invoice.user_pubkey,
+            bolt11: invoice.bolt11,
+            amount: invoice.amount,
+            state: invoice.state,
+            tweak: invoice.tweak,
+            nonce: invoice.nonce,
+            fiat_amount: invoice.fiat_amount,
+            fiat_currency: invoice.fiat_currency,
+            fiat_rate: invoice.fiat_rate,
+            preimage: invoice.preimage,
+            comment: invoice.comment,
+            payer: invoice.payer,
+            settled_at: None,
+            payment_hash: invoice.payment_hash,
+            created_at: Some(Utc::now()),
+            stage: None,
+        };
+        self.invoices.push(StoredInvoice {
+            invoice: invoice.clone(),
+            claimed_at: None,
+        });
+        Ok(invoice)
+    }
+
+    fn set_state(&mut self, id: i32, state: InvoiceState) {
+        if let Some(stored) = self.invoice_mut(id) {
+            stored.invoice.state = state;
+            if state == InvoiceState::Settled {
+                stored.invoice.settled_at = Some(Utc::now());
+            }
+        }
+    }
+
+    /// Makes `stage` the invoice's current one and records it.
+    fn set_stage(&mut self, invoice_id: i32, stage: InvoiceStage, reason: Option<&str>) {
+        if let Some(stored) = self.invoice_mut(invoice_id) {
+            stored.invoice.stage = Some(stage);
+            let event = InvoiceEvent {
+                id: self.events.len() as i32 + 1,
+                invoice_id,
+                stage,
+                reason: reason.map(str::to_string),
+                created_at: Utc::now(),
+            };
+            self.events.push(event);
+        }
+    }
+
+    fn invoices(&self) -> impl Iterator<Item = &Invoice> {
+        self.invoices.iter().map(|stored| &stored.invoice)
+    }
+}
+
+/// Cheap to clone; clones share the same tables.
+#[derive(Clone, Default)]
+pub struct MemoryStore(Arc<Mutex<Tables>>);
+
+impl MemoryStore {
+    fn tables(&self) -> std::sync::MutexGuard<'_, Tables> {
+        self.0.lock().expect("memory store lock poisoned")
+    }
+}
+
+fn within_window(invoice: &Invoice, window_secs: f64) -> bool {
+    let window = Duration::milliseconds((window_secs * 1000.0) as i64);
+    invoice
+        .created_at
+        .is_some_and(|created_at| created_at > Utc::now() - window)
+}
+
+#[async_trait]
+impl UserRepo for MemoryStore {
+    async fn create(&self, user: UserForCreate) -> Result<User> {
+        let mut tables = self.tables();
+        tables.check_unique_user(0, &user.name, &user.replit_id, &user.pubkey)?;
+        let user = User {
+            id: tables.users.len() as i32 + 1,
+            name: user.name,
+            replit_id: user.replit_id,
+            replit_profile_pic: user.replit_profile_pic,
+            pubkey: user.pubkey,
+            last_tweak: user.last_tweak,
+            relays: user.relays,
+            federation_ids: user.federation_ids,
+            connection_code_uuid: user.connection_code_uuid,
+            success_action: None,
+        };
+        tables.users.push(user.clone());
+        Ok(user)
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<User>> {
+        Ok(self
+            .tables()
+            .users
+            .iter()
+            .find(|user| user.id == id)
+            .cloned())
+    }
+
+    async fn get_by_name(&self, username: &str) -> Result<Option<User>> {
+        Ok(self
+            .tables()
+            .users
+            .iter()
+            .find(|user| user.name == username)
+            .cloned())
+    }
+
+    async fn get_by_pubkey(&self, pubkey: &str) -> Result<Option<User>> {
+        Ok(self
+            .tables()
+            .users
+            .iter()
+            .find(|user| user.pubkey == pubkey)
+            .cloned())
+    }
+
+    async fn update(&self, id: i32, update: UserForUpdate) -> Result<User> {
+        let mut tables = self.tables();
+        let mut user = tables.user_mut(id)?.clone();
+        if let Some(name) = update.name {
+            user.name = name;
+        }
+        if let Some(replit_id) = update.replit_id {
+            user.replit_id = replit_id;
+        }
+        if let Some(replit_profile_pic) = update.replit_profile_pic {
+            user.replit_profile_pic = replit_profile_pic;
+        }
+        if let Some(pubkey) = update.pubkey {
+            user.pubkey = pubkey;
+        }
+        if let Some(relays) = update.relays {
+            user.relays = relays;
+        }
+        if let Some(federation_ids) = update.federation_ids {
+            user.federation_ids = federation_ids;
+        }
+        if let Some(connection_code_uuid) = update.connection_code_uuid {
+            user.connection_code_uuid = connection_code_uuid;
+        }
+        if let Some(last_tweak) = update.last_tweak {
+            user.last_tweak = last_tweak;
+        }
+        tables.check_unique_user(id, &user.name, &user.replit_id, &user.pubkey)?;
+        *tables.user_mut(id)? = user.clone();
+        Ok(user)
+    }
+
+    async fn update_tweak(&self, user_id: i32, tweak: i64) -> Result<()> {
+        if let Some(user) = self
+            .tables()
+            .users
+            .iter_mut()
+            .find(|user| user.id == user_id)
+        {
+            user.last_tweak = user.last_tweak.max(tweak);
+        }
+        Ok(())
+    }
+
+    async fn update_success_action(
+        &self,
+        user: &User,
+        success_action: Option<&SuccessActionTemplate>,
+    ) -> Result<User> {
+        let mut tables = self.tables();
+        let user = tables.user_mut(user.id)?;
+        user.success_action = success_action.cloned();
+        Ok(user.clone())
+    }
+}
+
+#[async_trait]
+impl InvoiceRepo for MemoryStore {
+    async fn create(&self, invoice: InvoiceForCreate) -> Result<Invoice> {
+        self.tables().insert_invoice(invoice)
+    }
+
+    async fn create_with_tweak(&self, invoice: InvoiceForCreate) -> Result<Invoice> {
+        let mut tables = self.tables();
+        let (user_id, tweak) = (invoice.user_id, invoice.tweak);
+        let invoice = tables.insert_invoice(invoice)?;
+        let user = tables.user_mut(user_id)?;
+        user.last_tweak = user.last_tweak.max(tweak);
+        Ok(invoice)
+    }
+
+    async fn get_by_op_id(&self, op_id: &str) -> Result<Option<Invoice>> {
+        Ok(self
+            .tables()
+            .invoices()
+            .find(|invoice| invoice.op_id == op_id)
+            .cloned())
+    }
+
+    async fn update_state(&self, id: i32, state: InvoiceState) -> Result<()> {
+        self.tables().set_state(id, state);
+        Ok(())
+    }
+
+    async fn transition_state(
+        &self,
+        invoice_id: i32,
+        from: InvoiceState,
+        to: InvoiceState,
+        stage: InvoiceStage,
+        reason: Option<&str>,
+    ) -> Result<bool> {
+        let mut tables = self.tables();
+        let Some(stored) = tables.invoice_mut(invoice_id) else {
+            bail!("no invoice with id {invoice_id}");
+        };
+        if stored.invoice.state != from {
+            return Ok(false);
+        }
+        tables.set_state(invoice_id, to);
+        tables.set_stage(invoice_id, stage, reason);
+        Ok(true)
+    }
+
+    async fn get_by_state(&self, state: InvoiceState) -> Result<Vec<Invoice>> {
+        Ok(self
+            .tables()
+            .invoices()
+            .filter(|invoice| invoice.state == state)
+            .cloned()
+            .collect())
+    }
+
+    async fn get_pending_by_nonce(
+        &self,
+        user_id: i32,
+        nonce: &str,
+        window_secs: f64,
+    ) -> Result<Vec<Invoice>> {
+        Ok(self
+            .tables()
+            .invoices()
+            .filter(|invoice| {
+                invoice.user_id == user_id
+                    && invoice.nonce.as_deref() == Some(nonce)
+                    && invoice.state == InvoiceState::Pending
+                    && within_window(invoice, window_secs)
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn release_stale_nonce(&self, user_id: i32, nonce: &str, window_secs: f64) -> Result<()> {
+        for stored in self.tables().invoices.iter_mut() {
+            let invoice = &mut stored.invoice;
+            if invoice.user_id == user_id
+                && invoice.nonce.as_deref() == Some(nonce)
+                && invoice.state == InvoiceState::Pending
+                && !within_window(invoice, window_secs)
+            {
+                invoice.nonce = None;
+            }
+        }
+        Ok(())
+    }
+
+    async fn get_page_by_state(
+        &self,
+        state: InvoiceState,
+        after_id: i32,
+        limit: i64,
+    ) -> Result<Vec<Invoice>> {
+        let mut page: Vec<Invoice> = self
+            .tables()
+            .invoices()
+            .filter(|invoice| invoice.state == state && invoice.id > after_id)
+            .cloned()
+            .collect();
+        page.sort_by_key(|invoice| invoice.id);
+        page.truncate(limit.max(0) as usize);
+        Ok(page)
+    }
+
+    async fn get_recent_settled(&self, user_pubkey: &str, limit: i64) -> Result<Vec<Invoice>> {
+        let mut invoices: Vec<Invoice> = self
+            .tables()
+            .invoices()
+            .filter(|invoice| {
+                invoice.user_pubkey == user_pubkey && invoice.state == InvoiceState::Settled
+            })
+            .cloned()
+            .collect();
+        // settled_at DESC NULLS LAST, id DESC
+        invoices.sort_by(|a, b| {
+            (b.settled_at.is_some(), b.settled_at, b.id).cmp(&(
+                a.settled_at.is_some(),
+                a.settled_at,
+                a.id,
+            ))
+        });
+        invoices.truncate(limit.max(0) as usize);
+        Ok(invoices)
+    }
+
+    async fn get_unclaimed(&self, user_pubkey: &str) -> Result<Vec<Invoice>> {
+        Ok(self
+            .tables()
+            .invoices
+            .iter()
+            .filter(|stored| {
+                stored.invoice.user_pubkey == user_pubkey
+                    && stored.invoice.state == InvoiceState::Settled
+                    && stored.claimed_at.is_none()
+            })
+            .map(|stored| stored.invoice.clone())
+            .collect())
+    }
+
+    async fn mark_claimed(&self, user_pubkey: &str, op_ids: &[String]) -> Result<Vec<Invoice>> {
+        let now = Utc::now();
+        let mut claimed = Vec::new();
+        for stored in self.tables().invoices.iter_mut() {
+            if stored.invoice.user_pubkey == user_pubkey
+                && op_ids.contains(&stored.invoice.op_id)
+                && stored.invoice.state == InvoiceState::Settled
+                && stored.claimed_at.is_none()
+            {
+                stored.claimed_at = Some(now);
+                claimed.push(stored.invoice.clone());
+            }
+        }
+        Ok(claimed)
+    }
+
+    async fn get_by_payment_hash(
+        &self,
+        user_id: i32,
+        payment_hash: &str,
+    ) -> Result<Option<Invoice>> {
+        Ok(self
+            .tables()
+            .invoices()
+            .find(|invoice| {
+                invoice.user_id == user_id && invoice.payment_hash.as_deref() == Some(payment_hash)
+            })
+            .cloned())
+    }
+
+    async fn get_by_bolt11(&self, user_id: i32, bolt11: &str) -> Result<Option<Invoice>> {
+        Ok(self
+            .tables()
+            .invoices()
+            .find(|invoice| invoice.user_id == user_id && invoice.bolt11 == bolt11)
+            .cloned())
+    }
+
+    async fn list_for_user(
+        &self,
+        user_id: i32,
+        from: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+        include_unpaid: bool,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Invoice>> {
+        let mut invoices: Vec<Invoice> = self
+            .tables()
+            .invoices()
+            .filter(|invoice| {
+                invoice.user_id == user_id
+                    && from.map_or(true, |from| invoice.created_at >= Some(from))
+                    && until.map_or(true, |until| invoice.created_at <= Some(until))
+                    && (include_unpaid || invoice.state == InvoiceState::Settled)
+            })
+            .cloned()
+            .collect();
+        invoices.sort_by(|a, b| (b.created_at, b.id).cmp(&(a.created_at, a.id)));
+        Ok(invoices
+            .into_iter()
+            .skip(offset.max(0) as usize)
+            .take(limit.max(0) as usize)
+            .collect())
+    }
+
+    async fn record_event(
+        &self,
+        invoice_id: i32,
+        stage: InvoiceStage,
+        reason: Option<&str>,
+        state: Option<InvoiceState>,
+    ) -> Result<bool> {
+        let mut tables = self.tables();
+        let Some(stored) = tables.invoice_mut(invoice_id) else {
+            bail!("no invoice with id {invoice_id}");
+        };
+        // finished stages are incomparable with each other, so never left
+        if !stored.invoice.stage.map_or(true, |current| current < stage) {
+            return Ok(false);
+        }
+        tables.set_stage(invoice_id, stage, reason);
+        if let Some(state) = state {
+            tables.set_state(invoice_id, state);
+        }
+        Ok(true)
+    }
+
+    async fn get_events(&self, invoice_id: i32) -> Result<Vec<InvoiceEvent>> {
+        Ok(self
+            .tables()
+            .events
+            .iter()
+            .filter(|event| event.invoice_id == invoice_id)
+            .cloned()
+            .collect())
+    }
+}
@@ -1,16 +1,148 @@
+#[cfg(test)]
+mod conformance;
+pub mod invoice_events;
 pub mod invoices;
+#[cfg(test)]
+pub mod memory;
+pub mod nwc_connections;
+pub mod rate_limits;
+pub mod repo;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod tx;
 pub mod users;
+pub mod withdrawals;
+
+use std::fmt;
+use std::ops::Deref;
+use std::sync::Arc;
 
 use anyhow::Result;
-use deadpool_postgres::{Client, Pool, Runtime};
+use deadpool_postgres::{Client, ClientWrapper, Pool, Runtime, Status};
+use invoice_events::db::InvoiceEventDb;
 use invoices::db::InvoiceDb;
+use nwc_connections::db::NwcConnectionDb;
 use postgres_from_row::FromRow;
+use rate_limits::db::RateLimitDb;
+use repo::{InvoiceRepo, NwcConnectionRepo, RateLimitRepo, UserRepo, WithdrawalRepo};
+use tokio::sync::{Mutex, MutexGuard};
 use tokio_postgres::NoTls;
 use tracing::info;
+use tx::TxConn;
 use users::db::UserDb;
+use withdrawals::db::WithdrawalDb;
+
+/// Applied in order on every start, so each file must be idempotent.
+const SCHEMA: &[&str] = &[
+    include_str!("../../schema/v0.sql"),
+    include_str!("../../schema/v1.sql"),
+    include_str!("../../schema/v2.sql"),
+    include_str!("../../schema/v3.sql"),
+    include_str!("../../schema/v4.sql"),
+    include_str!("../../schema/v5.sql"),
+    include_str!("../../schema/v6.sql"),
+    include_str!("../../schema/v7.sql"),
+    include_str!("../../schema/v8.sql"),
+    include_str!("../../schema/v9.sql"),
+    include_str!("../../schema/v10.sql"),
+];
+
+/// Where users and invoices live, picked by the database URL scheme.
+/// Everything else is only stored in Postgres, so those repos are `None`
+/// when the URL points at SQLite.
+pub struct Storage {
+    pub users: Arc<dyn UserRepo>,
+    pub invoices: Arc<dyn InvoiceRepo>,
+    pub withdrawals: Option<Arc<dyn WithdrawalRepo>>,
+    pub nwc_connections: Option<Arc<dyn NwcConnectionRepo>>,
+    pub rate_limits: Option<Arc<dyn RateLimitRepo>>,
+    /// Only for health checks and pool stats; data goes through the repos.
+    pub postgres: Option<Db>,
+    #[cfg(feature = "sqlite")]
+    pub sqlite: Option<sqlite::SqliteDb>,
+}
+
+impl Storage {
+    /// Connects to `url` and brings its schema up to date.
+    pub async fn connect(url: &str) -> Result<Self> {
+        #[cfg(feature = "sqlite")]
+        if url.starts_with("sqlite:") {
+            let db = sqlite::SqliteDb::new(url)?;
+            db.setup_schema().await?;
+            return Ok(Self {
+                users: Arc::new(db.users()),
+                invoices: Arc::new(db.invoices()),
+                withdrawals: None,
+                nwc_connections: None,
+                rate_limits: None,
+                postgres: None,
+                sqlite: Some(db),
+            });
+        }
+
+        let db = Db::new(url.to_string()).await?;
+        db.setup_schema().await?;
+        Ok(Self {
+            users: Arc::new(db.users()),
+            invoices: Arc::new(db.invoices()),
+            withdrawals: Some(Arc::new(db.withdrawals())),
+            nwc_connections: Some(Arc::new(db.nwc_connections())),
+            rate_limits: Some(Arc::new(db.rate_limits())),
+            postgres: Some(db),
+            #[cfg(feature = "sqlite")]
+            sqlite: None,
+        })
+    }
+
+    /// Users and invoices in a fresh [`memory::MemoryStore`].
+    #[cfg(test)]
+    pub fn memory() -> Self {
+        let store = memory::MemoryStore::default();
+        Self {
+            users: Arc::new(store.clone()),
+            invoices: Arc::new(store),
+            withdrawals: None,
+            nwc_connections: None,
+            rate_limits: None,
+            postgres: None,
+            #[cfg(feature = "sqlite")]
+            sqlite: None,
+        }
+    }
+}
+
+/// The connection pool, or one transaction on it when obtained from a [`tx::Tx`].
+#[derive(Clone)]
+pub struct Db {
+    pool: Pool,
+    tx: Option<Arc<Mutex<TxConn>>>,
+}
+
+impl fmt::Debug for Db {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Db")
+            .field("pool", &self.pool.status())
+            .field("in_transaction", &self.tx.is_some())
+            .finish()
+    }
+}
+
+/// A pooled connection, or the transaction's while its lock is held.
+enum Conn<'a> {
+    Pooled(Client),
+    Tx(MutexGuard<'a, TxConn>),
+}
+
+impl Deref for Conn<'_> {
+    type Target = ClientWrapper;
 
-#[derive(Clone, Debug)]
-pub struct Db(Pool);
+    fn deref(&self) -> &ClientWrapper {
+        match self {
+            Conn::Pooled(client) => client,
+            Conn::Tx(conn) => conn.client.as_ref().expect("transaction connection"),
+        }
+    }
+}
 
 impl Db {
     pub async fn new(db_url: String) -> Result<Db> {
@@ -22,24 +154,36 @@ impl Db {
             pool_config.create_pool(Some(Runtime::Tokio1), NoTls)
         }?;
 
-        Ok(Db(connection_pool))
+        Ok(Db {
+            pool: connection_pool,
+            tx: None,
+        })
     }
 
+    /// A connection from the pool, outside any transaction.
     pub async fn client(&self) -> Result<Client> {
-        let client = self.0.get().await?;
+        let client = self.pool.get().await?;
         Ok(client)
     }
 
+    async fn conn(&self) -> Result<Conn<'_>> {
+        Ok(match &self.tx {
+            Some(conn) => Conn::Tx(conn.lock().await),
+            None => Conn::Pooled(self.client().await?),
+        })
+    }
+
+    pub fn pool_status(&self) -> Status {
+        self.pool.status()
+    }
+
     pub async fn setup_schema(&self) -> Result<()> {
         let client = self.client().await?;
         info!("Setting up schema");
 
-        let schema_sql = include_str!("../../schema/v0.sql");
-        for statement in schema_sql.split(';') {
-            let trimmed = statement.trim();
-            if !trimmed.is_empty() {
-                client.execute(trimmed, &[]).await?;
-            }
+        // whole files at once, so statements may contain `;` (as DO blocks do)
+        for schema_sql in SCHEMA {
+            client.batch_execute(schema_sql).await?;
         }
 
         info!("Schema setup complete");
@@ -54,6 +198,22 @@ impl Db {
     pub fn invoices(&self) -> InvoiceDb {
         InvoiceDb(self.clone())
     }
+
+    pub fn invoice_events(&self) -> InvoiceEventDb {
+        InvoiceEventDb(self.clone())
+    }
+
+    pub fn withdrawals(&self) -> WithdrawalDb {
+        WithdrawalDb(self.clone())
+    }
+
+    pub fn nwc_connections(&self) -> NwcConnectionDb {
+        NwcConnectionDb(self.clone())
+    }
+
+    pub fn rate_limits(&self) -> RateLimitDb {
+        RateLimitDb(self.clone())
+    }
     // --- END TABLES ---
 
     // --- START QUERIES ---
@@ -62,7 +222,7 @@ impl Db {
         sql: &str,
         params: &[&(dyn tokio_postgres::types::ToSql + Sync)],
     ) -> anyhow::Result<u64> {
-        let client = self.client().await?;
+        let client = self.conn().await?;
         let num_rows = client.execute(sql, params).await?;
         Ok(num_rows)
     }
@@ -75,7 +235,7 @@ impl Db {
     where
         T: FromRow,
     {
-        let client = self.client().await?;
+        let client = self.conn().await?;
         let result = client.query_one(sql, params).await?;
         Ok(T::try_from_row(&result)?)
     }
@@ -88,7 +248,7 @@ impl Db {
     where
         for<'a> T: tokio_postgres::types::FromSql<'a>,
     {
-        let client = self.client().await?;
+        let client = self.conn().await?;
         let result = client.query_one(sql, params).await?;
         Ok(result.try_get(0)?)
     }
@@ -101,7 +261,7 @@ impl Db {
     where
         T: FromRow,
     {
-        let client = self.client().await?;
+        let client = self.conn().await?;
         let result = client.query_opt(sql, params).await?;
         Ok(result.map(|row| T::try_from_row(&row)).transpose()?)
     }
@@ -114,7 +274,7 @@ impl Db {
     where
         T: FromRow,
     {
-        let client = self.client().await?;
+        let client = self.conn().await?;
         let result = client.query(sql, params).await?;
         Ok(result
             .iter()
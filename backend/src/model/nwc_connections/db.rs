@@ -0,0 +1,35 @@
+use crate::model::repo::NwcConnectionRepo;
+use crate::model::Db;
+use anyhow::Result;
+use async_trait::async_trait;
+
+use super::NwcConnection;
+
+#[derive(Clone)]
+pub struct NwcConnectionDb(pub Db);
+
+#[async_trait]
+impl NwcConnectionRepo for NwcConnectionDb {
+    async fn create(&self, user_id: i32, pubkey: &str, name: &str) -> Result<NwcConnection> {
+        let sql =
+            "INSERT INTO nwc_connections (user_id, pubkey, name) VALUES ($1, $2, $3) RETURNING *";
+        self.0
+            .query_one::<NwcConnection>(sql, &[&user_id, &pubkey, &name])
+            .await
+    }
+
+    async fn get_active_by_pubkey(&self, pubkey: &str) -> Result<Option<NwcConnection>> {
+        let sql = "SELECT * FROM nwc_connections WHERE pubkey = $1 AND revoked_at IS NULL";
+        self.0.query_opt::<NwcConnection>(sql, &[&pubkey]).await
+    }
+
+    async fn get_by_user(&self, user_id: i32) -> Result<Vec<NwcConnection>> {
+        let sql = "SELECT * FROM nwc_connections WHERE user_id = $1 ORDER BY id";
+        self.0.query(sql, &[&user_id]).await
+    }
+
+    async fn revoke(&self, user_id: i32, id: i32) -> Result<bool> {
+        let sql = "UPDATE nwc_connections SET revoked_at = now() WHERE id = $1 AND user_id = $2 AND revoked_at IS NULL";
+        Ok(self.0.execute(sql, &[&id, &user_id]).await? > 0)
+    }
+}
@@ -0,0 +1,35 @@
+pub mod db;
+
+use chrono::{DateTime, Utc};
+use postgres_from_row::FromRow;
+use serde::Serialize;
+use tokio_postgres::Row;
+
+/// A Nostr Wallet Connect client allowed to act on a user's address. Only the
+/// pubkey of the connection secret is stored; the secret is shown once.
+#[derive(Debug, Clone, Serialize)]
+pub struct NwcConnection {
+    pub id: i32,
+    pub user_id: i32,
+    pub pubkey: String,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl FromRow for NwcConnection {
+    fn from_row(row: &Row) -> Self {
+        Self::try_from_row(row).expect("Decoding row failed")
+    }
+
+    fn try_from_row(row: &Row) -> Result<Self, tokio_postgres::Error> {
+        Ok(NwcConnection {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            pubkey: row.get("pubkey"),
+            name: row.get("name"),
+            created_at: row.get("created_at"),
+            revoked_at: row.get("revoked_at"),
+        })
+    }
+}
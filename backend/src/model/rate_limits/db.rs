@@ -0,0 +1,28 @@
+use crate::model::repo::RateLimitRepo;
+use crate::model::Db;
+use anyhow::Result;
+use async_trait::async_trait;
+
+#[derive(Clone)]
+pub struct RateLimitDb(pub Db);
+
+#[async_trait]
+impl RateLimitRepo for RateLimitDb {
+    async fn take_token(&self, key: &str, burst: f64, refill_per_sec: f64) -> Result<bool> {
+        let sql = "INSERT INTO rate_limits (key, tokens, allowed, updated_at) VALUES ($1, $2 - 1, TRUE, now())
+            ON CONFLICT (key) DO UPDATE SET
+                allowed = LEAST($2, rate_limits.tokens + EXTRACT(EPOCH FROM now() - rate_limits.updated_at)::float8 * $3) >= 1,
+                tokens = LEAST($2, rate_limits.tokens + EXTRACT(EPOCH FROM now() - rate_limits.updated_at)::float8 * $3)
+                    - CASE WHEN LEAST($2, rate_limits.tokens + EXTRACT(EPOCH FROM now() - rate_limits.updated_at)::float8 * $3) >= 1 THEN 1 ELSE 0 END,
+                updated_at = now()
+            RETURNING allowed";
+        self.0
+            .query_value::<bool>(sql, &[&key, &burst, &refill_per_sec])
+            .await
+    }
+
+    async fn prune(&self, idle_secs: f64) -> Result<u64> {
+        let sql = "DELETE FROM rate_limits WHERE updated_at < now() - make_interval(secs => $1)";
+        self.0.execute(sql, &[&idle_secs]).await
+    }
+}
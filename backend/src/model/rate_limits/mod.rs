@@ -0,0 +1,4 @@
+//! Token buckets in the `rate_limits` table, used by
+//! [`RateLimiter`](crate::rate_limit::RateLimiter) with the Postgres backend.
+
+pub mod db;
@@ -0,0 +1,189 @@
+//! Storage interfaces. The Postgres tables implement all of them; users and
+//! invoices also have SQLite and in-memory stores, the rest are only
+//! available with Postgres.
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+
+use super::invoice_events::{InvoiceEvent, InvoiceStage};
+use super::invoices::{Invoice, InvoiceForCreate, InvoiceState};
+use super::nwc_connections::NwcConnection;
+use super::users::success_action::SuccessActionTemplate;
+use super::users::{User, UserForCreate, UserForUpdate};
+use super::withdrawals::{Withdrawal, WithdrawalForCreate, WithdrawalState};
+
+#[async_trait]
+pub trait UserRepo: Send + Sync {
+    async fn create(&self, user: UserForCreate) -> Result<User>;
+
+    async fn get(&self, id: i32) -> Result<Option<User>>;
+
+    async fn get_by_name(&self, username: &str) -> Result<Option<User>>;
+
+    /// `pubkey` is the hex x-only key the user registered with.
+    async fn get_by_pubkey(&self, pubkey: &str) -> Result<Option<User>>;
+
+    async fn update(&self, id: i32, user: UserForUpdate) -> Result<User>;
+
+    /// Raises the user's last tweak to `tweak`; it never moves backwards, so
+    /// callbacks committing out of order can't hand out a tweak twice.
+    async fn update_tweak(&self, user_id: i32, tweak: i64) -> Result<()>;
+
+    async fn update_success_action(
+        &self,
+        user: &User,
+        success_action: Option<&SuccessActionTemplate>,
+    ) -> Result<User>;
+}
+
+#[async_trait]
+pub trait InvoiceRepo: Send + Sync {
+    async fn create(&self, invoice: InvoiceForCreate) -> Result<Invoice>;
+
+    /// Stores the invoice and moves its user's `last_tweak` to the invoice's
+    /// tweak together, so a tweak is only used up by a stored invoice.
+    async fn create_with_tweak(&self, invoice: InvoiceForCreate) -> Result<Invoice>;
+
+    async fn get_by_op_id(&self, op_id: &str) -> Result<Option<Invoice>>;
+
+    async fn update_state(&self, id: i32, state: InvoiceState) -> Result<()>;
+
+    /// Moves the invoice from `from` to `to` and records `stage`, only if it
+    /// is still in `from`. Returns whether it moved, so a change made by
+    /// someone else in the meantime isn't acted on twice. Unlike
+    /// [`record_event`](Self::record_event) this replaces a final stage, as
+    /// a correction from the operation's outcome.
+    async fn transition_state(
+        &self,
+        invoice_id: i32,
+        from: InvoiceState,
+        to: InvoiceState,
+        stage: InvoiceStage,
+        reason: Option<&str>,
+    ) -> Result<bool>;
+
+    async fn get_by_state(&self, state: InvoiceState) -> Result<Vec<Invoice>>;
+
+    /// Pending invoices for a repeated callback created within `window_secs`.
+    async fn get_pending_by_nonce(
+        &self,
+        user_id: i32,
+        nonce: &str,
+        window_secs: f64,
+    ) -> Result<Vec<Invoice>>;
+
+    /// Detaches the nonce from pending invoices older than the idempotency
+    /// window so a fresh invoice can take it.
+    async fn release_stale_nonce(&self, user_id: i32, nonce: &str, window_secs: f64) -> Result<()>;
+
+    /// One page of invoices in `state` ordered by id, starting after `after_id`.
+    async fn get_page_by_state(
+        &self,
+        state: InvoiceState,
+        after_id: i32,
+        limit: i64,
+    ) -> Result<Vec<Invoice>>;
+
+    /// Most recently settled invoices for `user_pubkey`, newest first.
+    async fn get_recent_settled(&self, user_pubkey: &str, limit: i64) -> Result<Vec<Invoice>>;
+
+    /// Settled invoices locked to `user_pubkey` that its wallet hasn't claimed yet.
+    async fn get_unclaimed(&self, user_pubkey: &str) -> Result<Vec<Invoice>>;
+
+    /// Marks settled invoices as claimed, returning the ones that changed.
+    async fn mark_claimed(&self, user_pubkey: &str, op_ids: &[String]) -> Result<Vec<Invoice>>;
+
+    async fn get_by_payment_hash(
+        &self,
+        user_id: i32,
+        payment_hash: &str,
+    ) -> Result<Option<Invoice>>;
+
+    async fn get_by_bolt11(&self, user_id: i32, bolt11: &str) -> Result<Option<Invoice>>;
+
+    /// A user's invoices newest first, optionally bounded by creation time.
+    /// Only settled ones unless `include_unpaid` is set.
+    async fn list_for_user(
+        &self,
+        user_id: i32,
+        from: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+        include_unpaid: bool,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Invoice>>;
+
+    /// Records `stage` unless the invoice is already there, further along or
+    /// finished, and sets `state` in the same step when given and recorded.
+    /// Returns whether it was recorded, so only one of several subscribers
+    /// to the same operation acts on an outcome.
+    async fn record_event(
+        &self,
+        invoice_id: i32,
+        stage: InvoiceStage,
+        reason: Option<&str>,
+        state: Option<InvoiceState>,
+    ) -> Result<bool>;
+
+    /// An invoice's events, oldest first.
+    async fn get_events(&self, invoice_id: i32) -> Result<Vec<InvoiceEvent>>;
+}
+
+#[async_trait]
+pub trait WithdrawalRepo: Send + Sync {
+    /// Creates the link unless its user already has `max_claimable` links
+    /// that could still be claimed, returning `None` then.
+    async fn create(
+        &self,
+        withdrawal: WithdrawalForCreate,
+        max_claimable: i64,
+    ) -> Result<Option<Withdrawal>>;
+
+    async fn get(&self, id: &str) -> Result<Option<Withdrawal>>;
+
+    async fn get_by_user(&self, user_id: i32) -> Result<Vec<Withdrawal>>;
+
+    async fn get_by_state(&self, state: WithdrawalState) -> Result<Vec<Withdrawal>>;
+
+    /// Atomically moves a usable link to `Pending` for `bolt11`, so concurrent
+    /// callbacks can't pay out twice. Returns `None` if the link was taken.
+    async fn claim(&self, id: &str, k1: &str, bolt11: &str) -> Result<Option<Withdrawal>>;
+
+    /// Records the operation of a payment that was made. The state is left
+    /// alone: it is `Pending`, or `PaymentUnknown` if startup flagged the row
+    /// while the payment was being made.
+    async fn set_operation(&self, id: &str, op_id: &str, internal: bool) -> Result<()>;
+
+    /// Makes a claimed link usable again after its payment could not be
+    /// started. Rows with an operation are left alone.
+    async fn release(&self, id: &str) -> Result<()>;
+
+    /// Moves a withdrawal whose payment was made to `state`, which must not
+    /// be claimable.
+    async fn update_payment_state(&self, id: &str, state: WithdrawalState) -> Result<()>;
+}
+
+#[async_trait]
+pub trait NwcConnectionRepo: Send + Sync {
+    async fn create(&self, user_id: i32, pubkey: &str, name: &str) -> Result<NwcConnection>;
+
+    /// The active connection whose secret signs as `pubkey`.
+    async fn get_active_by_pubkey(&self, pubkey: &str) -> Result<Option<NwcConnection>>;
+
+    async fn get_by_user(&self, user_id: i32) -> Result<Vec<NwcConnection>>;
+
+    /// Returns whether an active connection of the user was revoked.
+    async fn revoke(&self, user_id: i32, id: i32) -> Result<bool>;
+}
+
+/// Token buckets shared between instances.
+#[async_trait]
+pub trait RateLimitRepo: Send + Sync {
+    /// Takes one token from the bucket for `key`, which holds up to `burst`
+    /// and refills at `refill_per_sec`. Returns whether one was available.
+    async fn take_token(&self, key: &str, burst: f64, refill_per_sec: f64) -> Result<bool>;
+
+    /// Drops buckets untouched for `idle_secs`, returning how many.
+    async fn prune(&self, idle_secs: f64) -> Result<u64>;
+}
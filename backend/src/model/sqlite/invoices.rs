@@ -0,0 +1,368 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Params, Row, TransactionBehavior};
+
+use super::{Json, SqliteDb, Timestamp};
+use crate::model::invoice_events::{InvoiceEvent, InvoiceStage};
+use crate::model::invoices::{Invoice, InvoiceForCreate, InvoiceState};
+use crate::model::repo::InvoiceRepo;
+
+#[derive(Clone)]
+pub struct SqliteInvoiceDb(pub SqliteDb);
+
+fn invoice_from_row(row: &Row) -> rusqlite::Result<Invoice> {
+    Ok(Invoice {
+        id: row.get("id")?,
+        federation_id: row.get("federation_id")?,
+        op_id: row.get("op_id")?,
+        user_id: row.get("user_id")?,
+        user_pubkey: row.get("user_pubkey")?,
+        bolt11: row.get("bolt11")?,
+        amount: row.get("amount")?,
+        state: row.get("state")?,
+        tweak: row.get("tweak")?,
+        nonce: row.get("nonce")?,
+        fiat_amount: row.get("fiat_amount")?,
+        fiat_currency: row.get("fiat_currency")?,
+        fiat_rate: row.get("fiat_rate")?,
+        preimage: row.get("preimage")?,
+        comment: row.get("comment")?,
+        payer: row.get("payer")?,
+        settled_at: row.get::<_, Option<Timestamp>>("settled_at")?.map(|t| t.0),
+        payment_hash: row.get("payment_hash")?,
+        created_at: Some(row.get::<_, Timestamp>("created_at")?.0),
+        stage: row.get("stage")?,
+    })
+}
+
+fn event_from_row(row: &Row) -> rusqlite::Result<InvoiceEvent> {
+    Ok(InvoiceEvent {
+        id: row.get("id")?,
+        invoice_id: row.get("invoice_id")?,
+        stage: row.get("stage")?,
+        reason: row.get("reason")?,
+        created_at: row.get::<_, Timestamp>("created_at")?.0,
+    })
+}
+
+fn query_invoices(conn: &Connection, sql: &str, params: impl Params) -> Result<Vec<Invoice>> {
+    let mut statement = conn.prepare(sql)?;
+    let invoices = statement
+        .query_map(params, invoice_from_row)?
+        .collect::<rusqlite::Result<_>>()?;
+    Ok(invoices)
+}
+
+fn insert(conn: &Connection, invoice: &InvoiceForCreate) -> Result<Invoice> {
+    let sql = "INSERT INTO invoices (op_id, federation_id, user_id, user_pubkey, amount, bolt11, tweak, state, nonce, fiat_amount, fiat_currency, fiat_rate, preimage, comment, payer, payment_hash, created_at) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17) RETURNING *";
+    Ok(conn.query_row(
+        sql,
+        params![
+            invoice.op_id,
+            invoice.federation_id,
+            invoice.user_id,
+            invoice.user_pubkey,
+            invoice.amount,
+            invoice.bolt11,
+            invoice.tweak,
+            invoice.state,
+            invoice.nonce,
+            invoice.fiat_amount,
+            invoice.fiat_currency,
+            invoice.fiat_rate,
+            invoice.preimage,
+            invoice.comment,
+            invoice.payer,
+            invoice.payment_hash,
+            Timestamp::now(),
+        ],
+        invoice_from_row,
+    )?)
+}
+
+fn update_state(conn: &Connection, id: i32, state: InvoiceState) -> Result<()> {
+    let sql = "UPDATE invoices SET state = ?1, settled_at = CASE WHEN ?1 = 1 THEN ?2 ELSE settled_at END WHERE id = ?3";
+    conn.execute(sql, params![state, Timestamp::now(), id])?;
+    Ok(())
+}
+
+/// The start of an idempotency window ending now.
+fn window_start(window_secs: f64) -> Timestamp {
+    Timestamp(Utc::now() - Duration::milliseconds((window_secs * 1000.0) as i64))
+}
+
+#[async_trait]
+impl InvoiceRepo for SqliteInvoiceDb {
+    async fn create(&self, invoice: InvoiceForCreate) -> Result<Invoice> {
+        self.0.interact(move |conn| insert(conn, &invoice)).await
+    }
+
+    async fn create_with_tweak(&self, invoice: InvoiceForCreate) -> Result<Invoice> {
+        self.0
+            .interact(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                tx.execute(
+                    "UPDATE users SET last_tweak = MAX(last_tweak, ?1) WHERE id = ?2",
+                    params![invoice.tweak, invoice.user_id],
+                )?;
+                let invoice = insert(&tx, &invoice)?;
+                tx.commit()?;
+                Ok(invoice)
+            })
+            .await
+    }
+
+    async fn get_by_op_id(&self, op_id: &str) -> Result<Option<Invoice>> {
+        let sql = "SELECT * FROM invoices WHERE op_id = ?1";
+        let op_id = op_id.to_string();
+        self.0
+            .interact(move |conn| Ok(conn.query_row(sql, [op_id], invoice_from_row).optional()?))
+            .await
+    }
+
+    async fn update_state(&self, id: i32, state: InvoiceState) -> Result<()> {
+        self.0
+            .interact(move |conn| update_state(conn, id, state))
+            .await
+    }
+
+    async fn transition_state(
+        &self,
+        invoice_id: i32,
+        from: InvoiceState,
+        to: InvoiceState,
+        stage: InvoiceStage,
+        reason: Option<&str>,
+    ) -> Result<bool> {
+        let reason = reason.map(str::to_string);
+        self.0
+            .interact(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let moved = tx.execute(
+                    "UPDATE invoices SET state = ?3, settled_at = CASE WHEN ?3 = 1 THEN ?4 ELSE settled_at END WHERE id = ?1 AND state = ?2",
+                    params![invoice_id, from, to, Timestamp::now()],
+                )? > 0;
+                if moved {
+                    tx.execute(
+                        "UPDATE invoices SET stage = ?2 WHERE id = ?1",
+                        params![invoice_id, stage],
+                    )?;
+                    tx.execute(
+                        "INSERT INTO invoice_events (invoice_id, stage, reason, created_at) VALUES (?1, ?2, ?3, ?4)",
+                        params![invoice_id, stage, reason, Timestamp::now()],
+                    )?;
+                }
+                tx.commit()?;
+                Ok(moved)
+            })
+            .await
+    }
+
+    async fn get_by_state(&self, state: InvoiceState) -> Result<Vec<Invoice>> {
+        let sql = "SELECT * FROM invoices WHERE state = ?1";
+        self.0
+            .interact(move |conn| query_invoices(conn, sql, [state]))
+            .await
+    }
+
+    async fn get_pending_by_nonce(
+        &self,
+        user_id: i32,
+        nonce: &str,
+        window_secs: f64,
+    ) -> Result<Vec<Invoice>> {
+        let sql = "SELECT * FROM invoices WHERE user_id = ?1 AND nonce = ?2 AND state = ?3 AND created_at > ?4";
+        let nonce = nonce.to_string();
+        self.0
+            .interact(move |conn| {
+                query_invoices(
+                    conn,
+                    sql,
+                    params![
+                        user_id,
+                        nonce,
+                        InvoiceState::Pending,
+                        window_start(window_secs)
+                    ],
+                )
+            })
+            .await
+    }
+
+    async fn release_stale_nonce(&self, user_id: i32, nonce: &str, window_secs: f64) -> Result<()> {
+        let sql = "UPDATE invoices SET nonce = NULL WHERE user_id = ?1 AND nonce = ?2 AND state = ?3 AND created_at <= ?4";
+        let nonce = nonce.to_string();
+        self.0
+            .interact(move |conn| {
+                conn.execute(
+                    sql,
+                    params![
+                        user_id,
+                        nonce,
+                        InvoiceState::Pending,
+                        window_start(window_secs)
+                    ],
+                )?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn get_page_by_state(
+        &self,
+        state: InvoiceState,
+        after_id: i32,
+        limit: i64,
+    ) -> Result<Vec<Invoice>> {
+        let sql = "SELECT * FROM invoices WHERE state = ?1 AND id > ?2 ORDER BY id LIMIT ?3";
+        self.0
+            .interact(move |conn| query_invoices(conn, sql, params![state, after_id, limit]))
+            .await
+    }
+
+    async fn get_recent_settled(&self, user_pubkey: &str, limit: i64) -> Result<Vec<Invoice>> {
+        let sql = "SELECT * FROM invoices WHERE user_pubkey = ?1 AND state = ?2 ORDER BY settled_at IS NULL, settled_at DESC, id DESC LIMIT ?3";
+        let user_pubkey = user_pubkey.to_string();
+        self.0
+            .interact(move |conn| {
+                query_invoices(
+                    conn,
+                    sql,
+                    params![user_pubkey, InvoiceState::Settled, limit],
+                )
+            })
+            .await
+    }
+
+    async fn get_unclaimed(&self, user_pubkey: &str) -> Result<Vec<Invoice>> {
+        let sql = "SELECT * FROM invoices WHERE user_pubkey = ?1 AND state = ?2 AND claimed_at IS NULL ORDER BY id";
+        let user_pubkey = user_pubkey.to_string();
+        self.0
+            .interact(move |conn| {
+                query_invoices(conn, sql, params![user_pubkey, InvoiceState::Settled])
+            })
+            .await
+    }
+
+    async fn mark_claimed(&self, user_pubkey: &str, op_ids: &[String]) -> Result<Vec<Invoice>> {
+        let sql = "UPDATE invoices SET claimed_at = ?1 WHERE user_pubkey = ?2 AND op_id IN (SELECT value FROM json_each(?3)) AND state = ?4 AND claimed_at IS NULL RETURNING *";
+        let user_pubkey = user_pubkey.to_string();
+        let op_ids = op_ids.to_vec();
+        self.0
+            .interact(move |conn| {
+                query_invoices(
+                    conn,
+                    sql,
+                    params![
+                        Timestamp::now(),
+                        user_pubkey,
+                        Json(op_ids),
+                        InvoiceState::Settled
+                    ],
+                )
+            })
+            .await
+    }
+
+    async fn get_by_payment_hash(
+        &self,
+        user_id: i32,
+        payment_hash: &str,
+    ) -> Result<Option<Invoice>> {
+        let sql = "SELECT * FROM invoices WHERE user_id = ?1 AND payment_hash = ?2";
+        let payment_hash = payment_hash.to_string();
+        self.0
+            .interact(move |conn| {
+                Ok(conn
+                    .query_row(sql, params![user_id, payment_hash], invoice_from_row)
+                    .optional()?)
+            })
+            .await
+    }
+
+    async fn get_by_bolt11(&self, user_id: i32, bolt11: &str) -> Result<Option<Invoice>> {
+        let sql = "SELECT * FROM invoices WHERE user_id = ?1 AND bolt11 = ?2";
+        let bolt11 = bolt11.to_string();
+        self.0
+            .interact(move |conn| {
+                Ok(conn
+                    .query_row(sql, params![user_id, bolt11], invoice_from_row)
+                    .optional()?)
+            })
+            .await
+    }
+
+    async fn list_for_user(
+        &self,
+        user_id: i32,
+        from: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+        include_unpaid: bool,
+        limit: i64,
+        offset: i64,
+    ) -> Result<Vec<Invoice>> {
+        let sql = "SELECT * FROM invoices WHERE user_id = ?1 AND (?2 IS NULL OR created_at >= ?2) AND (?3 IS NULL OR created_at <= ?3) AND (?4 OR state = ?5) ORDER BY created_at DESC, id DESC LIMIT ?6 OFFSET ?7";
+        self.0
+            .interact(move |conn| {
+                query_invoices(
+                    conn,
+                    sql,
+                    params![
+                        user_id,
+                        from.map(Timestamp),
+                        until.map(Timestamp),
+                        include_unpaid,
+                        InvoiceState::Settled,
+                        limit,
+                        offset,
+                    ],
+                )
+            })
+            .await
+    }
+
+    async fn record_event(
+        &self,
+        invoice_id: i32,
+        stage: InvoiceStage,
+        reason: Option<&str>,
+        state: Option<InvoiceState>,
+    ) -> Result<bool> {
+        let reason = reason.map(str::to_string);
+        self.0
+            .interact(move |conn| {
+                let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                // finished stages (4 and up) are never left
+                let advanced = tx.execute(
+                    "UPDATE invoices SET stage = ?2 WHERE id = ?1 AND (stage IS NULL OR (stage < ?2 AND stage < 4))",
+                    params![invoice_id, stage],
+                )?;
+                if advanced > 0 {
+                    tx.execute(
+                        "INSERT INTO invoice_events (invoice_id, stage, reason, created_at) VALUES (?1, ?2, ?3, ?4)",
+                        params![invoice_id, stage, reason, Timestamp::now()],
+                    )?;
+                    if let Some(state) = state {
+                        update_state(&tx, invoice_id, state)?;
+                    }
+                }
+                tx.commit()?;
+                Ok(advanced > 0)
+            })
+            .await
+    }
+
+    async fn get_events(&self, invoice_id: i32) -> Result<Vec<InvoiceEvent>> {
+        let sql = "SELECT * FROM invoice_events WHERE invoice_id = ?1 ORDER BY created_at, id";
+        self.0
+            .interact(move |conn| {
+                let mut statement = conn.prepare(sql)?;
+                let events = statement
+                    .query_map([invoice_id], event_from_row)?
+                    .collect::<rusqlite::Result<_>>()?;
+                Ok(events)
+            })
+            .await
+    }
+}
@@ -0,0 +1,194 @@
+//! Users and invoices in SQLite, for small deployments that don't want to
+//! run Postgres. Selected with a `sqlite:` database URL.
+
+mod invoices;
+mod users;
+
+use std::fmt;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use deadpool_sqlite::{Config, Pool, Runtime, Status};
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use rusqlite::Connection;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tracing::info;
+
+pub use invoices::SqliteInvoiceDb;
+pub use users::SqliteUserDb;
+
+use super::invoice_events::InvoiceStage;
+use super::invoices::InvoiceState;
+
+/// Applied in order, like the Postgres schema. SQLite can't add a column
+/// only if it's missing, so the number of files already applied is kept in
+/// `user_version` and only newer ones run.
+const SCHEMA: &[&str] = &[include_str!("../../../schema/sqlite/v0.sql")];
+
+/// How long a write waits on another connection's lock before failing.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct SqliteDb {
+    pool: Pool,
+}
+
+impl fmt::Debug for SqliteDb {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SqliteDb")
+            .field("pool", &self.pool.status())
+            .finish()
+    }
+}
+
+impl SqliteDb {
+    /// `url` is `sqlite:<path>` or `sqlite://<path>`; the path may also be an
+    /// SQLite `file:` URI.
+    pub fn new(url: &str) -> Result<Self> {
+        let Some(path) = url
+            .strip_prefix("sqlite://")
+            .or_else(|| url.strip_prefix("sqlite:"))
+        else {
+            bail!("Not an SQLite URL");
+        };
+        if path.is_empty() {
+            bail!("SQLite URL has no path");
+        }
+        let pool = Config::new(path).create_pool(Runtime::Tokio1)?;
+        Ok(Self { pool })
+    }
+
+    pub async fn setup_schema(&self) -> Result<()> {
+        info!("Setting up SQLite schema");
+        self.interact(|conn| {
+            conn.pragma_update(None, "journal_mode", "WAL")?;
+            let applied: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+            for (version, schema_sql) in SCHEMA.iter().enumerate().skip(applied) {
+                let tx = conn.transaction()?;
+                tx.execute_batch(schema_sql)?;
+                tx.pragma_update(None, "user_version", version + 1)?;
+                tx.commit()?;
+            }
+            Ok(())
+        })
+        .await?;
+        info!("SQLite schema setup complete");
+        Ok(())
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        self.interact(|conn| Ok(conn.query_row("SELECT 1", [], |_| Ok(()))?))
+            .await
+    }
+
+    pub fn pool_status(&self) -> Status {
+        self.pool.status()
+    }
+
+    // --- START TABLES ---
+    pub fn users(&self) -> SqliteUserDb {
+        SqliteUserDb(self.clone())
+    }
+
+    pub fn invoices(&self) -> SqliteInvoiceDb {
+        SqliteInvoiceDb(self.clone())
+    }
+    // --- END TABLES ---
+
+    /// Runs `f` with a pooled connection on the blocking thread pool.
+    async fn interact<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Connection) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let conn = self.pool.get().await?;
+        conn.interact(move |conn| {
+            conn.pragma_update(None, "foreign_keys", true)?;
+            conn.busy_timeout(BUSY_TIMEOUT)?;
+            f(conn)
+        })
+        .await
+        .map_err(|e| anyhow!("SQLite connection failed: {e}"))?
+    }
+}
+
+/// A timestamp stored as fixed width RFC 3339 text, so it also sorts as text.
+struct Timestamp(DateTime<Utc>);
+
+impl Timestamp {
+    fn now() -> Self {
+        Self(Utc::now())
+    }
+}
+
+impl ToSql for Timestamp {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(self.0.to_rfc3339_opts(SecondsFormat::Micros, true).into())
+    }
+}
+
+impl FromSql for Timestamp {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        DateTime::parse_from_rfc3339(value.as_str()?)
+            .map(|time| Self(time.with_timezone(&Utc)))
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+/// A value stored as JSON text.
+struct Json<T>(T);
+
+impl<T: Serialize> ToSql for Json<T> {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        serde_json::to_string(&self.0)
+            .map(Into::into)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+    }
+}
+
+impl<T: DeserializeOwned> FromSql for Json<T> {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        serde_json::from_str(value.as_str()?)
+            .map(Self)
+            .map_err(|e| FromSqlError::Other(Box::new(e)))
+    }
+}
+
+impl ToSql for InvoiceState {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok((*self as i32).into())
+    }
+}
+
+impl FromSql for InvoiceState {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match i32::column_result(value)? {
+            0 => Ok(InvoiceState::Pending),
+            1 => Ok(InvoiceState::Settled),
+            2 => Ok(InvoiceState::Cancelled),
+            value => Err(FromSqlError::OutOfRange(value.into())),
+        }
+    }
+}
+
+impl ToSql for InvoiceStage {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok((*self as i32).into())
+    }
+}
+
+impl FromSql for InvoiceStage {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        match i32::column_result(value)? {
+            0 => Ok(InvoiceStage::Created),
+            1 => Ok(InvoiceStage::WaitingForPayment),
+            2 => Ok(InvoiceStage::Funded),
+            3 => Ok(InvoiceStage::AwaitingFunds),
+            4 => Ok(InvoiceStage::Claimed),
+            5 => Ok(InvoiceStage::Canceled),
+            value => Err(FromSqlError::OutOfRange(value.into())),
+        }
+    }
+}
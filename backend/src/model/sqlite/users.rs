@@ -0,0 +1,123 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use rusqlite::{params, OptionalExtension, Row};
+
+use super::{Json, SqliteDb};
+use crate::model::repo::UserRepo;
+use crate::model::users::success_action::SuccessActionTemplate;
+use crate::model::users::{User, UserForCreate, UserForUpdate};
+
+pub struct SqliteUserDb(pub SqliteDb);
+
+fn user_from_row(row: &Row) -> rusqlite::Result<User> {
+    Ok(User {
+        id: row.get("id")?,
+        name: row.get("name")?,
+        replit_id: row.get("replit_id")?,
+        replit_profile_pic: row.get("replit_profile_pic")?,
+        pubkey: row.get("pubkey")?,
+        last_tweak: row.get("last_tweak")?,
+        relays: row.get::<_, Json<_>>("relays")?.0,
+        federation_ids: row.get::<_, Json<_>>("federation_ids")?.0,
+        connection_code_uuid: row.get("connection_code_uuid")?,
+        success_action: row
+            .get::<_, Option<Json<SuccessActionTemplate>>>("success_action")?
+            .map(|Json(action)| action),
+    })
+}
+
+#[async_trait]
+impl UserRepo for SqliteUserDb {
+    async fn create(&self, user: UserForCreate) -> Result<User> {
+        let sql = "INSERT INTO users (name, replit_id, replit_profile_pic, pubkey, relays, federation_ids, connection_code_uuid, last_tweak) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8) RETURNING *";
+        self.0
+            .interact(move |conn| {
+                Ok(conn.query_row(
+                    sql,
+                    params![
+                        user.name,
+                        user.replit_id,
+                        user.replit_profile_pic,
+                        user.pubkey,
+                        Json(&user.relays),
+                        Json(&user.federation_ids),
+                        user.connection_code_uuid,
+                        user.last_tweak,
+                    ],
+                    user_from_row,
+                )?)
+            })
+            .await
+    }
+
+    async fn get(&self, id: i32) -> Result<Option<User>> {
+        let sql = "SELECT * FROM users WHERE id = ?1";
+        self.0
+            .interact(move |conn| Ok(conn.query_row(sql, [id], user_from_row).optional()?))
+            .await
+    }
+
+    async fn get_by_name(&self, username: &str) -> Result<Option<User>> {
+        let sql = "SELECT * FROM users WHERE name = ?1";
+        let username = username.to_string();
+        self.0
+            .interact(move |conn| Ok(conn.query_row(sql, [username], user_from_row).optional()?))
+            .await
+    }
+
+    async fn get_by_pubkey(&self, pubkey: &str) -> Result<Option<User>> {
+        let sql = "SELECT * FROM users WHERE pubkey = ?1";
+        let pubkey = pubkey.to_string();
+        self.0
+            .interact(move |conn| Ok(conn.query_row(sql, [pubkey], user_from_row).optional()?))
+            .await
+    }
+
+    async fn update(&self, id: i32, user: UserForUpdate) -> Result<User> {
+        let sql = "UPDATE users SET name = COALESCE(?1, name), replit_id = COALESCE(?2, replit_id), replit_profile_pic = COALESCE(?3, replit_profile_pic), pubkey = COALESCE(?4, pubkey), relays = COALESCE(?5, relays), federation_ids = COALESCE(?6, federation_ids), connection_code_uuid = COALESCE(?7, connection_code_uuid), last_tweak = COALESCE(?8, last_tweak) WHERE id = ?9 RETURNING *";
+        self.0
+            .interact(move |conn| {
+                Ok(conn.query_row(
+                    sql,
+                    params![
+                        user.name,
+                        user.replit_id,
+                        user.replit_profile_pic,
+                        user.pubkey,
+                        user.relays.as_ref().map(Json),
+                        user.federation_ids.as_ref().map(Json),
+                        user.connection_code_uuid,
+                        user.last_tweak,
+                        id,
+                    ],
+                    user_from_row,
+                )?)
+            })
+            .await
+    }
+
+    async fn update_tweak(&self, user_id: i32, tweak: i64) -> Result<()> {
+        let sql = "UPDATE users SET last_tweak = MAX(last_tweak, ?1) WHERE id = ?2";
+        self.0
+            .interact(move |conn| {
+                conn.execute(sql, params![tweak, user_id])?;
+                Ok(())
+            })
+            .await
+    }
+
+    async fn update_success_action(
+        &self,
+        user: &User,
+        success_action: Option<&SuccessActionTemplate>,
+    ) -> Result<User> {
+        let sql = "UPDATE users SET success_action = ?1 WHERE id = ?2 RETURNING *";
+        let success_action = success_action.cloned();
+        let id = user.id;
+        self.0
+            .interact(move |conn| {
+                Ok(conn.query_row(sql, params![success_action.map(Json), id], user_from_row)?)
+            })
+            .await
+    }
+}
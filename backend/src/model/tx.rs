@@ -0,0 +1,141 @@
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use deadpool_postgres::Client;
+use tokio::sync::Mutex;
+use tokio_postgres::error::SqlState;
+use tracing::warn;
+
+use super::invoice_events::db::InvoiceEventDb;
+use super::invoices::db::InvoiceDb;
+use super::users::db::UserDb;
+use super::withdrawals::db::WithdrawalDb;
+use super::Db;
+
+/// Attempts per [`Db::transaction`] before a serialization failure is returned.
+const MAX_ATTEMPTS: u32 = 5;
+const RETRY_BACKOFF: Duration = Duration::from_millis(20);
+
+/// The connection an open transaction runs on.
+pub(super) struct TxConn {
+    pub(super) client: Option<Client>,
+    open: bool,
+}
+
+impl Drop for TxConn {
+    fn drop(&mut self) {
+        // A connection still inside a transaction must not go back to the
+        // pool, so it is closed instead, which rolls the transaction back.
+        if self.open {
+            if let Some(client) = self.client.take() {
+                drop(deadpool_postgres::Object::take(client));
+            }
+        }
+    }
+}
+
+/// A serializable transaction. Table accessors run on it until it is
+/// committed or rolled back; dropping it unfinished rolls it back.
+#[derive(Clone)]
+pub struct Tx(Db);
+
+impl Tx {
+    pub(super) async fn begin(db: &Db) -> Result<Self> {
+        let client = db.client().await?;
+        client
+            .batch_execute("BEGIN ISOLATION LEVEL SERIALIZABLE")
+            .await?;
+        let conn = TxConn {
+            client: Some(client),
+            open: true,
+        };
+        Ok(Self(Db {
+            pool: db.pool.clone(),
+            tx: Some(Arc::new(Mutex::new(conn))),
+        }))
+    }
+
+    pub fn users(&self) -> UserDb {
+        self.0.users()
+    }
+
+    pub fn invoices(&self) -> InvoiceDb {
+        self.0.invoices()
+    }
+
+    pub fn invoice_events(&self) -> InvoiceEventDb {
+        self.0.invoice_events()
+    }
+
+    pub fn withdrawals(&self) -> WithdrawalDb {
+        self.0.withdrawals()
+    }
+
+    pub async fn commit(self) -> Result<()> {
+        self.finish("COMMIT").await
+    }
+
+    pub async fn rollback(self) -> Result<()> {
+        self.finish("ROLLBACK").await
+    }
+
+    async fn finish(&self, sql: &str) -> Result<()> {
+        let Some(conn) = &self.0.tx else {
+            unreachable!("Tx always holds a transaction");
+        };
+        let mut conn = conn.lock().await;
+        if let Some(client) = &conn.client {
+            client.batch_execute(sql).await?;
+        }
+        conn.open = false;
+        Ok(())
+    }
+}
+
+impl Db {
+    pub async fn begin(&self) -> Result<Tx> {
+        Tx::begin(self).await
+    }
+
+    /// Runs `f` in a serializable transaction, committing if it succeeds and
+    /// rolling back if it fails. Serialization failures rerun `f` from the
+    /// start, so it must not have effects outside the database.
+    pub async fn transaction<T, F, Fut>(&self, f: F) -> Result<T>
+    where
+        F: Fn(Tx) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut attempt = 1;
+        loop {
+            let tx = self.begin().await?;
+            let result = match f(tx.clone()).await {
+                Ok(value) => tx.commit().await.map(|_| value),
+                Err(e) => {
+                    if let Err(rollback) = tx.rollback().await {
+                        warn!("Failed to roll back transaction: {rollback}");
+                    }
+                    Err(e)
+                }
+            };
+            match result {
+                Err(e) if attempt < MAX_ATTEMPTS && is_serialization_failure(&e) => {
+                    warn!("Retrying transaction after serialization failure (attempt {attempt})");
+                    tokio::time::sleep(RETRY_BACKOFF * attempt).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+fn is_serialization_failure(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<tokio_postgres::Error>()
+        .and_then(|e| e.code())
+        .is_some_and(|code| {
+            *code == SqlState::T_R_SERIALIZATION_FAILURE || *code == SqlState::T_R_DEADLOCK_DETECTED
+        })
+}
@@ -1,12 +1,17 @@
+use crate::model::repo::UserRepo;
+use crate::model::users::success_action::SuccessActionTemplate;
 use crate::model::users::{User, UserForCreate, UserForUpdate};
 use crate::model::Db;
 use anyhow::Result;
+use async_trait::async_trait;
+use postgres_types::Json;
 use tracing::info;
 
 pub struct UserDb(pub Db);
 
-impl UserDb {
-    pub async fn create(&self, user: UserForCreate) -> Result<User> {
+#[async_trait]
+impl UserRepo for UserDb {
+    async fn create(&self, user: UserForCreate) -> Result<User> {
         let sql = "INSERT INTO users (name, replit_id, replit_profile_pic, pubkey, relays, federation_ids, connection_code_uuid, last_tweak) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING *";
         self.0
             .query_one::<User>(
@@ -25,18 +30,23 @@ impl UserDb {
             .await
     }
 
-    pub async fn get(&self, id: i32) -> Result<Option<User>> {
+    async fn get(&self, id: i32) -> Result<Option<User>> {
         let sql = "SELECT * FROM users WHERE id = $1";
         self.0.query_opt::<User>(sql, &[&id]).await
     }
 
-    pub async fn get_by_name(&self, username: &str) -> Result<Option<User>> {
+    async fn get_by_name(&self, username: &str) -> Result<Option<User>> {
         info!("Getting user by name: {}", username);
         let sql = "SELECT * FROM users WHERE name = $1";
         self.0.query_opt::<User>(sql, &[&username]).await
     }
 
-    pub async fn update(&self, id: i32, user: UserForUpdate) -> Result<User> {
+    async fn get_by_pubkey(&self, pubkey: &str) -> Result<Option<User>> {
+        let sql = "SELECT * FROM users WHERE pubkey = $1";
+        self.0.query_opt::<User>(sql, &[&pubkey]).await
+    }
+
+    async fn update(&self, id: i32, user: UserForUpdate) -> Result<User> {
         let mut updates = Vec::new();
         let mut params: Vec<&(dyn tokio_postgres::types::ToSql + Sync)> = Vec::new();
         let mut param_count = 1;
@@ -92,12 +102,25 @@ impl UserDb {
         self.0.query_one::<User>(&sql, &params).await
     }
 
-    pub async fn update_tweak(&self, user: &User, tweak: i64) -> Result<()> {
-        let sql = "UPDATE users SET last_tweak = $1 WHERE id = $2";
-        self.0.execute(sql, &[&tweak, &user.id]).await?;
+    async fn update_tweak(&self, user_id: i32, tweak: i64) -> Result<()> {
+        let sql = "UPDATE users SET last_tweak = GREATEST(last_tweak, $1) WHERE id = $2";
+        self.0.execute(sql, &[&tweak, &user_id]).await?;
         Ok(())
     }
 
+    async fn update_success_action(
+        &self,
+        user: &User,
+        success_action: Option<&SuccessActionTemplate>,
+    ) -> Result<User> {
+        let sql = "UPDATE users SET success_action = $1 WHERE id = $2 RETURNING *";
+        self.0
+            .query_one::<User>(sql, &[&success_action.map(Json), &user.id])
+            .await
+    }
+}
+
+impl UserDb {
     pub async fn update_or_create_user(
         &self,
         name: &str,
@@ -107,31 +130,39 @@ impl UserDb {
         relays: Vec<String>,
         federation_ids: Vec<String>,
     ) -> Result<()> {
-        if let Some(user) = self.get_by_name(name).await? {
-            info!("User {} already exists", name);
-            let user_for_update = UserForUpdate::builder()
-                .name(name.to_string())
-                .replit_id(replit_id.to_string())
-                .replit_profile_pic(replit_profile_pic.to_string())
-                .pubkey(pubkey.to_string())
-                .relays(relays)
-                .federation_ids(federation_ids)
-                .build();
-            self.update(user.id, user_for_update).await?;
-        } else {
-            info!("User {} does not exist", name);
-            let user = UserForCreate::new(
-                name.to_string(),
-                replit_id.to_string(),
-                replit_profile_pic.to_string(),
-                pubkey.to_string(),
-                relays,
-                federation_ids,
-            );
-            self.create(user).await?;
-        }
-
-        Ok(())
+        self.0
+            .transaction(|tx| {
+                let relays = relays.clone();
+                let federation_ids = federation_ids.clone();
+                async move {
+                    let users = tx.users();
+                    if let Some(user) = users.get_by_name(name).await? {
+                        info!("User {} already exists", name);
+                        let user_for_update = UserForUpdate::builder()
+                            .name(name.to_string())
+                            .replit_id(replit_id.to_string())
+                            .replit_profile_pic(replit_profile_pic.to_string())
+                            .pubkey(pubkey.to_string())
+                            .relays(relays)
+                            .federation_ids(federation_ids)
+                            .build();
+                        users.update(user.id, user_for_update).await?;
+                    } else {
+                        info!("User {} does not exist", name);
+                        let user = UserForCreate::new(
+                            name.to_string(),
+                            replit_id.to_string(),
+                            replit_profile_pic.to_string(),
+                            pubkey.to_string(),
+                            relays,
+                            federation_ids,
+                        );
+                        users.create(user).await?;
+                    }
+                    Ok(())
+                }
+            })
+            .await
     }
 
     pub async fn create_new_user(
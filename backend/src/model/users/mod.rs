@@ -1,11 +1,15 @@
 pub mod db;
+pub mod success_action;
 
 use anyhow::Result;
 use postgres_from_row::FromRow;
+use postgres_types::Json;
 use serde::Serialize;
 use tokio_postgres::Row;
 use uuid::Uuid;
 
+use self::success_action::SuccessActionTemplate;
+
 #[derive(Debug, Clone, Serialize)]
 pub struct UserForCreate {
     pub name: String,
@@ -51,6 +55,9 @@ pub struct User {
     pub relays: Vec<String>,
     pub federation_ids: Vec<String>,
     pub connection_code_uuid: String,
+    /// May hold an `aes` secret, so it is never serialized with the user.
+    #[serde(skip)]
+    pub success_action: Option<SuccessActionTemplate>,
 }
 
 impl FromRow for User {
@@ -68,7 +75,10 @@ impl FromRow for User {
             last_tweak: row.get("last_tweak"),
             relays: row.get("relays"),
             federation_ids: row.get("federation_ids"),
-            connection_code_uuid: row.get("connection_code"),
+            connection_code_uuid: row.get("connection_code_uuid"),
+            success_action: row
+                .get::<_, Option<Json<SuccessActionTemplate>>>("success_action")
+                .map(|Json(action)| action),
         })
     }
 }
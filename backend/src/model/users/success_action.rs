@@ -0,0 +1,69 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+/// LUD-09 caps `message` and `description` at 144 characters.
+const MAX_TEXT_LEN: usize = 144;
+/// LUD-10 caps the base64 ciphertext at 4kb.
+const MAX_CIPHERTEXT_LEN: usize = 4096;
+
+/// What a payer's wallet shows after paying a user, stored per user and
+/// rendered into the callback response. `aes` secrets are encrypted with the
+/// invoice preimage at render time, so they only unlock once paid.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "tag", rename_all = "lowercase")]
+pub enum SuccessActionTemplate {
+    Message {
+        message: String,
+    },
+    Url {
+        description: String,
+        url: Url,
+    },
+    Aes {
+        description: String,
+        plaintext: String,
+    },
+}
+
+impl SuccessActionTemplate {
+    /// `callback_host` is the host wallets call back to; LUD-09 requires `url`
+    /// actions to stay on that domain.
+    pub fn validate(&self, callback_host: Option<&str>) -> Result<()> {
+        match self {
+            Self::Message { message } => check_text("message", message),
+            Self::Url { description, url } => {
+                check_text("description", description)?;
+                if !matches!(url.scheme(), "http" | "https") {
+                    bail!("url must be http(s)");
+                }
+                if url.host_str() != callback_host {
+                    bail!("url must be on the same domain as the callback");
+                }
+                Ok(())
+            }
+            Self::Aes {
+                description,
+                plaintext,
+            } => {
+                check_text("description", description)?;
+                if plaintext.is_empty() {
+                    bail!("plaintext must not be empty");
+                }
+                // AES-CBC pads to the next full block, base64 then expands by 4/3
+                let padded = (plaintext.len() / 16 + 1) * 16;
+                if padded.div_ceil(3) * 4 > MAX_CIPHERTEXT_LEN {
+                    bail!("plaintext is too long");
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn check_text(field: &str, value: &str) -> Result<()> {
+    if value.chars().count() > MAX_TEXT_LEN {
+        bail!("{field} must be at most {MAX_TEXT_LEN} characters");
+    }
+    Ok(())
+}
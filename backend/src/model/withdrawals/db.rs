@@ -0,0 +1,135 @@
+use crate::model::repo::WithdrawalRepo;
+use crate::model::Db;
+use anyhow::{ensure, Result};
+use async_trait::async_trait;
+
+use super::{Withdrawal, WithdrawalForCreate, WithdrawalState};
+
+#[derive(Clone)]
+pub struct WithdrawalDb(pub Db);
+
+#[async_trait]
+impl WithdrawalRepo for WithdrawalDb {
+    async fn create(
+        &self,
+        withdrawal: WithdrawalForCreate,
+        max_claimable: i64,
+    ) -> Result<Option<Withdrawal>> {
+        self.0
+            .transaction(|tx| {
+                let withdrawal = withdrawal.clone();
+                async move {
+                    let db = tx.withdrawals().0;
+                    let sql = "SELECT count(*) FROM withdrawals WHERE user_id = $1 AND state IN ($2, $3)";
+                    let claimable = db
+                        .query_value::<i64>(
+                            sql,
+                            &[
+                                &withdrawal.user_id,
+                                &WithdrawalState::Open,
+                                &WithdrawalState::Failed,
+                            ],
+                        )
+                        .await?;
+                    if claimable >= max_claimable {
+                        return Ok(None);
+                    }
+
+                    let sql = "INSERT INTO withdrawals (id, user_id, federation_id, k1, description, min_withdrawable, max_withdrawable, state) VALUES ($1, $2, $3, $4, $5, $6, $7, $8) RETURNING *";
+                    db.query_one::<Withdrawal>(
+                        sql,
+                        &[
+                            &withdrawal.id,
+                            &withdrawal.user_id,
+                            &withdrawal.federation_id,
+                            &withdrawal.k1,
+                            &withdrawal.description,
+                            &withdrawal.min_withdrawable,
+                            &withdrawal.max_withdrawable,
+                            &WithdrawalState::Open,
+                        ],
+                    )
+                    .await
+                    .map(Some)
+                }
+            })
+            .await
+    }
+
+    async fn get(&self, id: &str) -> Result<Option<Withdrawal>> {
+        let sql = "SELECT * FROM withdrawals WHERE id = $1";
+        self.0.query_opt::<Withdrawal>(sql, &[&id]).await
+    }
+
+    async fn get_by_user(&self, user_id: i32) -> Result<Vec<Withdrawal>> {
+        let sql = "SELECT * FROM withdrawals WHERE user_id = $1 ORDER BY created_at DESC";
+        self.0.query(sql, &[&user_id]).await
+    }
+
+    async fn get_by_state(&self, state: WithdrawalState) -> Result<Vec<Withdrawal>> {
+        let sql = "SELECT * FROM withdrawals WHERE state = $1";
+        self.0.query(sql, &[&state]).await
+    }
+
+    async fn claim(&self, id: &str, k1: &str, bolt11: &str) -> Result<Option<Withdrawal>> {
+        let sql = "UPDATE withdrawals SET state = $1, bolt11 = $2, op_id = NULL, updated_at = now() WHERE id = $3 AND k1 = $4 AND state IN ($5, $6) RETURNING *";
+        self.0
+            .query_opt::<Withdrawal>(
+                sql,
+                &[
+                    &WithdrawalState::Pending,
+                    &bolt11,
+                    &id,
+                    &k1,
+                    &WithdrawalState::Open,
+                    &WithdrawalState::Failed,
+                ],
+            )
+            .await
+    }
+
+    async fn set_operation(&self, id: &str, op_id: &str, internal: bool) -> Result<()> {
+        let sql = "UPDATE withdrawals SET op_id = $1, internal = $2, updated_at = now() WHERE id = $3 AND state IN ($4, $5)";
+        self.0
+            .execute(
+                sql,
+                &[
+                    &op_id,
+                    &internal,
+                    &id,
+                    &WithdrawalState::Pending,
+                    &WithdrawalState::PaymentUnknown,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn release(&self, id: &str) -> Result<()> {
+        let sql = "UPDATE withdrawals SET state = $1, updated_at = now() WHERE id = $2 AND state = $3 AND op_id IS NULL";
+        self.0
+            .execute(
+                sql,
+                &[&WithdrawalState::Failed, &id, &WithdrawalState::Pending],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn update_payment_state(&self, id: &str, state: WithdrawalState) -> Result<()> {
+        ensure!(!state.is_claimable(), "{state:?} would reopen a paid link");
+        let sql = "UPDATE withdrawals SET state = $1, updated_at = now() WHERE id = $2 AND state IN ($3, $4)";
+        self.0
+            .execute(
+                sql,
+                &[
+                    &state,
+                    &id,
+                    &WithdrawalState::Pending,
+                    &WithdrawalState::PaymentUnknown,
+                ],
+            )
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,149 @@
+pub mod db;
+
+use anyhow::Result;
+use postgres_from_row::FromRow;
+use postgres_types::{FromSql, ToSql};
+use serde::{Deserialize, Serialize};
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[repr(i32)]
+pub enum WithdrawalState {
+    /// Waiting for a wallet to submit an invoice.
+    Open = 0,
+    /// An invoice was submitted and is being paid.
+    Pending = 1,
+    Paid = 2,
+    /// The payment could not be started; the link can be used again.
+    Failed = 3,
+    /// A payment may have been made but its outcome isn't known, e.g. its
+    /// operation couldn't be recorded or followed. The reconciler resolves it
+    /// from the federation's operation log.
+    PaymentUnknown = 4,
+    /// The payment was made and failed. The link stays used up rather than
+    /// risk paying out twice.
+    PaymentFailed = 5,
+}
+
+impl WithdrawalState {
+    /// Whether a wallet may submit an invoice. Only before any payment was
+    /// made, so a link never pays out twice.
+    pub fn is_claimable(&self) -> bool {
+        matches!(self, Self::Open | Self::Failed)
+    }
+}
+
+impl FromSql<'_> for WithdrawalState {
+    fn accepts(ty: &postgres_types::Type) -> bool {
+        ty.name() == "int4"
+    }
+
+    fn from_sql(
+        ty: &postgres_types::Type,
+        raw: &[u8],
+    ) -> Result<Self, Box<dyn std::error::Error + Sync + Send>> {
+        let value = i32::from_sql(ty, raw)?;
+        match value {
+            0 => Ok(WithdrawalState::Open),
+            1 => Ok(WithdrawalState::Pending),
+            2 => Ok(WithdrawalState::Paid),
+            3 => Ok(WithdrawalState::Failed),
+            4 => Ok(WithdrawalState::PaymentUnknown),
+            5 => Ok(WithdrawalState::PaymentFailed),
+            _ => Err(format!("Invalid withdrawal state: {}", value).into()),
+        }
+    }
+}
+
+impl ToSql for WithdrawalState {
+    fn to_sql(
+        &self,
+        ty: &postgres_types::Type,
+        out: &mut bytes::BytesMut,
+    ) -> Result<tokio_postgres::types::IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        (*self as i32).to_sql(ty, out)
+    }
+
+    fn accepts(ty: &postgres_types::Type) -> bool {
+        ty.name() == "int4"
+    }
+
+    fn to_sql_checked(
+        &self,
+        ty: &postgres_types::Type,
+        out: &mut bytes::BytesMut,
+    ) -> Result<tokio_postgres::types::IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        (*self as i32).to_sql_checked(ty, out)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WithdrawalForCreate {
+    pub id: String,
+    pub user_id: i32,
+    pub federation_id: String,
+    pub k1: String,
+    pub description: String,
+    pub min_withdrawable: i64,
+    pub max_withdrawable: i64,
+}
+
+impl WithdrawalForCreate {
+    pub fn new(
+        user_id: i32,
+        federation_id: String,
+        description: String,
+        min_withdrawable: i64,
+        max_withdrawable: i64,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            user_id,
+            federation_id,
+            k1: hex::encode(rand::random::<[u8; 32]>()),
+            description,
+            min_withdrawable,
+            max_withdrawable,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Withdrawal {
+    pub id: String,
+    pub user_id: i32,
+    pub federation_id: String,
+    /// Secret the wallet echoes back in the callback, per LUD-03.
+    pub k1: String,
+    pub description: String,
+    pub min_withdrawable: i64,
+    pub max_withdrawable: i64,
+    pub state: WithdrawalState,
+    pub bolt11: Option<String>,
+    pub op_id: Option<String>,
+    /// Paid within the federation rather than through a gateway.
+    pub internal: bool,
+}
+
+impl FromRow for Withdrawal {
+    fn from_row(row: &Row) -> Self {
+        Self::try_from_row(row).expect("Decoding row failed")
+    }
+
+    fn try_from_row(row: &Row) -> Result<Self, tokio_postgres::Error> {
+        Ok(Withdrawal {
+            id: row.get("id"),
+            user_id: row.get("user_id"),
+            federation_id: row.get("federation_id"),
+            k1: row.get("k1"),
+            description: row.get("description"),
+            min_withdrawable: row.get("min_withdrawable"),
+            max_withdrawable: row.get("max_withdrawable"),
+            state: row.get("state"),
+            bolt11: row.get("bolt11"),
+            op_id: row.get("op_id"),
+            internal: row.get("internal"),
+        })
+    }
+}
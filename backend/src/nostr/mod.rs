@@ -0,0 +1,153 @@
+#[cfg(test)]
+mod nip46_signer;
+pub mod notification;
+pub mod nwc;
+pub mod relay_pool;
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context, Result};
+
+use nostr_sdk::nips::nip46::NostrConnectURI;
+use nostr_sdk::EventBuilder;
+use nostr_sdk::FromBech32;
+use nostr_sdk::Keys;
+use nostr_sdk::Kind;
+use nostr_sdk::Nip46Signer;
+use nostr_sdk::NostrSigner;
+use nostr_sdk::PublicKey;
+use nostr_sdk::SecretKey;
+use nostr_sdk::Tag;
+use nostr_sdk::ToBech32;
+use tracing::info;
+
+use self::notification::{
+    payment_state_identifier, PaymentNotification, PaymentState, PAYMENT_NOTIFICATION_VERSION,
+};
+use self::relay_pool::RelayPool;
+use crate::config::NostrSignerConfig;
+use crate::model::invoices::Invoice;
+
+/// How long to wait on a remote signer, both to connect and per request.
+const BUNKER_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
+pub struct Nostr {
+    pub client: nostr_sdk::Client,
+    pub pool: RelayPool,
+    signer: NostrSigner,
+    public_key: PublicKey,
+}
+
+impl Nostr {
+    pub async fn new(
+        signer: &NostrSignerConfig,
+        relays: Vec<String>,
+        max_user_relays: usize,
+    ) -> Result<Self> {
+        let signer = match signer {
+            NostrSignerConfig::Keys(nsec) => {
+                NostrSigner::Keys(Keys::new(SecretKey::from_bech32(nsec.expose())?))
+            }
+            NostrSignerConfig::Bunker(uri) => {
+                let uri = NostrConnectURI::parse(uri.expose())?;
+                // the bunker authorizes this ephemeral key, not the server key
+                let signer = Nip46Signer::new(uri, Keys::generate(), BUNKER_TIMEOUT, None)
+                    .await
+                    .context("Failed to connect to the nostr bunker")?;
+                info!("Using NIP-46 remote signer");
+                NostrSigner::nip46(signer)
+            }
+        };
+        let public_key = signer.public_key().await?;
+        info!("Nostr npub: {}", public_key.to_bech32()?);
+
+        let client = nostr_sdk::Client::new(signer.clone());
+        let pool = RelayPool::new(client.clone(), relays, max_user_relays);
+        Ok(Self {
+            client,
+            pool,
+            signer,
+            public_key,
+        })
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        self.public_key
+    }
+
+    pub async fn nip04_encrypt(&self, public_key: &PublicKey, content: &str) -> Result<String> {
+        Ok(self.signer.nip04_encrypt(public_key, content).await?)
+    }
+
+    pub async fn nip04_decrypt(&self, public_key: &PublicKey, content: &str) -> Result<String> {
+        Ok(self.signer.nip04_decrypt(public_key, content).await?)
+    }
+
+    /// Connects the server relays; user relays are connected when first needed.
+    pub async fn connect(&self) -> Result<()> {
+        let relays = self.pool.server_relays();
+        info!("Adding {} relays", relays.len());
+        for relay in relays {
+            self.client.add_relay(relay).await?;
+        }
+        self.client.connect().await;
+        Ok(())
+    }
+
+    /// Sends a [`PaymentNotification`] for a settled invoice as a NIP-17
+    /// gift-wrapped DM.
+    pub async fn notify_payment(&self, invoice: &Invoice, user_relays: &[String]) -> Result<()> {
+        let receiver = PublicKey::from_str(&invoice.user_pubkey)?;
+        let notification = serde_json::to_string(&PaymentNotification::received(invoice))?;
+        let relays = self.pool.relays_for(&receiver, user_relays).await;
+        let output = self
+            .client
+            .send_private_msg_to(relays, receiver, notification, None)
+            .await?;
+        self.pool.record(&output).await;
+
+        info!(
+            "Sent payment notification {} for invoice {} to {} relays",
+            output.val,
+            invoice.op_id,
+            output.success.len()
+        );
+
+        Ok(())
+    }
+
+    /// Replaces the user's NIP-78 resync event with their latest payments,
+    /// NIP-44 encrypted to them.
+    pub async fn publish_payment_state(
+        &self,
+        user_pubkey: &str,
+        invoices: &[Invoice],
+        user_relays: &[String],
+    ) -> Result<()> {
+        let receiver = PublicKey::from_str(user_pubkey)?;
+        let state = PaymentState {
+            version: PAYMENT_NOTIFICATION_VERSION,
+            payments: invoices.iter().map(PaymentNotification::received).collect(),
+        };
+        let content = self
+            .signer
+            .nip44_encrypt(&receiver, serde_json::to_string(&state)?)
+            .await
+            .context("Failed to encrypt payment state")?;
+        let builder = EventBuilder::new(
+            Kind::ApplicationSpecificData,
+            content,
+            [
+                Tag::identifier(payment_state_identifier(&receiver.to_hex())),
+                Tag::public_key(receiver),
+            ],
+        );
+
+        let relays = self.pool.relays_for(&receiver, user_relays).await;
+        let output = self.client.send_event_builder_to(relays, builder).await?;
+        self.pool.record(&output).await;
+        Ok(())
+    }
+}
@@ -0,0 +1,158 @@
+//! Signing through a NIP-46 bunker: a relay stand-in served from the test
+//! process answers the signer's requests itself, with keys the server never
+//! sees.
+
+use std::collections::HashSet;
+
+use anyhow::{bail, Result};
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::response::Response;
+use axum::routing::get;
+use axum::Router;
+use nostr_sdk::nips::nip46::{Message as Nip46Message, Request as Nip46Request, ResponseResult};
+use nostr_sdk::nips::{nip04, nip44};
+use nostr_sdk::{
+    ClientMessage, Event, EventBuilder, JsonUtil, Keys, Kind, RelayMessage, SubscriptionId, Tag,
+};
+
+use super::Nostr;
+use crate::config::NostrSignerConfig;
+use crate::secret::SecretString;
+
+/// Acknowledges every event, and answers kind 24133 requests addressed to
+/// `keys` on the subscriptions of the connection that sent them.
+async fn start_bunker(keys: Keys) -> Result<String> {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("ws://{}", listener.local_addr()?);
+    let app = Router::new().route("/", get(handle_relay)).with_state(keys);
+    tokio::spawn(async move { axum::serve(listener, app).await });
+    Ok(url)
+}
+
+async fn handle_relay(State(keys): State<Keys>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| serve_relay(keys, socket))
+}
+
+async fn serve_relay(keys: Keys, mut socket: WebSocket) {
+    let mut subscriptions: HashSet<SubscriptionId> = HashSet::new();
+    while let Some(Ok(message)) = socket.recv().await {
+        let Message::Text(text) = message else {
+            continue;
+        };
+        let mut replies = Vec::new();
+        match ClientMessage::from_json(&text) {
+            Ok(ClientMessage::Event(event)) => {
+                replies.push(RelayMessage::ok(event.id, true, ""));
+                if event.kind == Kind::NostrConnect {
+                    match answer(&keys, &event) {
+                        Ok(response) => replies.extend(
+                            subscriptions
+                                .iter()
+                                .map(|id| RelayMessage::event(id.clone(), response.clone())),
+                        ),
+                        Err(e) => panic!("bunker stand-in failed: {e:#}"),
+                    }
+                }
+            }
+            Ok(ClientMessage::Req {
+                subscription_id, ..
+            }) => {
+                subscriptions.insert(subscription_id.clone());
+                replies.push(RelayMessage::eose(subscription_id));
+            }
+            Ok(ClientMessage::Close(subscription_id)) => {
+                subscriptions.remove(&subscription_id);
+            }
+            _ => {}
+        }
+        for reply in replies {
+            if socket.send(Message::Text(reply.as_json())).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Handles one request, replying in the encryption it was sent with.
+fn answer(keys: &Keys, event: &Event) -> Result<Event> {
+    let secret_key = keys.secret_key()?;
+    let (content, nip44) = match nip04::decrypt(secret_key, &event.pubkey, &event.content) {
+        Ok(content) => (content, false),
+        Err(_) => (
+            nip44::decrypt(secret_key, &event.pubkey, &event.content)?,
+            true,
+        ),
+    };
+    let message = Nip46Message::from_json(content)?;
+    let result = match message.to_request()? {
+        Nip46Request::Connect { .. } => ResponseResult::Connect,
+        Nip46Request::GetPublicKey => ResponseResult::GetPublicKey(keys.public_key()),
+        Nip46Request::Nip04Encrypt { public_key, text } => {
+            ResponseResult::Nip04Encrypt(nip04::encrypt(secret_key, &public_key, text)?)
+        }
+        Nip46Request::Nip04Decrypt {
+            public_key,
+            ciphertext,
+        } => ResponseResult::Nip04Decrypt(nip04::decrypt(secret_key, &public_key, ciphertext)?),
+        Nip46Request::Ping => ResponseResult::Pong,
+        request => bail!("unexpected request {request:?}"),
+    };
+
+    let response = Nip46Message::response(message.id(), Some(result), None).as_json();
+    let content = if nip44 {
+        nip44::encrypt(
+            secret_key,
+            &event.pubkey,
+            response,
+            nip44::Version::default(),
+        )?
+    } else {
+        nip04::encrypt(secret_key, &event.pubkey, response)?
+    };
+    Ok(
+        EventBuilder::new(Kind::NostrConnect, content, [Tag::public_key(event.pubkey)])
+            .to_event(keys)?,
+    )
+}
+
+#[tokio::test]
+async fn signs_with_the_bunker_key() -> Result<()> {
+    let bunker_keys = Keys::generate();
+    let relay_url = start_bunker(bunker_keys.clone()).await?;
+    let uri = format!(
+        "bunker://{}?relay={}",
+        bunker_keys.public_key().to_hex(),
+        relay_url
+    );
+
+    let nostr = Nostr::new(
+        &NostrSignerConfig::Bunker(SecretString::new(uri)),
+        vec![relay_url],
+        0,
+    )
+    .await?;
+    assert_eq!(nostr.public_key(), bunker_keys.public_key());
+
+    // NWC traffic is encrypted and decrypted by the bunker
+    let client_keys = Keys::generate();
+    let request = nostr
+        .nip04_encrypt(&client_keys.public_key(), "make_invoice")
+        .await?;
+    assert_eq!(
+        nip04::decrypt(
+            client_keys.secret_key()?,
+            &bunker_keys.public_key(),
+            &request
+        )?,
+        "make_invoice"
+    );
+    let reply = nip04::encrypt(client_keys.secret_key()?, &bunker_keys.public_key(), "paid")?;
+    assert_eq!(
+        nostr
+            .nip04_decrypt(&client_keys.public_key(), &reply)
+            .await?,
+        "paid"
+    );
+    Ok(())
+}
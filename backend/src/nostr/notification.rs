@@ -0,0 +1,92 @@
+//! Payment notifications sent to recipients over nostr.
+//!
+//! Every settled invoice is sent to the user as a NIP-17 private direct
+//! message (NIP-59 gift wrapped, NIP-44 encrypted) whose content is a JSON
+//! [`PaymentNotification`]:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "type": "payment_received",
+//!   "amount_msats": 21000,
+//!   "federation_id": "15db8cb4f1ec8e484d73b889372bec94812580f929e8148b7437d359af422cd3",
+//!   "tweak": 7,
+//!   "op_id": "9f0c…",
+//!   "comment": "thanks!",
+//!   "payer": "3bf0c63f…",
+//!   "settled_at": 1700000000
+//! }
+//! ```
+//!
+//! New optional fields may appear within a version; removing or changing the
+//! meaning of a field bumps `version`, so wallets should ignore unknown fields
+//! and messages with a version they don't understand.
+//!
+//! When `publish_payment_state` is enabled the server also keeps one NIP-78
+//! (kind 30078) replaceable event per user, tagged `d` =
+//! `replex:payments:<user pubkey hex>` and `p` = the user, whose content is a
+//! NIP-44 encrypted [`PaymentState`] of their most recent payments. Wallets
+//! that missed DMs fetch it to resync.
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::model::invoices::Invoice;
+
+pub const PAYMENT_NOTIFICATION_VERSION: u32 = 1;
+
+/// Payments included in the replaceable [`PaymentState`] event.
+pub const PAYMENT_STATE_LIMIT: i64 = 50;
+
+pub fn payment_state_identifier(user_pubkey: &str) -> String {
+    format!("replex:payments:{user_pubkey}")
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PaymentNotificationType {
+    PaymentReceived,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PaymentNotification {
+    pub version: u32,
+    #[serde(rename = "type")]
+    pub notification_type: PaymentNotificationType,
+    pub amount_msats: u64,
+    pub federation_id: String,
+    /// Index the user's pubkey was tweaked with; the wallet needs it to claim the ecash.
+    pub tweak: i64,
+    pub op_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub comment: Option<String>,
+    /// Hex pubkey of the zap request author, when paid as a zap.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub payer: Option<String>,
+    /// Unix seconds.
+    pub settled_at: i64,
+}
+
+impl PaymentNotification {
+    pub fn received(invoice: &Invoice) -> Self {
+        Self {
+            version: PAYMENT_NOTIFICATION_VERSION,
+            notification_type: PaymentNotificationType::PaymentReceived,
+            amount_msats: invoice.amount as u64,
+            federation_id: invoice.federation_id.clone(),
+            tweak: invoice.tweak,
+            op_id: invoice.op_id.clone(),
+            comment: invoice.comment.clone(),
+            payer: invoice.payer.clone(),
+            settled_at: invoice.settled_at.unwrap_or_else(Utc::now).timestamp(),
+        }
+    }
+}
+
+/// Content of the per-user replaceable resync event.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PaymentState {
+    pub version: u32,
+    /// Most recent first.
+    pub payments: Vec<PaymentNotification>,
+}
@@ -0,0 +1,435 @@
+//! Nostr Wallet Connect (NIP-47) service for users' addresses.
+//!
+//! Each connection has its own secret; requests (kind 23194) signed with it
+//! act on the owning user's invoices. Requests and responses (kind 23195) are
+//! NIP-04 encrypted between the connection secret and the server key, and
+//! are exchanged on the configured server relays.
+
+use std::collections::{HashSet, VecDeque};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use chrono::DateTime;
+use multimint::fedimint_ln_common::lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescription};
+use nostr_sdk::{
+    Event, EventBuilder, EventId, Filter, Kind, PublicKey, RelayPoolNotification, SecretKey, Tag,
+    Timestamp,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info, warn};
+use url::Url;
+
+use crate::model::invoices::{Invoice, InvoiceState};
+use crate::model::nwc_connections::NwcConnection;
+use crate::router::handlers::lnurlp::callback::check_sendable;
+use crate::state::{AppState, InvoiceRequest};
+
+pub const SUPPORTED_METHODS: &[&str] = &["make_invoice", "lookup_invoice", "list_transactions"];
+
+const DEFAULT_TRANSACTIONS_LIMIT: i64 = 20;
+const MAX_TRANSACTIONS_LIMIT: i64 = 100;
+/// Relays may deliver a request more than once; this many recent ids are remembered.
+const SEEN_REQUESTS: usize = 1_000;
+
+/// `nostr+walletconnect://` URI a client imports to use a connection.
+pub fn connection_uri(service: &PublicKey, relays: &[String], secret: &SecretKey) -> Url {
+    let mut uri = Url::parse(&format!("nostr+walletconnect://{}", service.to_hex()))
+        .expect("hex pubkey is a valid host");
+    {
+        let mut query = uri.query_pairs_mut();
+        for relay in relays {
+            query.append_pair("relay", relay);
+        }
+        query.append_pair("secret", &secret.to_secret_hex());
+    }
+    uri
+}
+
+#[derive(Deserialize, Debug)]
+struct NwcRequest {
+    method: String,
+    #[serde(default)]
+    params: serde_json::Value,
+}
+
+#[derive(Deserialize, Debug)]
+struct MakeInvoiceParams {
+    amount: u64,
+    description: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct LookupInvoiceParams {
+    payment_hash: Option<String>,
+    invoice: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct ListTransactionsParams {
+    from: Option<i64>,
+    until: Option<i64>,
+    limit: Option<i64>,
+    offset: Option<i64>,
+    unpaid: Option<bool>,
+    #[serde(rename = "type")]
+    transaction_type: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum NwcErrorCode {
+    RateLimited,
+    NotImplemented,
+    Unauthorized,
+    NotFound,
+    Internal,
+    Other,
+}
+
+#[derive(Serialize, Debug)]
+pub struct NwcError {
+    pub code: NwcErrorCode,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct NwcResponse {
+    pub result_type: String,
+    pub error: Option<NwcError>,
+    pub result: Option<serde_json::Value>,
+}
+
+impl NwcResponse {
+    fn error(method: &str, code: NwcErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            result_type: method.to_string(),
+            error: Some(NwcError {
+                code,
+                message: message.into(),
+            }),
+            result: None,
+        }
+    }
+}
+
+/// NIP-47 transaction; replex only receives, so every one is `incoming`.
+#[derive(Serialize, Debug)]
+pub struct Transaction {
+    #[serde(rename = "type")]
+    pub transaction_type: &'static str,
+    pub invoice: String,
+    pub description: Option<String>,
+    pub preimage: Option<String>,
+    pub payment_hash: Option<String>,
+    pub amount: u64,
+    pub fees_paid: u64,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub settled_at: Option<i64>,
+}
+
+impl From<&Invoice> for Transaction {
+    fn from(invoice: &Invoice) -> Self {
+        let bolt11 = Bolt11Invoice::from_str(&invoice.bolt11).ok();
+        let created_at = invoice.created_at.map(|t| t.timestamp()).or_else(|| {
+            bolt11
+                .as_ref()
+                .map(|b| b.duration_since_epoch().as_secs() as i64)
+        });
+        Self {
+            transaction_type: "incoming",
+            invoice: invoice.bolt11.clone(),
+            description: bolt11.as_ref().and_then(|b| match b.description() {
+                Bolt11InvoiceDescription::Direct(d) => Some(d.to_string()),
+                Bolt11InvoiceDescription::Hash(_) => None,
+            }),
+            preimage: (invoice.state == InvoiceState::Settled)
+                .then(|| invoice.preimage.clone())
+                .flatten(),
+            payment_hash: invoice
+                .payment_hash
+                .clone()
+                .or_else(|| bolt11.as_ref().map(|b| b.payment_hash().to_string())),
+            amount: invoice.amount as u64,
+            fees_paid: 0,
+            created_at: created_at.unwrap_or_default(),
+            expires_at: bolt11
+                .as_ref()
+                .and_then(|b| b.expires_at())
+                .map(|t| t.as_secs() as i64),
+            settled_at: invoice.settled_at.map(|t| t.timestamp()),
+        }
+    }
+}
+
+pub fn spawn_nwc_service(state: AppState) {
+    if state.nwc_connections().is_err() {
+        info!("NWC service disabled, it needs a Postgres database");
+        return;
+    }
+    let tasks = state.tasks.clone();
+    tasks.spawn(async move {
+        if let Err(e) = run(&state).await {
+            error!("NWC service stopped: {e}");
+        }
+    });
+    info!("Started NWC service");
+}
+
+async fn run(state: &AppState) -> Result<()> {
+    let client = &state.nostr.client;
+    let relays = state.nostr.pool.server_relays().to_vec();
+    let mut notifications = client.notifications();
+
+    let info = EventBuilder::new(Kind::WalletConnectInfo, SUPPORTED_METHODS.join(" "), []);
+    client.send_event_builder_to(relays.clone(), info).await?;
+
+    let filter = Filter::new()
+        .kind(Kind::WalletConnectRequest)
+        .pubkey(state.nostr.public_key())
+        .since(Timestamp::now());
+    let subscription = client.subscribe_to(relays, vec![filter], None).await?.val;
+
+    let mut seen: HashSet<EventId> = HashSet::new();
+    let mut seen_order: VecDeque<EventId> = VecDeque::new();
+    loop {
+        let notification = tokio::select! {
+            _ = state.shutdown.cancelled() => break,
+            notification = notifications.recv() => notification,
+        };
+        match notification {
+            Ok(RelayPoolNotification::Event {
+                subscription_id,
+                event,
+                ..
+            }) if subscription_id == subscription => {
+                if !seen.insert(event.id) {
+                    continue;
+                }
+                seen_order.push_back(event.id);
+                if seen_order.len() > SEEN_REQUESTS {
+                    if let Some(oldest) = seen_order.pop_front() {
+                        seen.remove(&oldest);
+                    }
+                }
+
+                let request_state = state.clone();
+                state.tasks.spawn(async move {
+                    if let Err(e) = handle_request(&request_state, &event).await {
+                        warn!("Failed to handle NWC request {}: {}", event.id, e);
+                    }
+                });
+            }
+            Ok(_) => {}
+            Err(RecvError::Lagged(missed)) => warn!("NWC service missed {missed} notifications"),
+            Err(RecvError::Closed) => break,
+        }
+    }
+
+    client.unsubscribe(subscription).await;
+    Ok(())
+}
+
+async fn handle_request(state: &AppState, event: &Event) -> Result<()> {
+    let content = state
+        .nostr
+        .nip04_decrypt(&event.pubkey, &event.content)
+        .await?;
+    let request: NwcRequest = serde_json::from_str(&content).context("Invalid NWC request")?;
+
+    let connection = state
+        .nwc_connections()
+        .map_err(|e| e.error)?
+        .get_active_by_pubkey(&event.pubkey.to_hex())
+        .await?;
+    let response = match connection {
+        Some(connection) => {
+            info!(
+                "NWC {} request from connection {}",
+                request.method, connection.id
+            );
+            match dispatch(state, &connection, &request).await {
+                Ok(response) => response,
+                Err(e) => {
+                    error!("NWC {} failed: {:?}", request.method, e);
+                    NwcResponse::error(&request.method, NwcErrorCode::Internal, "Internal error")
+                }
+            }
+        }
+        None => NwcResponse::error(
+            &request.method,
+            NwcErrorCode::Unauthorized,
+            "Unknown or revoked connection",
+        ),
+    };
+
+    let content = state
+        .nostr
+        .nip04_encrypt(&event.pubkey, &serde_json::to_string(&response)?)
+        .await?;
+    let reply = EventBuilder::new(
+        Kind::WalletConnectResponse,
+        content,
+        [Tag::public_key(event.pubkey), Tag::event(event.id)],
+    );
+    state
+        .nostr
+        .client
+        .send_event_builder_to(state.nostr.pool.server_relays().to_vec(), reply)
+        .await?;
+    Ok(())
+}
+
+async fn dispatch(
+    state: &AppState,
+    connection: &NwcConnection,
+    request: &NwcRequest,
+) -> Result<NwcResponse> {
+    let method = request.method.as_str();
+    let result = match method {
+        "make_invoice" => {
+            let Ok(params) = serde_json::from_value(request.params.clone()) else {
+                return Ok(NwcResponse::error(
+                    method,
+                    NwcErrorCode::Other,
+                    "Invalid params",
+                ));
+            };
+            match state.rate_limiter.check_nwc(&connection.pubkey).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    warn!("Rate limited NWC connection {}", connection.id);
+                    return Ok(NwcResponse::error(
+                        method,
+                        NwcErrorCode::RateLimited,
+                        "Too many requests, try again later",
+                    ));
+                }
+                Err(e) => error!("Rate limiter error: {e}"),
+            }
+            if let Err(e) = check_sendable(&state.config, params.amount) {
+                return Ok(NwcResponse::error(
+                    method,
+                    NwcErrorCode::Other,
+                    e.error.to_string(),
+                ));
+            }
+            make_invoice(state, connection, params).await?
+        }
+        "lookup_invoice" => {
+            let Ok(params) = serde_json::from_value(request.params.clone()) else {
+                return Ok(NwcResponse::error(
+                    method,
+                    NwcErrorCode::Other,
+                    "Invalid params",
+                ));
+            };
+            match lookup_invoice(state, connection, params).await? {
+                Some(result) => result,
+                None => {
+                    return Ok(NwcResponse::error(
+                        method,
+                        NwcErrorCode::NotFound,
+                        "Invoice not found",
+                    ))
+                }
+            }
+        }
+        "list_transactions" => {
+            let params = serde_json::from_value(request.params.clone()).unwrap_or_default();
+            list_transactions(state, connection, params).await?
+        }
+        _ => {
+            return Ok(NwcResponse::error(
+                method,
+                NwcErrorCode::NotImplemented,
+                format!("{method} is not supported"),
+            ))
+        }
+    };
+
+    Ok(NwcResponse {
+        result_type: method.to_string(),
+        error: None,
+        result: Some(result),
+    })
+}
+
+async fn make_invoice(
+    state: &AppState,
+    connection: &NwcConnection,
+    params: MakeInvoiceParams,
+) -> Result<serde_json::Value> {
+    let user = state
+        .users
+        .get(connection.user_id)
+        .await?
+        .context("User not found")?;
+    let federation_id = state.get_federation(&user).await.map_err(|e| e.error)?;
+
+    let request = InvoiceRequest {
+        amount_msats: params.amount,
+        comment: params.description,
+        nonce: None,
+        fiat: None,
+        payer: None,
+    };
+    let (_, invoice) = state
+        .create_invoice_store_and_notify(&user, &request, federation_id)
+        .await?;
+    Ok(serde_json::to_value(Transaction::from(&invoice))?)
+}
+
+async fn lookup_invoice(
+    state: &AppState,
+    connection: &NwcConnection,
+    params: LookupInvoiceParams,
+) -> Result<Option<serde_json::Value>> {
+    let invoices = &state.invoices;
+    let invoice = match (params.payment_hash, params.invoice) {
+        (Some(payment_hash), _) => {
+            invoices
+                .get_by_payment_hash(connection.user_id, &payment_hash)
+                .await?
+        }
+        (None, Some(bolt11)) => invoices.get_by_bolt11(connection.user_id, &bolt11).await?,
+        (None, None) => bail!("Either payment_hash or invoice is required"),
+    };
+    invoice
+        .map(|invoice| serde_json::to_value(Transaction::from(&invoice)))
+        .transpose()
+        .map_err(Into::into)
+}
+
+async fn list_transactions(
+    state: &AppState,
+    connection: &NwcConnection,
+    params: ListTransactionsParams,
+) -> Result<serde_json::Value> {
+    // nothing is ever sent from a user's address
+    if params.transaction_type.as_deref() == Some("outgoing") {
+        return Ok(serde_json::json!({ "transactions": [] }));
+    }
+
+    let invoices = state
+        .invoices
+        .list_for_user(
+            connection.user_id,
+            params
+                .from
+                .and_then(|secs| DateTime::from_timestamp(secs, 0)),
+            params
+                .until
+                .and_then(|secs| DateTime::from_timestamp(secs, 0)),
+            params.unpaid.unwrap_or(false),
+            params
+                .limit
+                .unwrap_or(DEFAULT_TRANSACTIONS_LIMIT)
+                .clamp(1, MAX_TRANSACTIONS_LIMIT),
+            params.offset.unwrap_or(0).max(0),
+        )
+        .await?;
+    let transactions: Vec<Transaction> = invoices.iter().map(Transaction::from).collect();
+    Ok(serde_json::json!({ "transactions": transactions }))
+}
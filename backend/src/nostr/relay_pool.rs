@@ -0,0 +1,233 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use nostr_sdk::{Client, EventId, Filter, Kind, Output, PublicKey};
+use serde::Serialize;
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// How long a user's NIP-65 relay list is reused before it is fetched again.
+const RELAY_LIST_TTL: Duration = Duration::from_secs(60 * 60);
+const RELAY_LIST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Cached relay lists and per-relay stats are keyed by what users choose, so
+/// the least recently used entries are dropped past these sizes.
+const MAX_RELAY_LISTS: usize = 10_000;
+const MAX_RELAY_STATS: usize = 1_000;
+
+/// Delivery results for one relay since startup.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RelayDeliveryStats {
+    pub delivered: u64,
+    pub failed: u64,
+    pub last_delivered_at: Option<u64>,
+    pub last_attempt_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+#[derive(Default)]
+struct PoolState {
+    /// Connected non-server relays by when they were last used.
+    user_relays: HashMap<String, Instant>,
+    relay_lists: HashMap<PublicKey, (Instant, Vec<String>)>,
+    stats: BTreeMap<String, RelayDeliveryStats>,
+}
+
+/// Relays to deliver to on top of the configured server relays. User relays
+/// are connected on first use and the least recently used ones are dropped
+/// once more than `max_user_relays` are open.
+#[derive(Clone)]
+pub struct RelayPool {
+    client: Client,
+    server_relays: Vec<String>,
+    max_user_relays: usize,
+    state: Arc<Mutex<PoolState>>,
+}
+
+impl RelayPool {
+    pub fn new(client: Client, server_relays: Vec<String>, max_user_relays: usize) -> Self {
+        Self {
+            client,
+            server_relays,
+            max_user_relays,
+            state: Default::default(),
+        }
+    }
+
+    pub fn server_relays(&self) -> &[String] {
+        &self.server_relays
+    }
+
+    /// The union of the server relays, the user's stored relays and the read
+    /// relays of their NIP-65 list, connected and ready to send to.
+    pub async fn relays_for(&self, user: &PublicKey, user_relays: &[String]) -> Vec<String> {
+        let mut wanted: Vec<String> = user_relays.to_vec();
+        wanted.extend(self.relay_list(user).await);
+
+        let mut relays = self.server_relays.clone();
+        for relay in wanted {
+            if relays.contains(&relay) {
+                continue;
+            }
+            if self.ensure_connected(&relay).await {
+                relays.push(relay);
+            }
+        }
+        relays
+    }
+
+    /// Connects to `relay` unless it is already open. The pool is only
+    /// locked around bookkeeping, so a slow relay holds up nobody else.
+    async fn ensure_connected(&self, relay: &str) -> bool {
+        if self.max_user_relays == 0 {
+            return false;
+        }
+        if let Some(last_used) = self.state.lock().await.user_relays.get_mut(relay) {
+            *last_used = Instant::now();
+            return true;
+        }
+
+        if let Err(e) = self.client.add_relay(relay).await {
+            warn!("Skipping invalid user relay {}: {}", relay, e);
+            return false;
+        }
+        if let Err(e) = self.client.connect_relay(relay).await {
+            warn!("Failed to connect to user relay {}: {}", relay, e);
+            let _ = self.client.remove_relay(relay).await;
+            return false;
+        }
+
+        let evicted = {
+            let mut state = self.state.lock().await;
+            state.user_relays.insert(relay.to_string(), Instant::now());
+            let mut evicted = Vec::new();
+            while state.user_relays.len() > self.max_user_relays {
+                let Some(oldest) = state
+                    .user_relays
+                    .iter()
+                    .min_by_key(|(_, last_used)| **last_used)
+                    .map(|(relay, _)| relay.clone())
+                else {
+                    break;
+                };
+                state.user_relays.remove(&oldest);
+                evicted.push(oldest);
+            }
+            evicted
+        };
+        for oldest in &evicted {
+            info!("Disconnecting idle user relay {}", oldest);
+            if let Err(e) = self.client.remove_relay(oldest).await {
+                warn!("Failed to remove relay {}: {}", oldest, e);
+            }
+        }
+        !evicted.iter().any(|evicted| evicted == relay)
+    }
+
+    /// Read relays from the user's NIP-65 (kind 10002) list, looked up on the
+    /// server relays and cached. Lookup failures yield no extra relays.
+    async fn relay_list(&self, user: &PublicKey) -> Vec<String> {
+        if let Some((fetched_at, relays)) = self.state.lock().await.relay_lists.get(user) {
+            if fetched_at.elapsed() < RELAY_LIST_TTL {
+                return relays.clone();
+            }
+        }
+
+        let filter = Filter::new().author(*user).kind(Kind::RelayList).limit(1);
+        let relays = match self
+            .client
+            .get_events_from(
+                self.server_relays.clone(),
+                vec![filter],
+                Some(RELAY_LIST_TIMEOUT),
+            )
+            .await
+        {
+            Ok(events) => events
+                .into_iter()
+                .max_by_key(|event| event.created_at)
+                .map(|event| {
+                    event
+                        .tags
+                        .iter()
+                        .map(|tag| tag.as_slice())
+                        .filter(|tag| tag.first().map(String::as_str) == Some("r"))
+                        // unmarked relays are both read and write
+                        .filter(|tag| tag.get(2).map_or(true, |marker| marker == "read"))
+                        .filter_map(|tag| tag.get(1).cloned())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            Err(e) => {
+                warn!("Failed to fetch relay list for {}: {}", user, e);
+                Vec::new()
+            }
+        };
+
+        let mut state = self.state.lock().await;
+        if state.relay_lists.len() >= MAX_RELAY_LISTS && !state.relay_lists.contains_key(user) {
+            state
+                .relay_lists
+                .retain(|_, (fetched_at, _)| fetched_at.elapsed() < RELAY_LIST_TTL);
+            if state.relay_lists.len() >= MAX_RELAY_LISTS {
+                let oldest = state
+                    .relay_lists
+                    .iter()
+                    .min_by_key(|(_, (fetched_at, _))| *fetched_at)
+                    .map(|(user, _)| *user);
+                if let Some(oldest) = oldest {
+                    state.relay_lists.remove(&oldest);
+                }
+            }
+        }
+        state
+            .relay_lists
+            .insert(*user, (Instant::now(), relays.clone()));
+        relays
+    }
+
+    pub async fn record(&self, output: &Output<EventId>) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let mut state = self.state.lock().await;
+        for relay in &output.success {
+            let stats = state.stats.entry(relay.to_string()).or_default();
+            stats.delivered += 1;
+            stats.last_delivered_at = Some(now);
+            stats.last_attempt_at = now;
+        }
+        for (relay, error) in &output.failed {
+            let stats = state.stats.entry(relay.to_string()).or_default();
+            stats.failed += 1;
+            stats.last_attempt_at = now;
+            stats.last_error = error.clone();
+        }
+
+        while state.stats.len() > MAX_RELAY_STATS {
+            let oldest = state
+                .stats
+                .iter()
+                .filter(|(relay, _)| !self.is_server_relay(relay))
+                .min_by_key(|(_, stats)| stats.last_attempt_at)
+                .map(|(relay, _)| relay.clone());
+            let Some(oldest) = oldest else {
+                break;
+            };
+            state.stats.remove(&oldest);
+        }
+    }
+
+    fn is_server_relay(&self, relay: &str) -> bool {
+        let relay = relay.trim_end_matches('/');
+        self.server_relays
+            .iter()
+            .any(|server| server.trim_end_matches('/') == relay)
+    }
+
+    pub async fn stats(&self) -> BTreeMap<String, RelayDeliveryStats> {
+        self.state.lock().await.stats.clone()
+    }
+}
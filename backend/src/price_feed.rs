@@ -0,0 +1,114 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+#[cfg(test)]
+mod tests;
+
+const MSATS_PER_BTC: f64 = 100_000_000_000.0;
+
+/// A fiat currency together with its current exchange rate.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct CurrencyRate {
+    pub code: String,
+    pub name: String,
+    pub symbol: String,
+    /// Digits after the decimal point of the currency's display unit, e.g. 2 for cents.
+    pub decimals: u32,
+    /// Millisatoshis per smallest unit of the currency.
+    pub multiplier: f64,
+}
+
+/// Fiat amount converted at a rate fixed when the invoice is created.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FiatQuote {
+    pub currency: String,
+    /// Amount in the currency's smallest unit.
+    pub amount: i64,
+    pub multiplier: f64,
+    pub amount_msats: u64,
+}
+
+#[async_trait]
+pub trait PriceFeed: Send + Sync {
+    async fn currencies(&self) -> Result<Vec<CurrencyRate>>;
+
+    async fn quote(&self, currency: &str, amount: u64) -> Result<FiatQuote> {
+        let rate = self
+            .currencies()
+            .await?
+            .into_iter()
+            .find(|rate| rate.code.eq_ignore_ascii_case(currency))
+            .ok_or_else(|| anyhow!("Unsupported currency: {currency}"))?;
+        let amount_msats = (amount as f64 * rate.multiplier).round() as u64;
+        Ok(FiatQuote {
+            currency: rate.code,
+            amount: i64::try_from(amount).context("Amount too large")?,
+            multiplier: rate.multiplier,
+            amount_msats,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct StaticCurrency {
+    name: String,
+    symbol: String,
+    decimals: u32,
+    /// Price of one whole bitcoin in the currency's display unit.
+    btc_price: f64,
+}
+
+/// Fixed rates read from a TOML file keyed by currency code, for tests and
+/// deployments that don't want an external price source:
+///
+/// ```toml
+/// [USD]
+/// name = "US Dollar"
+/// symbol = "$"
+/// decimals = 2
+/// btc_price = 60000.0
+/// ```
+pub struct StaticPriceFeed(Vec<CurrencyRate>);
+
+impl StaticPriceFeed {
+    pub fn new(rates: Vec<CurrencyRate>) -> Self {
+        Self(rates)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read price feed {}", path.display()))?;
+        let currencies: BTreeMap<String, StaticCurrency> = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse price feed {}", path.display()))?;
+
+        let rates = currencies
+            .into_iter()
+            .map(|(code, currency)| {
+                if currency.btc_price <= 0.0 {
+                    return Err(anyhow!("btc_price for {code} must be positive"));
+                }
+                let units_per_btc = currency.btc_price * 10f64.powi(currency.decimals as i32);
+                Ok(CurrencyRate {
+                    code,
+                    name: currency.name,
+                    symbol: currency.symbol,
+                    decimals: currency.decimals,
+                    multiplier: MSATS_PER_BTC / units_per_btc,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self(rates))
+    }
+}
+
+#[async_trait]
+impl PriceFeed for StaticPriceFeed {
+    async fn currencies(&self) -> Result<Vec<CurrencyRate>> {
+        Ok(self.0.clone())
+    }
+}
@@ -0,0 +1,79 @@
+//! Static rates read from TOML and the conversions quoted from them.
+
+use std::path::PathBuf;
+
+use super::*;
+
+fn example_feed() -> Result<StaticPriceFeed> {
+    StaticPriceFeed::from_file(
+        &PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("prices.example.toml"),
+    )
+}
+
+/// A price feed file with `contents`, removed when dropped.
+struct TempFeed(PathBuf);
+
+impl TempFeed {
+    fn new(contents: &str) -> Result<Self> {
+        let path = std::env::temp_dir().join(format!(
+            "replex-prices-{}.toml",
+            hex::encode(rand::random::<[u8; 8]>())
+        ));
+        std::fs::write(&path, contents)?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for TempFeed {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+#[tokio::test]
+async fn reads_rates_per_smallest_unit() -> Result<()> {
+    let rates = example_feed()?.currencies().await?;
+    let codes: Vec<&str> = rates.iter().map(|rate| rate.code.as_str()).collect();
+    assert_eq!(codes, ["EUR", "USD"]);
+
+    // 60000 USD per BTC is 6_000_000 cents, each worth 1e11 / 6e6 msats
+    let usd = &rates[1];
+    assert_eq!(usd.symbol, "$");
+    assert_eq!(usd.decimals, 2);
+    assert!((usd.multiplier - 100_000_000_000.0 / 6_000_000.0).abs() < 1e-6);
+    Ok(())
+}
+
+#[tokio::test]
+async fn quotes_convert_to_msats() -> Result<()> {
+    let feed = example_feed()?;
+
+    // 5 USD at 60000 USD per BTC is 8333.33 sats
+    let quote = feed.quote("usd", 500).await?;
+    assert_eq!(quote.currency, "USD");
+    assert_eq!(quote.amount, 500);
+    assert_eq!(quote.amount_msats, 8_333_333);
+
+    assert_eq!(feed.quote("EUR", 0).await?.amount_msats, 0);
+    assert!(feed.quote("JPY", 100).await.is_err());
+    assert!(feed.quote("USD", u64::MAX).await.is_err());
+    Ok(())
+}
+
+#[test]
+fn rejects_invalid_files() -> Result<()> {
+    let missing = std::env::temp_dir().join("replex-prices-missing.toml");
+    assert!(StaticPriceFeed::from_file(&missing).is_err());
+
+    let zero = TempFeed::new(
+        "[USD]\nname = \"US Dollar\"\nsymbol = \"$\"\ndecimals = 2\nbtc_price = 0.0\n",
+    )?;
+    let err = StaticPriceFeed::from_file(&zero.0)
+        .err()
+        .context("zero price accepted")?;
+    assert!(err.to_string().contains("btc_price for USD"), "{err:#}");
+
+    let incomplete = TempFeed::new("[USD]\nname = \"US Dollar\"\n")?;
+    assert!(StaticPriceFeed::from_file(&incomplete.0).is_err());
+    Ok(())
+}
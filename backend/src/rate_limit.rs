@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use axum::extract::{ConnectInfo, Path, Request, State};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use tracing::{error, info, warn};
+
+use crate::model::repo::RateLimitRepo;
+use crate::router::handlers::lnurlp::LnurlErrorResponse;
+use crate::state::AppState;
+
+#[cfg(test)]
+mod tests;
+
+/// In-memory buckets are pruned once the map grows past this many keys, and
+/// the least recently used are dropped if it is still over.
+const MAX_MEMORY_BUCKETS: usize = 10_000;
+const PRUNE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Token bucket parameters: up to `burst` requests at once, refilled at
+/// `per_minute` requests per minute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit {
+    pub burst: u32,
+    pub per_minute: u32,
+}
+
+impl Limit {
+    fn refill_per_sec(&self) -> f64 {
+        self.per_minute as f64 / 60.0
+    }
+
+    /// Seconds until an emptied bucket is full again, after which it is
+    /// equivalent to a new one.
+    fn full_after_secs(&self) -> f64 {
+        self.burst as f64 / self.refill_per_sec()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitBackend {
+    Memory,
+    Postgres,
+}
+
+impl FromStr for RateLimitBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(Self::Memory),
+            "postgres" => Ok(Self::Postgres),
+            _ => Err(format!("expected \"memory\" or \"postgres\", got {s:?}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitSettings {
+    pub per_ip: Limit,
+    pub per_username: Limit,
+    pub backend: RateLimitBackend,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+#[derive(Clone)]
+enum Store {
+    Memory(Arc<Mutex<HashMap<String, Bucket>>>),
+    /// Shared across instances so limits hold behind a load balancer.
+    Shared(Arc<dyn RateLimitRepo>),
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    store: Store,
+    settings: RateLimitSettings,
+}
+
+impl RateLimiter {
+    /// `shared` is `None` without Postgres, which config validation only
+    /// allows with the memory backend.
+    pub fn new(settings: RateLimitSettings, shared: Option<Arc<dyn RateLimitRepo>>) -> Self {
+        let store = match (settings.backend, shared) {
+            (RateLimitBackend::Postgres, Some(shared)) => Store::Shared(shared),
+            _ => Store::Memory(Default::default()),
+        };
+        Self { store, settings }
+    }
+
+    pub async fn check_ip(&self, ip: &str) -> Result<bool> {
+        self.check(&format!("ip:{ip}"), self.settings.per_ip).await
+    }
+
+    /// Keyed by client too, so nobody can use up a user's allowance for
+    /// everyone else.
+    pub async fn check_username(&self, username: &str, ip: &str) -> Result<bool> {
+        self.check(
+            &format!("user:{username}:ip:{ip}"),
+            self.settings.per_username,
+        )
+        .await
+    }
+
+    /// NWC requests arrive over relays rather than HTTP, so a connection's
+    /// invoices are limited by its key, at the per-username rate.
+    pub async fn check_nwc(&self, connection_pubkey: &str) -> Result<bool> {
+        self.check(
+            &format!("nwc:{connection_pubkey}"),
+            self.settings.per_username,
+        )
+        .await
+    }
+
+    fn full_after_secs(&self) -> f64 {
+        self.settings
+            .per_ip
+            .full_after_secs()
+            .max(self.settings.per_username.full_after_secs())
+    }
+
+    /// Drops buckets that have refilled completely.
+    pub async fn prune(&self) -> Result<u64> {
+        let full_after = self.full_after_secs();
+        match &self.store {
+            Store::Memory(buckets) => {
+                let mut buckets = buckets.lock().await;
+                let before = buckets.len();
+                prune_memory(&mut buckets, full_after);
+                Ok((before - buckets.len()) as u64)
+            }
+            Store::Shared(repo) => repo.prune(full_after).await,
+        }
+    }
+
+    /// Takes one token from the bucket for `key`, returning whether one was available.
+    async fn check(&self, key: &str, limit: Limit) -> Result<bool> {
+        match &self.store {
+            Store::Memory(buckets) => {
+                let mut buckets = buckets.lock().await;
+                let now = Instant::now();
+                if buckets.len() >= MAX_MEMORY_BUCKETS && !buckets.contains_key(key) {
+                    prune_memory(&mut buckets, self.full_after_secs());
+                }
+
+                let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+                    tokens: limit.burst as f64,
+                    updated_at: now,
+                });
+                let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+                bucket.tokens =
+                    (bucket.tokens + elapsed * limit.refill_per_sec()).min(limit.burst as f64);
+                bucket.updated_at = now;
+
+                if bucket.tokens >= 1.0 {
+                    bucket.tokens -= 1.0;
+                    Ok(true)
+                } else {
+                    Ok(false)
+                }
+            }
+            Store::Shared(repo) => {
+                repo.take_token(key, limit.burst as f64, limit.refill_per_sec())
+                    .await
+            }
+        }
+    }
+}
+
+fn prune_memory(buckets: &mut HashMap<String, Bucket>, full_after_secs: f64) {
+    let now = Instant::now();
+    buckets.retain(|_, b| now.duration_since(b.updated_at).as_secs_f64() < full_after_secs);
+    if buckets.len() >= MAX_MEMORY_BUCKETS {
+        // Still full of active keys: forget the least recently used half
+        // rather than grow without bound.
+        let mut updated: Vec<Instant> = buckets.values().map(|b| b.updated_at).collect();
+        let (_, cutoff, _) = updated.select_nth_unstable(buckets.len() / 2);
+        let cutoff = *cutoff;
+        buckets.retain(|_, b| b.updated_at > cutoff);
+    }
+}
+
+pub fn spawn_pruner(state: AppState) {
+    let tasks = state.tasks.clone();
+    tasks.spawn(async move {
+        let mut interval = tokio::time::interval(PRUNE_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = state.shutdown.cancelled() => break,
+                _ = interval.tick() => match state.rate_limiter.prune().await {
+                    Ok(0) => {}
+                    Ok(pruned) => info!("Pruned {} rate limit buckets", pruned),
+                    Err(e) => error!("Failed to prune rate limit buckets: {e}"),
+                },
+            }
+        }
+    });
+}
+
+/// The client address, or behind a trusted proxy the last `X-Forwarded-For`
+/// entry: the one the proxy appended. Earlier entries come from the client
+/// and can be anything.
+fn client_ip(request: &Request, trust_forwarded_headers: bool) -> String {
+    if trust_forwarded_headers {
+        let forwarded = request
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.rsplit(',').next())
+            .map(str::trim)
+            .filter(|h| !h.is_empty());
+        if let Some(ip) = forwarded {
+            return ip.to_string();
+        }
+    }
+
+    request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn too_many_requests(reason: &str) -> Response {
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        Json(LnurlErrorResponse::new(reason)),
+    )
+        .into_response()
+}
+
+/// Middleware for the LNURL routes, keyed by client IP. Storage errors fail
+/// open so an unavailable limiter doesn't take payments down with it.
+pub async fn limit_lnurl(State(state): State<AppState>, request: Request, next: Next) -> Response {
+    let ip = client_ip(&request, state.config.trust_forwarded_headers);
+    match state.rate_limiter.check_ip(&ip).await {
+        Ok(true) => {}
+        Ok(false) => {
+            warn!("Rate limited ip {}", ip);
+            return too_many_requests("Too many requests, try again later");
+        }
+        Err(e) => error!("Rate limiter error: {e}"),
+    }
+
+    next.run(request).await
+}
+
+/// Middleware for the pay callback, which creates invoices, keyed by the
+/// `username` path parameter and client IP.
+pub async fn limit_lnurl_callback(
+    State(state): State<AppState>,
+    Path(params): Path<HashMap<String, String>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(username) = params.get("username") {
+        let ip = client_ip(&request, state.config.trust_forwarded_headers);
+        match state.rate_limiter.check_username(username, &ip).await {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("Rate limited username {} for ip {}", username, ip);
+                return too_many_requests("Too many requests for this address, try again later");
+            }
+            Err(e) => error!("Rate limiter error: {e}"),
+        }
+    }
+
+    next.run(request).await
+}
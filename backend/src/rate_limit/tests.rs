@@ -0,0 +1,138 @@
+//! Token buckets in memory, pruning and picking the client address.
+
+use axum::body::Body;
+
+use super::*;
+
+fn limiter(burst: u32, per_minute: u32) -> RateLimiter {
+    let limit = Limit { burst, per_minute };
+    let settings = RateLimitSettings {
+        per_ip: limit,
+        per_username: limit,
+        backend: RateLimitBackend::Memory,
+    };
+    RateLimiter::new(settings, None)
+}
+
+/// Moves the last update of `key`'s bucket back by `by`, as if that much
+/// time had passed.
+async fn age(limiter: &RateLimiter, key: &str, by: Duration) {
+    let Store::Memory(buckets) = &limiter.store else {
+        panic!("expected the memory store");
+    };
+    let mut buckets = buckets.lock().await;
+    let bucket = buckets.get_mut(key).expect("bucket for key");
+    bucket.updated_at = bucket.updated_at.checked_sub(by).expect("instant in range");
+}
+
+async fn allowed(limiter: &RateLimiter, ip: &str, times: usize) -> Result<usize> {
+    let mut allowed = 0;
+    for _ in 0..times {
+        if limiter.check_ip(ip).await? {
+            allowed += 1;
+        }
+    }
+    Ok(allowed)
+}
+
+#[test]
+fn limits_refill_per_second() {
+    let limit = Limit {
+        burst: 20,
+        per_minute: 30,
+    };
+    assert_eq!(limit.refill_per_sec(), 0.5);
+    assert_eq!(limit.full_after_secs(), 40.0);
+}
+
+#[tokio::test]
+async fn buckets_allow_a_burst_then_refill() -> Result<()> {
+    let limiter = limiter(3, 60);
+    assert_eq!(allowed(&limiter, "1.2.3.4", 5).await?, 3);
+    // other clients have their own bucket
+    assert!(limiter.check_ip("5.6.7.8").await?);
+
+    // one token a second
+    age(&limiter, "ip:1.2.3.4", Duration::from_millis(1_500)).await;
+    assert_eq!(allowed(&limiter, "1.2.3.4", 3).await?, 1);
+
+    // but never more than the burst
+    age(&limiter, "ip:1.2.3.4", Duration::from_secs(600)).await;
+    assert_eq!(allowed(&limiter, "1.2.3.4", 5).await?, 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn usernames_are_limited_per_client() -> Result<()> {
+    let limiter = limiter(1, 1);
+    assert!(limiter.check_username("alice", "1.2.3.4").await?);
+    assert!(!limiter.check_username("alice", "1.2.3.4").await?);
+    assert!(limiter.check_username("alice", "5.6.7.8").await?);
+    assert!(limiter.check_username("bob", "1.2.3.4").await?);
+    // separate from the per-ip bucket
+    assert!(limiter.check_ip("1.2.3.4").await?);
+    Ok(())
+}
+
+#[tokio::test]
+async fn prune_drops_refilled_buckets() -> Result<()> {
+    let limiter = limiter(2, 60);
+    limiter.check_ip("1.2.3.4").await?;
+    limiter.check_ip("5.6.7.8").await?;
+    assert_eq!(limiter.prune().await?, 0);
+
+    age(&limiter, "ip:1.2.3.4", Duration::from_secs(3)).await;
+    assert_eq!(limiter.prune().await?, 1);
+    assert_eq!(allowed(&limiter, "5.6.7.8", 2).await?, 1);
+    Ok(())
+}
+
+#[test]
+fn prune_memory_caps_active_buckets() {
+    let start = Instant::now();
+    let mut buckets: HashMap<String, Bucket> = (0..MAX_MEMORY_BUCKETS)
+        .map(|i| {
+            let bucket = Bucket {
+                tokens: 0.0,
+                updated_at: start + Duration::from_millis(i as u64),
+            };
+            (i.to_string(), bucket)
+        })
+        .collect();
+
+    // none have refilled, so the least recently used half goes
+    prune_memory(&mut buckets, 3_600.0);
+    assert!(buckets.len() <= MAX_MEMORY_BUCKETS / 2);
+    assert!(!buckets.contains_key("0"));
+    assert!(buckets.contains_key(&(MAX_MEMORY_BUCKETS - 1).to_string()));
+}
+
+fn request(forwarded_for: Option<&str>, peer: Option<[u8; 4]>) -> Request {
+    let mut request = Request::builder();
+    if let Some(forwarded_for) = forwarded_for {
+        request = request.header("X-Forwarded-For", forwarded_for);
+    }
+    if let Some(peer) = peer {
+        request = request.extension(ConnectInfo(SocketAddr::from((peer, 40_000))));
+    }
+    request.body(Body::empty()).expect("valid request")
+}
+
+#[test]
+fn client_ip_trusts_only_the_last_forwarded_hop() {
+    let proxied = request(Some("6.6.6.6, 1.2.3.4"), Some([10, 0, 0, 1]));
+    assert_eq!(client_ip(&proxied, true), "1.2.3.4");
+    assert_eq!(client_ip(&proxied, false), "10.0.0.1");
+
+    let single = request(Some(" 1.2.3.4 "), Some([10, 0, 0, 1]));
+    assert_eq!(client_ip(&single, true), "1.2.3.4");
+
+    let trailing_comma = request(Some("1.2.3.4, "), Some([10, 0, 0, 1]));
+    assert_eq!(client_ip(&trailing_comma, true), "10.0.0.1");
+
+    assert_eq!(
+        client_ip(&request(None, Some([10, 0, 0, 1])), true),
+        "10.0.0.1"
+    );
+    assert_eq!(client_ip(&request(None, None), true), "unknown");
+}
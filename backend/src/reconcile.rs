@@ -0,0 +1,550 @@
+//! Compares the `invoices` table with the federation clients' operation logs
+//! and repairs what diverged, e.g. when the process died between a claim and
+//! the state update, or a subscription task ended early.
+
+use std::collections::{BTreeSet, HashMap};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::{bail, Result};
+use multimint::fedimint_client::oplog::OperationLogEntry;
+use multimint::fedimint_client::ClientHandleArc;
+use multimint::fedimint_ln_client::{
+    InternalPayState, LightningOperationMeta, LightningOperationMetaVariant, LnPayState,
+    LnReceiveState,
+};
+use multimint::fedimint_ln_common::lightning_invoice::Bolt11Invoice;
+use serde::Serialize;
+use tokio::sync::{Mutex, RwLock};
+use tracing::{error, info, warn};
+
+use crate::model::invoice_events::InvoiceStage;
+use crate::model::invoices::{Invoice, InvoiceState};
+use crate::model::repo::WithdrawalRepo;
+use crate::model::withdrawals::{Withdrawal, WithdrawalState};
+use crate::state::{internal_pay_outcome, ln_pay_outcome, AppState};
+
+const OPERATION_PAGE_SIZE: usize = 100;
+const INVOICE_PAGE_SIZE: i64 = 100;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct StateFix {
+    pub op_id: String,
+    pub federation_id: String,
+    pub from: InvoiceState,
+    pub to: InvoiceState,
+}
+
+/// A lightning receive in a client's log with no invoice row.
+#[derive(Debug, Clone, Serialize)]
+pub struct OrphanOperation {
+    pub op_id: String,
+    pub federation_id: String,
+    pub bolt11: String,
+    pub state: Option<InvoiceState>,
+}
+
+/// An invoice row with no operation in its federation's log, e.g. after a
+/// recovery. Expired pending ones are cancelled; the rest can't be monitored.
+#[derive(Debug, Clone, Serialize)]
+pub struct OrphanInvoice {
+    pub op_id: String,
+    pub federation_id: String,
+    pub state: InvoiceState,
+    pub cancelled: bool,
+}
+
+/// A withdrawal whose uncertain payment was found final in the operation log.
+#[derive(Debug, Clone, Serialize)]
+pub struct WithdrawalFix {
+    pub id: String,
+    pub federation_id: String,
+    pub to: WithdrawalState,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ReconcileReport {
+    pub started_at: u64,
+    /// Whether settled and cancelled invoices and whole operation logs were
+    /// checked too, rather than only pending invoices.
+    pub full: bool,
+    pub finished_at: u64,
+    /// Invoices that agreed with their operation, or that a subscription
+    /// is watching.
+    pub matched: usize,
+    /// Invoices whose state was corrected from their operation's outcome.
+    pub fixed: Vec<StateFix>,
+    /// Pending invoices that had lost their subscription and were resubscribed.
+    pub resubscribed: Vec<String>,
+    pub fixed_withdrawals: Vec<WithdrawalFix>,
+    /// Withdrawals in `PaymentUnknown` whose payment is in flight again.
+    pub resubscribed_withdrawals: Vec<String>,
+    /// Withdrawals in `PaymentUnknown` with no final outcome in the log,
+    /// left for manual review.
+    pub unresolved_withdrawals: Vec<String>,
+    /// Only looked for in full runs.
+    pub orphan_operations: Vec<OrphanOperation>,
+    pub orphan_invoices: Vec<OrphanInvoice>,
+    /// Federations with invoices but no registered client.
+    pub missing_federations: Vec<String>,
+    pub errors: Vec<String>,
+}
+
+/// Runs reconciliations one at a time and keeps the latest report.
+#[derive(Clone, Default)]
+pub struct Reconciler {
+    last: Arc<RwLock<Option<ReconcileReport>>>,
+    running: Arc<Mutex<()>>,
+}
+
+impl Reconciler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub async fn last_report(&self) -> Option<ReconcileReport> {
+        self.last.read().await.clone()
+    }
+
+    /// `full` also checks finished invoices and scans every operation log
+    /// for orphans, which periodic runs skip as it grows with history.
+    pub async fn run(&self, state: &AppState, full: bool) -> Result<ReconcileReport> {
+        let _running = self.running.lock().await;
+        let report = reconcile(state, full).await?;
+        *self.last.write().await = Some(report.clone());
+        Ok(report)
+    }
+}
+
+pub fn spawn_reconciler(state: AppState) {
+    let period = state.config.reconcile_interval;
+    if period.is_zero() {
+        return;
+    }
+    let tasks = state.tasks.clone();
+    tasks.spawn(async move {
+        // startup already resubscribes pending invoices, so skip the first tick
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        loop {
+            tokio::select! {
+                _ = state.shutdown.cancelled() => break,
+                _ = interval.tick() => {
+                    if let Err(e) = state.reconciler.run(&state, false).await {
+                        error!("Reconciliation failed: {e}");
+                    }
+                }
+            }
+        }
+    });
+    info!("Started reconciler, every {}s", period.as_secs());
+}
+
+async fn reconcile(state: &AppState, full: bool) -> Result<ReconcileReport> {
+    let mut report = ReconcileReport {
+        started_at: now(),
+        full,
+        ..Default::default()
+    };
+
+    let clients: HashMap<String, ClientHandleArc> = state
+        .mm
+        .clients
+        .lock()
+        .await
+        .iter()
+        .map(|(federation_id, client)| (federation_id.to_string(), client.clone()))
+        .collect();
+
+    if let Ok(withdrawals) = state.withdrawals() {
+        if let Err(e) = reconcile_withdrawals(state, &*withdrawals, &mut report).await {
+            error!("Failed to reconcile withdrawals: {e}");
+            report.errors.push(format!("withdrawals: {e}"));
+        }
+    }
+
+    let invoice_states: &[InvoiceState] = if full {
+        &[
+            InvoiceState::Pending,
+            InvoiceState::Settled,
+            InvoiceState::Cancelled,
+        ]
+    } else {
+        &[InvoiceState::Pending]
+    };
+    let mut missing_federations = BTreeSet::new();
+    for &invoice_state in invoice_states {
+        reconcile_invoices(
+            state,
+            &clients,
+            invoice_state,
+            &mut missing_federations,
+            &mut report,
+        )
+        .await?;
+    }
+    for federation_id in &missing_federations {
+        warn!("No client for federation {}", federation_id);
+    }
+    report.missing_federations = missing_federations.into_iter().collect();
+
+    if full {
+        for (federation_id, client) in &clients {
+            if let Err(e) = find_orphan_operations(state, federation_id, client, &mut report).await
+            {
+                error!("Failed to scan federation {}: {e}", federation_id);
+                report.errors.push(format!("{federation_id}: {e}"));
+            }
+        }
+    }
+
+    report.finished_at = now();
+    info!(
+        "Reconciled invoices: {} matched, {} fixed, {} resubscribed, {} orphan operations, {} orphan invoices",
+        report.matched,
+        report.fixed.len(),
+        report.resubscribed.len(),
+        report.orphan_operations.len(),
+        report.orphan_invoices.len()
+    );
+    info!(
+        "Reconciled withdrawals: {} fixed, {} resubscribed, {} unresolved",
+        report.fixed_withdrawals.len(),
+        report.resubscribed_withdrawals.len(),
+        report.unresolved_withdrawals.len()
+    );
+    Ok(report)
+}
+
+/// Checks the invoices in `invoice_state` page by page against their
+/// operations, each looked up by id.
+async fn reconcile_invoices(
+    state: &AppState,
+    clients: &HashMap<String, ClientHandleArc>,
+    invoice_state: InvoiceState,
+    missing_federations: &mut BTreeSet<String>,
+    report: &mut ReconcileReport,
+) -> Result<()> {
+    let mut after_id = 0;
+    loop {
+        let page = state
+            .invoices
+            .get_page_by_state(invoice_state, after_id, INVOICE_PAGE_SIZE)
+            .await?;
+        let Some(last) = page.last() else {
+            return Ok(());
+        };
+        after_id = last.id;
+        let page_len = page.len();
+
+        for invoice in page {
+            let Some(client) = clients.get(&invoice.federation_id) else {
+                missing_federations.insert(invoice.federation_id.clone());
+                continue;
+            };
+            let op_id = invoice.op_id.clone();
+            if let Err(e) = reconcile_invoice(state, client, invoice, report).await {
+                error!("Failed to reconcile invoice {}: {e}", op_id);
+                report.errors.push(format!("invoice {op_id}: {e}"));
+            }
+        }
+        if (page_len as i64) < INVOICE_PAGE_SIZE {
+            return Ok(());
+        }
+    }
+}
+
+async fn reconcile_invoice(
+    state: &AppState,
+    client: &ClientHandleArc,
+    invoice: Invoice,
+    report: &mut ReconcileReport,
+) -> Result<()> {
+    // a live subscription records the outcome and notifies by itself
+    if state.is_monitored(&invoice.op_id) {
+        report.matched += 1;
+        return Ok(());
+    }
+
+    let entry = client
+        .operation_log()
+        .get_operation(invoice.op_id.parse()?)
+        .await;
+    let Some(entry) = entry else {
+        let expired = Bolt11Invoice::from_str(&invoice.bolt11)
+            .map(|bolt11| bolt11.is_expired())
+            .unwrap_or(true);
+        let cancelled = invoice.state == InvoiceState::Pending
+            && expired
+            && correct_state(
+                state,
+                &invoice,
+                InvoiceState::Cancelled,
+                "reconciler: expired with no operation to monitor",
+            )
+            .await?;
+        report.orphan_invoices.push(OrphanInvoice {
+            op_id: invoice.op_id,
+            federation_id: invoice.federation_id,
+            state: invoice.state,
+            cancelled,
+        });
+        return Ok(());
+    };
+    if entry.operation_module_kind() != "ln"
+        || !matches!(
+            entry.meta::<LightningOperationMeta>().variant,
+            LightningOperationMetaVariant::Receive { .. }
+        )
+    {
+        bail!("Operation {} is not a receive", invoice.op_id);
+    }
+
+    match receive_outcome(&entry) {
+        Some(outcome) if outcome != invoice.state => {
+            warn!(
+                "Invoice {} is {:?} but its operation is {:?}",
+                invoice.op_id, invoice.state, outcome
+            );
+            // only whoever actually moves the invoice notifies
+            if correct_state(
+                state,
+                &invoice,
+                outcome,
+                "reconciler: corrected from operation outcome",
+            )
+            .await?
+            {
+                if outcome == InvoiceState::Settled {
+                    state.notify_settled(&invoice).await;
+                }
+                report.fixed.push(StateFix {
+                    op_id: invoice.op_id,
+                    federation_id: invoice.federation_id,
+                    from: invoice.state,
+                    to: outcome,
+                });
+            }
+        }
+        None if invoice.state == InvoiceState::Pending => {
+            let op_id = invoice.op_id.clone();
+            state.subscribe_to_invoice(invoice).await?;
+            report.resubscribed.push(op_id);
+        }
+        _ => report.matched += 1,
+    }
+    Ok(())
+}
+
+/// Moves the invoice to `to` if it is still in the state it was read in.
+async fn correct_state(
+    state: &AppState,
+    invoice: &Invoice,
+    to: InvoiceState,
+    reason: &str,
+) -> Result<bool> {
+    let stage = match to {
+        InvoiceState::Settled => InvoiceStage::Claimed,
+        _ => InvoiceStage::Canceled,
+    };
+    state
+        .invoices
+        .transition_state(invoice.id, invoice.state, to, stage, Some(reason))
+        .await
+}
+
+/// The invoice state a receive operation finished in, if it has.
+fn receive_outcome(entry: &OperationLogEntry) -> Option<InvoiceState> {
+    match entry.outcome::<LnReceiveState>() {
+        Some(LnReceiveState::Claimed) => Some(InvoiceState::Settled),
+        Some(LnReceiveState::Canceled { .. }) => Some(InvoiceState::Cancelled),
+        _ => None,
+    }
+}
+
+enum PaymentResolution {
+    Final(WithdrawalState),
+    InFlight,
+    Unknown,
+}
+
+/// Settles withdrawals in `PaymentUnknown` from their payment's outcome.
+/// A withdrawal never goes back to a claimable state here: one whose
+/// payment can't be found stays unknown.
+async fn reconcile_withdrawals(
+    state: &AppState,
+    withdrawals: &dyn WithdrawalRepo,
+    report: &mut ReconcileReport,
+) -> Result<()> {
+    let unknown = withdrawals
+        .get_by_state(WithdrawalState::PaymentUnknown)
+        .await?;
+    for withdrawal in unknown {
+        let resolution = match state.get_client(&withdrawal.federation_id).await {
+            Ok(client) => resolve_withdrawal(state, withdrawals, &client, &withdrawal).await,
+            Err(_) => Ok(PaymentResolution::Unknown),
+        };
+        match resolution {
+            Ok(PaymentResolution::Final(to)) => report.fixed_withdrawals.push(WithdrawalFix {
+                id: withdrawal.id,
+                federation_id: withdrawal.federation_id,
+                to,
+            }),
+            Ok(PaymentResolution::InFlight) => report.resubscribed_withdrawals.push(withdrawal.id),
+            Ok(PaymentResolution::Unknown) => {
+                warn!("Withdrawal {} payment is still unknown", withdrawal.id);
+                report.unresolved_withdrawals.push(withdrawal.id)
+            }
+            Err(e) => {
+                error!("Failed to reconcile withdrawal {}: {e}", withdrawal.id);
+                report
+                    .errors
+                    .push(format!("withdrawal {}: {e}", withdrawal.id));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn resolve_withdrawal(
+    state: &AppState,
+    withdrawals: &dyn WithdrawalRepo,
+    client: &ClientHandleArc,
+    withdrawal: &Withdrawal,
+) -> Result<PaymentResolution> {
+    let operation = match &withdrawal.op_id {
+        Some(op_id) => client
+            .operation_log()
+            .get_operation(op_id.parse()?)
+            .await
+            .map(|entry| (op_id.clone(), entry)),
+        None => match &withdrawal.bolt11 {
+            Some(bolt11) => find_payment(client, bolt11).await,
+            None => None,
+        },
+    };
+    let Some((op_id, entry)) = operation else {
+        return Ok(PaymentResolution::Unknown);
+    };
+    let internal = match entry.meta::<LightningOperationMeta>().variant {
+        LightningOperationMetaVariant::Pay(pay) => pay.is_internal_payment,
+        _ => bail!("Operation {op_id} is not a payment"),
+    };
+    let outcome = if internal {
+        entry
+            .outcome::<InternalPayState>()
+            .as_ref()
+            .and_then(internal_pay_outcome)
+    } else {
+        entry
+            .outcome::<LnPayState>()
+            .as_ref()
+            .and_then(ln_pay_outcome)
+    };
+
+    match outcome {
+        Some(WithdrawalState::PaymentUnknown) => Ok(PaymentResolution::Unknown),
+        Some(to) => {
+            withdrawals.update_payment_state(&withdrawal.id, to).await?;
+            Ok(PaymentResolution::Final(to))
+        }
+        None => {
+            if withdrawal.op_id.is_none() {
+                withdrawals
+                    .set_operation(&withdrawal.id, &op_id, internal)
+                    .await?;
+            }
+            state
+                .subscribe_to_withdrawal(Withdrawal {
+                    op_id: Some(op_id),
+                    internal,
+                    ..withdrawal.clone()
+                })
+                .await?;
+            Ok(PaymentResolution::InFlight)
+        }
+    }
+}
+
+/// The payment of `bolt11` in the client's log, for a withdrawal whose
+/// operation wasn't recorded.
+async fn find_payment(
+    client: &ClientHandleArc,
+    bolt11: &str,
+) -> Option<(String, OperationLogEntry)> {
+    let log = client.operation_log();
+    let mut start_after = None;
+    loop {
+        let page = log.list_operations(OPERATION_PAGE_SIZE, start_after).await;
+        let Some((last, _)) = page.last() else {
+            return None;
+        };
+        start_after = Some(*last);
+        let page_len = page.len();
+
+        for (key, entry) in page {
+            if entry.operation_module_kind() != "ln" {
+                continue;
+            }
+            if let LightningOperationMetaVariant::Pay(pay) =
+                entry.meta::<LightningOperationMeta>().variant
+            {
+                if pay.invoice.to_string() == bolt11 {
+                    return Some((key.operation_id.fmt_full().to_string(), entry));
+                }
+            }
+        }
+        if page_len < OPERATION_PAGE_SIZE {
+            return None;
+        }
+    }
+}
+
+/// Lightning receives in the client's log with no invoice row. This reads
+/// the whole log, so only full runs do it.
+async fn find_orphan_operations(
+    state: &AppState,
+    federation_id: &str,
+    client: &ClientHandleArc,
+    report: &mut ReconcileReport,
+) -> Result<()> {
+    let log = client.operation_log();
+    let mut start_after = None;
+    loop {
+        let page = log.list_operations(OPERATION_PAGE_SIZE, start_after).await;
+        let Some((last, _)) = page.last() else {
+            return Ok(());
+        };
+        start_after = Some(*last);
+
+        for (key, entry) in &page {
+            if entry.operation_module_kind() != "ln" {
+                continue;
+            }
+            let LightningOperationMetaVariant::Receive { invoice, .. } =
+                entry.meta::<LightningOperationMeta>().variant
+            else {
+                continue;
+            };
+            let op_id = key.operation_id.fmt_full().to_string();
+            if state.invoices.get_by_op_id(&op_id).await?.is_none() {
+                report.orphan_operations.push(OrphanOperation {
+                    op_id,
+                    federation_id: federation_id.to_string(),
+                    bolt11: invoice.to_string(),
+                    state: receive_outcome(entry),
+                });
+            }
+        }
+        if page.len() < OPERATION_PAGE_SIZE {
+            return Ok(());
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
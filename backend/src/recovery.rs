@@ -0,0 +1,117 @@
+//! Rebuilds the fedimint client DB after `fm_db_path` is lost. Each client is
+//! recovered with the configured mnemonic through fedimint's module recovery,
+//! which restores its e-cash from the federation's latest backup and history
+//! so note nonces aren't reused. Operation history is not stored by the
+//! federation, so invoices whose operations are gone show up as orphans in
+//! the reconcile report.
+
+use std::io::ErrorKind;
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use fedimint_api_client::api::net::Connector;
+use fedimint_bip39::{Bip39RootSecretStrategy, Mnemonic};
+use fedimint_mint_client::MintClientInit;
+use fedimint_rocksdb::RocksDb;
+use fedimint_wallet_client::WalletClientInit;
+use multimint::fedimint_client::secret::{get_default_client_secret, RootSecretStrategy};
+use multimint::fedimint_client::Client;
+use multimint::fedimint_core::db::Database;
+use multimint::fedimint_core::invite_code::InviteCode;
+use multimint::fedimint_ln_client::LightningClientInit;
+use tracing::info;
+
+use crate::config::Config;
+use crate::reconcile::ReconcileReport;
+use crate::secret::SecretString;
+use crate::state::AppState;
+
+pub async fn recover(config: Config) -> Result<ReconcileReport> {
+    if config.dev {
+        bail!("Dev mode joins no federation, there is nothing to recover");
+    }
+    check_empty(&config.fm_db_path)?;
+    std::fs::create_dir_all(&config.fm_db_path)?;
+    info!(
+        "Recovering {} federations into {}",
+        config.federation_invite_codes.len(),
+        config.fm_db_path.display()
+    );
+    for invite_code in &config.federation_invite_codes {
+        recover_federation(&config.fm_db_path, invite_code, &config.mnemonic)
+            .await
+            .with_context(|| format!("Failed to recover federation {invite_code}"))?;
+    }
+
+    // the recovered clients are opened as they are, not joined again
+    let state = AppState::new(config).await?;
+    let report = state.reconciler.run(&state, true).await;
+    state.shutdown.cancel();
+    state.tasks.close();
+    state.tasks.wait().await;
+    state.close().await;
+    report
+}
+
+/// Recovers one client into the per-federation DB multimint opens, and only
+/// returns once every module has finished recovering.
+async fn recover_federation(
+    fm_db_path: &Path,
+    invite_code: &InviteCode,
+    mnemonic: &SecretString,
+) -> Result<()> {
+    let federation_id = invite_code.federation_id();
+    let db: Database = RocksDb::open(fm_db_path.join(format!("{federation_id}.db")))?.into();
+    let mut builder = Client::builder(db).await?;
+    builder.with_module(LightningClientInit::default());
+    builder.with_module(MintClientInit);
+    builder.with_module(WalletClientInit(None));
+    builder.with_primary_module(1);
+
+    let mnemonic = Mnemonic::parse_normalized(mnemonic.expose())?;
+    let root_secret = get_default_client_secret(
+        &Bip39RootSecretStrategy::<12>::to_root_secret(&mnemonic),
+        &federation_id,
+    );
+    let client_config = Connector::default()
+        .download_from_invite_code(invite_code)
+        .await?;
+    let backup = builder
+        .download_backup_from_federation(&root_secret, &client_config, invite_code.api_secret())
+        .await?;
+    info!(
+        "Recovering federation {} {}",
+        federation_id,
+        if backup.is_some() {
+            "from its latest backup"
+        } else {
+            "from its history, no backup was found"
+        }
+    );
+
+    let client = builder
+        .recover(root_secret, client_config, invite_code.api_secret(), backup)
+        .await?;
+    client.wait_for_all_recoveries().await?;
+    info!("Recovered federation {}", federation_id);
+    client.shutdown().await;
+    Ok(())
+}
+
+/// An existing client DB has its own secret and history, which recovering
+/// on top of it would not reproduce.
+fn check_empty(path: &Path) -> Result<()> {
+    match std::fs::read_dir(path) {
+        Ok(mut entries) => {
+            if entries.next().is_some() {
+                bail!(
+                    "{} is not empty, move it aside before recovering",
+                    path.display()
+                );
+            }
+            Ok(())
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
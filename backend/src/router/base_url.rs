@@ -0,0 +1,101 @@
+use std::convert::Infallible;
+
+use axum::async_trait;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use axum::http::HeaderMap;
+use url::Url;
+
+use crate::state::AppState;
+
+/// Builds every externally visible link. Resolves to the configured
+/// `public_base_url`, adjusted by `X-Forwarded-*` headers when the server is
+/// configured to trust them.
+#[derive(Debug, Clone)]
+pub struct BaseUrl(Url);
+
+impl BaseUrl {
+    pub fn new(url: Url) -> Self {
+        Self(url)
+    }
+
+    pub fn url(&self) -> &Url {
+        &self.0
+    }
+
+    /// Appends percent-encoded path segments to the base url.
+    pub fn join(&self, segments: &[&str]) -> Url {
+        let mut url = self.0.clone();
+        url.path_segments_mut()
+            .expect("public base url is validated as http(s)")
+            .pop_if_empty()
+            .extend(segments);
+        url
+    }
+
+    pub fn lnurlp_well_known(&self, username: &str) -> Url {
+        self.join(&[".well-known", "lnurlp", username])
+    }
+
+    pub fn lnurlp_callback(&self, username: &str) -> Url {
+        self.join(&["lnurlp", username, "callback"])
+    }
+
+    pub fn lnurlp_verify(&self, username: &str, op_id: &str) -> Url {
+        self.join(&["lnurlp", username, "verify", op_id])
+    }
+
+    pub fn lnurlw(&self, id: &str) -> Url {
+        self.join(&["lnurlw", id])
+    }
+
+    pub fn lnurlw_callback(&self, id: &str) -> Url {
+        self.join(&["lnurlw", id, "callback"])
+    }
+
+    fn from_forwarded_headers(mut url: Url, headers: &HeaderMap) -> Url {
+        let header = |name: &str| {
+            headers
+                .get(name)
+                .and_then(|h| h.to_str().ok())
+                // proxies may append their own values, the first one is the client-facing one
+                .and_then(|h| h.split(',').next())
+                .map(str::trim)
+                .filter(|h| !h.is_empty())
+        };
+
+        if let Some(proto) = header("X-Forwarded-Proto") {
+            if matches!(proto, "http" | "https") {
+                let _ = url.set_scheme(proto);
+            }
+        }
+        if let Some(host) = header("X-Forwarded-Host") {
+            let (host, port) = match host.rsplit_once(':') {
+                Some((host, port)) if port.parse::<u16>().is_ok() => (host, port.parse().ok()),
+                _ => (host, None),
+            };
+            if url.set_host(Some(host)).is_ok() {
+                let _ = url.set_port(port);
+            }
+        }
+        if let Some(prefix) = header("X-Forwarded-Prefix") {
+            url.set_path(prefix);
+        }
+
+        url
+    }
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for BaseUrl {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Infallible> {
+        let url = state.config.public_base_url.clone();
+        if state.config.trust_forwarded_headers {
+            Ok(Self(Self::from_forwarded_headers(url, &parts.headers)))
+        } else {
+            Ok(Self(url))
+        }
+    }
+}
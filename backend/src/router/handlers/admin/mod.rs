@@ -0,0 +1,107 @@
+use anyhow::anyhow;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use multimint::fedimint_core::core::OperationId;
+use serde::Serialize;
+
+use super::auth::AdminAuth;
+use crate::error::AppError;
+use crate::lightning::FakeLightning;
+use crate::model::invoice_events::InvoiceEvent;
+use crate::model::invoices::Invoice;
+use crate::reconcile::ReconcileReport;
+use crate::state::AppState;
+
+/// The latest reconciliation, periodic or requested.
+#[axum_macros::debug_handler]
+pub async fn handle_reconcile_report(
+    _: AdminAuth,
+    State(state): State<AppState>,
+) -> Result<Json<ReconcileReport>, AppError> {
+    let report = state.reconciler.last_report().await.ok_or_else(|| {
+        AppError::new(
+            StatusCode::NOT_FOUND,
+            anyhow!("No reconciliation has run yet"),
+        )
+    })?;
+    Ok(Json(report))
+}
+
+/// Reconciles everything now, including finished invoices and orphan
+/// operations, waiting for a run already in progress to finish first.
+#[axum_macros::debug_handler]
+pub async fn handle_reconcile(
+    _: AdminAuth,
+    State(state): State<AppState>,
+) -> Result<Json<ReconcileReport>, AppError> {
+    Ok(Json(state.reconciler.run(&state, true).await?))
+}
+
+#[derive(Serialize)]
+pub struct InvoiceTimeline {
+    pub invoice: Invoice,
+    pub events: Vec<InvoiceEvent>,
+}
+
+/// Every recorded stage of an invoice, oldest first, for debugging stuck payments.
+#[axum_macros::debug_handler]
+pub async fn handle_invoice_timeline(
+    _: AdminAuth,
+    Path(op_id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<Json<InvoiceTimeline>, AppError> {
+    let invoice = state
+        .invoices
+        .get_by_op_id(&op_id)
+        .await?
+        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, anyhow!("Invoice not found")))?;
+    let events = state.invoices.get_events(invoice.id).await?;
+    Ok(Json(InvoiceTimeline { invoice, events }))
+}
+
+/// Pays a pending invoice of the fake lightning backend, in dev mode only.
+#[axum_macros::debug_handler]
+pub async fn handle_settle_invoice(
+    _: AdminAuth,
+    Path(op_id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, AppError> {
+    let (fake, op_id) = fake_receive(&state, &op_id).await?;
+    fake.settle(op_id)
+        .map_err(|e| AppError::new(StatusCode::CONFLICT, e))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Cancels a pending invoice of the fake lightning backend, in dev mode only.
+#[axum_macros::debug_handler]
+pub async fn handle_cancel_invoice(
+    _: AdminAuth,
+    Path(op_id): Path<String>,
+    State(state): State<AppState>,
+) -> Result<StatusCode, AppError> {
+    let (fake, op_id) = fake_receive(&state, &op_id).await?;
+    fake.cancel(op_id)
+        .map_err(|e| AppError::new(StatusCode::CONFLICT, e))?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn fake_receive<'a>(
+    state: &'a AppState,
+    op_id: &str,
+) -> Result<(&'a FakeLightning, OperationId), AppError> {
+    let fake = state
+        .fake_lightning
+        .as_ref()
+        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, anyhow!("Not in dev mode")))?;
+    if state.invoices.get_by_op_id(op_id).await?.is_none() {
+        return Err(AppError::new(
+            StatusCode::NOT_FOUND,
+            anyhow!("Invoice not found"),
+        ));
+    }
+    let op_id = op_id
+        .parse()
+        .map_err(|_| AppError::new(StatusCode::BAD_REQUEST, anyhow!("Invalid op_id")))?;
+    Ok((fake, op_id))
+}
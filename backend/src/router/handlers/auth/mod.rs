@@ -0,0 +1,178 @@
+use std::convert::Infallible;
+
+use anyhow::{anyhow, bail, Context};
+use axum::async_trait;
+use axum::body::Body;
+use axum::extract::{FromRequestParts, Request};
+use axum::http::request::Parts;
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::middleware::Next;
+use axum::response::Response;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use multimint::fedimint_core::bitcoin_hashes::{sha256, Hash};
+use nostr_sdk::{Event, JsonUtil, Kind, PublicKey, Timestamp};
+use subtle::ConstantTimeEq;
+use url::Url;
+
+use crate::error::AppError;
+use crate::model::users::User;
+use crate::router::base_url::BaseUrl;
+use crate::state::AppState;
+
+/// How far a NIP-98 event's `created_at` may be from now.
+const NIP98_MAX_AGE_SECS: u64 = 60;
+/// Largest body read to check a NIP-98 `payload` tag against.
+const NIP98_MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// The registered user whose key signed the request's NIP-98 authorization.
+/// Headers like `X-Replit-User-Name` can be sent by anyone who reaches the
+/// server directly, so changes to a user's account need their signature.
+pub struct NostrUser(pub User);
+
+#[async_trait]
+impl FromRequestParts<AppState> for NostrUser {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, AppError> {
+        let NostrAuth(pubkey) = NostrAuth::from_request_parts(parts, state).await?;
+        let user = state
+            .users
+            .get_by_pubkey(&pubkey.to_hex())
+            .await?
+            .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, anyhow!("User not registered")))?;
+        Ok(Self(user))
+    }
+}
+
+/// An operator request carrying `Authorization: Bearer <admin_token>`. The
+/// admin endpoints don't exist unless a token is configured.
+pub struct AdminAuth;
+
+#[async_trait]
+impl FromRequestParts<AppState> for AdminAuth {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, AppError> {
+        let expected = state
+            .config
+            .admin_token
+            .as_ref()
+            .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, anyhow!("Not found")))?;
+        let token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|h| h.to_str().ok())
+            .and_then(|h| h.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        if !bool::from(token.as_bytes().ct_eq(expected.expose().as_bytes())) {
+            return Err(AppError::new(
+                StatusCode::UNAUTHORIZED,
+                anyhow!("Invalid admin token"),
+            ));
+        }
+        Ok(Self)
+    }
+}
+
+/// The pubkey that signed the request's NIP-98 `Authorization: Nostr <event>`
+/// header. Wallets hold the key invoices are tweaked from, so this is how they
+/// authenticate without a Replit session.
+pub struct NostrAuth(pub PublicKey);
+
+/// SHA-256 of a NIP-98 authorized request's body, `None` when it has none.
+#[derive(Clone, Copy)]
+struct BodyHash(Option<sha256::Hash>);
+
+fn nostr_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Nostr "))
+}
+
+/// Middleware hashing the body of requests with a NIP-98 authorization, so
+/// [`NostrAuth`] can hold it to the event's `payload` tag. Without it a
+/// captured header could be replayed with another body until it expires.
+pub async fn hash_body(request: Request, next: Next) -> Result<Response, AppError> {
+    if nostr_token(request.headers()).is_none() {
+        return Ok(next.run(request).await);
+    }
+    let (parts, body) = request.into_parts();
+    let bytes = axum::body::to_bytes(body, NIP98_MAX_BODY_BYTES)
+        .await
+        .map_err(|e| AppError::new(StatusCode::PAYLOAD_TOO_LARGE, anyhow!(e)))?;
+    let hash = BodyHash((!bytes.is_empty()).then(|| sha256::Hash::hash(&bytes)));
+    let mut request = Request::from_parts(parts, Body::from(bytes));
+    request.extensions_mut().insert(hash);
+    Ok(next.run(request).await)
+}
+
+impl NostrAuth {
+    fn verify(parts: &Parts, base_url: &BaseUrl) -> anyhow::Result<PublicKey> {
+        let token = nostr_token(&parts.headers).context("Missing Nostr authorization header")?;
+        let event = Event::from_json(BASE64.decode(token.trim())?)?;
+        event.verify()?;
+
+        if event.kind != Kind::HttpAuth {
+            bail!("Wrong event kind");
+        }
+        let now = Timestamp::now().as_u64();
+        if now.abs_diff(event.created_at.as_u64()) > NIP98_MAX_AGE_SECS {
+            bail!("Authorization event expired");
+        }
+
+        let tag = |name: &str| {
+            event
+                .tags
+                .iter()
+                .map(|tag| tag.as_slice())
+                .find(|tag| tag.first().map(String::as_str) == Some(name))
+                .and_then(|tag| tag.get(1))
+        };
+        let signed_url = Url::parse(tag("u").context("Missing u tag")?)?;
+        if signed_url != request_url(parts, base_url) {
+            bail!("Authorization is for a different url");
+        }
+        let method = tag("method").context("Missing method tag")?;
+        if !method.eq_ignore_ascii_case(parts.method.as_str()) {
+            bail!("Authorization is for a different method");
+        }
+        // fails closed on routes hash_body doesn't run on
+        let BodyHash(body_hash) = parts
+            .extensions
+            .get::<BodyHash>()
+            .context("Request body can't be checked")?;
+        if let Some(body_hash) = body_hash {
+            let payload = tag("payload").context("Missing payload tag")?;
+            if !payload.eq_ignore_ascii_case(&body_hash.to_string()) {
+                bail!("Authorization is for a different body");
+            }
+        }
+
+        Ok(event.pubkey)
+    }
+}
+
+/// The absolute url the client requested, as seen from outside.
+fn request_url(parts: &Parts, base_url: &BaseUrl) -> Url {
+    let mut url = base_url.url().clone();
+    let path = format!("{}{}", url.path().trim_end_matches('/'), parts.uri.path());
+    url.set_path(&path);
+    url.set_query(parts.uri.query());
+    url
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for NostrAuth {
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, AppError> {
+        let base_url = BaseUrl::from_request_parts(parts, state)
+            .await
+            .map_err(|e: Infallible| match e {})?;
+        let pubkey = Self::verify(parts, &base_url)
+            .map_err(|e| AppError::new(StatusCode::UNAUTHORIZED, e))?;
+        Ok(Self(pubkey))
+    }
+}
@@ -0,0 +1,26 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::Serialize;
+
+use crate::health::{ComponentStatus, HealthReport};
+use crate::state::AppState;
+
+#[derive(Serialize)]
+pub struct ReadyResponse {
+    pub status: ComponentStatus,
+    #[serde(flatten)]
+    pub report: HealthReport,
+}
+
+#[axum_macros::debug_handler]
+pub async fn handle_ready(State(state): State<AppState>) -> (StatusCode, Json<ReadyResponse>) {
+    let report = state.health.report().await;
+    let status = report.status(state.config.dev);
+    let code = match status {
+        ComponentStatus::Unhealthy => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::OK,
+    };
+
+    (code, Json(ReadyResponse { status, report }))
+}
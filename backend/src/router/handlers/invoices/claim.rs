@@ -0,0 +1,67 @@
+use axum::extract::State;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::error::AppError;
+use crate::model::invoices::Invoice;
+use crate::router::handlers::auth::NostrAuth;
+use crate::state::AppState;
+
+/// What a wallet needs to sweep the ecash of one settled invoice.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UnclaimedInvoice {
+    pub federation_id: String,
+    pub op_id: String,
+    /// Index the user's pubkey was tweaked with for this invoice.
+    pub tweak: i64,
+    pub amount: i64,
+}
+
+impl From<Invoice> for UnclaimedInvoice {
+    fn from(invoice: Invoice) -> Self {
+        Self {
+            federation_id: invoice.federation_id,
+            op_id: invoice.op_id,
+            tweak: invoice.tweak,
+            amount: invoice.amount,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ClaimRequest {
+    pub op_ids: Vec<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ClaimResponse {
+    /// The requested op ids that were settled and not yet claimed.
+    pub claimed: Vec<String>,
+}
+
+#[axum_macros::debug_handler]
+pub async fn handle_unclaimed(
+    State(state): State<AppState>,
+    NostrAuth(pubkey): NostrAuth,
+) -> Result<Json<Vec<UnclaimedInvoice>>, AppError> {
+    let invoices = state.invoices.get_unclaimed(&pubkey.to_hex()).await?;
+    Ok(Json(invoices.into_iter().map(Into::into).collect()))
+}
+
+#[axum_macros::debug_handler]
+pub async fn handle_claim(
+    State(state): State<AppState>,
+    NostrAuth(pubkey): NostrAuth,
+    Json(request): Json<ClaimRequest>,
+) -> Result<Json<ClaimResponse>, AppError> {
+    let claimed = state
+        .invoices
+        .mark_claimed(&pubkey.to_hex(), &request.op_ids)
+        .await?
+        .into_iter()
+        .map(|invoice| invoice.op_id)
+        .collect::<Vec<_>>();
+    info!("Marked {} invoices claimed for {}", claimed.len(), pubkey);
+    Ok(Json(ClaimResponse { claimed }))
+}
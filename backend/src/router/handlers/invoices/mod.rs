@@ -1,3 +1,5 @@
+pub mod claim;
+
 use axum::extract::{Query, State};
 use axum::Json;
 use serde::Deserialize;
@@ -14,7 +16,7 @@ pub async fn handle_invoices(
     Query(query): Query<InvoiceQuery>,
     State(state): State<AppState>,
 ) -> Result<Json<Vec<Invoice>>, AppError> {
-    let invoices = state.db.invoices().get_by_state(InvoiceState::Pending).await?;
+    let invoices = state.invoices.get_by_state(InvoiceState::Pending).await?;
     let user_invoices = invoices.into_iter()
         .filter(|invoice| invoice.user_id == query.user_id)
         .collect::<Vec<Invoice>>();
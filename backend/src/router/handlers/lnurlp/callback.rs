@@ -1,21 +1,85 @@
-use anyhow::Result;
+use std::fmt;
+use std::str::FromStr;
+
+use aes::cipher::block_padding::Pkcs7;
+use aes::cipher::{BlockEncryptMut, KeyIvInit};
+use anyhow::{anyhow, Result};
 use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
 use axum::Json;
-use multimint::fedimint_ln_client::LightningClientModule;
-use serde::{Deserialize, Serialize};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use nostr_sdk::{Event, JsonUtil};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use tracing::{debug, info};
 use url::Url;
 
 use super::LnurlStatus;
+use crate::config::Config;
 use crate::error::AppError;
+use crate::metrics;
+use crate::model::invoices::Invoice;
+use crate::model::users::success_action::SuccessActionTemplate;
+use crate::router::base_url::BaseUrl;
 use crate::serde_helpers::empty_string_as_none;
-use crate::state::AppState;
+use crate::state::{AppState, InvoiceRequest};
+
+#[cfg(test)]
+mod tests;
+
+/// Requested amount, either plain millisatoshis or, per LUD-21 currencies,
+/// `<amount>.<CODE>` in the smallest unit of an advertised currency.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CallbackAmount {
+    Msats(u64),
+    Fiat { amount: u64, currency: String },
+}
+
+impl FromStr for CallbackAmount {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('.') {
+            Some((amount, currency))
+                if !currency.is_empty() && currency.bytes().all(|b| b.is_ascii_alphabetic()) =>
+            {
+                Ok(Self::Fiat {
+                    amount: amount.parse()?,
+                    currency: currency.to_uppercase(),
+                })
+            }
+            Some(_) => Err(anyhow!("Invalid currency in amount {s:?}")),
+            None => Ok(Self::Msats(s.parse()?)),
+        }
+    }
+}
+
+impl fmt::Display for CallbackAmount {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Msats(msats) => write!(f, "{msats}"),
+            Self::Fiat { amount, currency } => write!(f, "{amount}.{currency}"),
+        }
+    }
+}
+
+impl Serialize for CallbackAmount {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for CallbackAmount {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct LnurlCallbackParams {
-    pub amount: u64,
+    pub amount: CallbackAmount,
     #[serde(default, deserialize_with = "empty_string_as_none")]
     pub nonce: Option<String>,
     #[serde(default, deserialize_with = "empty_string_as_none")]
@@ -26,11 +90,86 @@ pub struct LnurlCallbackParams {
     pub nostr: Option<String>,
 }
 
+impl LnurlCallbackParams {
+    /// Key under which retried callbacks are deduplicated: the wallet's `nonce`,
+    /// or for zaps the id of the zap request event.
+    pub fn idempotency_key(&self) -> Option<String> {
+        self.nonce
+            .clone()
+            .or_else(|| self.zap_request().map(|event| event.id.to_hex()))
+    }
+
+    /// Hex pubkey of the zap request author, if this payment is a zap.
+    pub fn payer(&self) -> Option<String> {
+        self.zap_request().map(|event| event.pubkey.to_hex())
+    }
+
+    fn zap_request(&self) -> Option<Event> {
+        self.nostr
+            .as_deref()
+            .and_then(|zap_request| Event::from_json(zap_request).ok())
+    }
+}
+
+type Aes256CbcEnc = cbc::Encryptor<aes::Aes256>;
+
+/// LUD-09 success action, with LUD-10 `aes` payloads.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "tag", rename_all = "lowercase")]
+pub enum LnurlCallbackSuccessAction {
+    Message {
+        message: String,
+    },
+    Url {
+        description: String,
+        url: Url,
+    },
+    Aes {
+        description: String,
+        ciphertext: String,
+        iv: String,
+    },
+}
+
+impl LnurlCallbackSuccessAction {
+    /// Renders a stored template for one invoice, encrypting `aes` plaintexts
+    /// with the invoice preimage so they can only be read once it is paid.
+    pub fn render(template: SuccessActionTemplate, invoice: &Invoice) -> Result<Self> {
+        Ok(match template {
+            SuccessActionTemplate::Message { message } => Self::Message { message },
+            SuccessActionTemplate::Url { description, url } => Self::Url { description, url },
+            SuccessActionTemplate::Aes {
+                description,
+                plaintext,
+            } => {
+                let preimage: [u8; 32] = hex::decode(
+                    invoice
+                        .preimage
+                        .as_deref()
+                        .ok_or_else(|| anyhow!("Invoice {} has no preimage", invoice.op_id))?,
+                )?
+                .try_into()
+                .map_err(|_| anyhow!("Invalid preimage for invoice {}", invoice.op_id))?;
+                let iv: [u8; 16] = rand::random();
+                let ciphertext = Aes256CbcEnc::new(&preimage.into(), &iv.into())
+                    .encrypt_padded_vec_mut::<Pkcs7>(plaintext.as_bytes());
+                Self::Aes {
+                    description,
+                    ciphertext: BASE64.encode(ciphertext),
+                    iv: BASE64.encode(iv),
+                }
+            }
+        })
+    }
+}
+
+/// Conversion applied to a currency-denominated callback.
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
-pub struct LnurlCallbackSuccessAction {
-    pub tag: String,
-    pub message: String,
+pub struct LnurlConverted {
+    pub amount: u64,
+    pub currency: String,
+    pub multiplier: f64,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -45,6 +184,8 @@ pub struct LnurlCallbackResponse {
     pub success_action: Option<LnurlCallbackSuccessAction>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub routes: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub converted: Option<LnurlConverted>,
 }
 
 #[axum_macros::debug_handler]
@@ -52,36 +193,92 @@ pub async fn handle_callback(
     Path(username): Path<String>,
     Query(params): Query<LnurlCallbackParams>,
     State(state): State<AppState>,
+    base_url: BaseUrl,
 ) -> Result<Json<LnurlCallbackResponse>, AppError> {
     debug!("Callback for user: {}, params: {:?}", username, params);
 
-    let user = state.db.users().get_by_name(&username).await?.unwrap();
-    let (federation_id, client) = state.get_federation_and_client(&user).await?;
-    let ln = client.get_first_module::<LightningClientModule>();
+    let result = process_callback(&state, &base_url, &username, &params).await;
+    let outcome = if result.is_ok() { "ok" } else { "error" };
+    metrics::CALLBACKS_SERVED
+        .with_label_values(&[outcome])
+        .inc();
+
+    result.map(Json)
+}
+
+async fn process_callback(
+    state: &AppState,
+    base_url: &BaseUrl,
+    username: &str,
+    params: &LnurlCallbackParams,
+) -> Result<LnurlCallbackResponse, AppError> {
+    let user = state
+        .users
+        .get_by_name(username)
+        .await?
+        .ok_or_else(|| AppError::new(StatusCode::NOT_FOUND, anyhow!("User not found")))?;
+    let federation_id = state.get_federation(&user).await?;
+
+    let (amount_msats, fiat) = match &params.amount {
+        CallbackAmount::Msats(msats) => (*msats, None),
+        CallbackAmount::Fiat { amount, currency } => {
+            let price_feed = state.price_feed.as_ref().ok_or_else(|| {
+                AppError::new(
+                    StatusCode::BAD_REQUEST,
+                    anyhow!("Currency amounts are not supported"),
+                )
+            })?;
+            let quote = price_feed
+                .quote(currency, *amount)
+                .await
+                .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, e))?;
+            (quote.amount_msats, Some(quote))
+        }
+    };
+    check_sendable(&state.config, amount_msats)?;
+    let request = InvoiceRequest {
+        amount_msats,
+        comment: params.comment.clone(),
+        nonce: params.idempotency_key(),
+        fiat,
+        payer: params.payer(),
+    };
 
     let (op_id, invoice) = state
-        .create_invoice_store_and_notify(&ln, &user, &params, federation_id)
+        .create_invoice_store_and_notify(&user, &request, federation_id)
         .await?;
 
-    let verify_url = create_verify_url(&username, &op_id.fmt_full().to_string())?;
-    let response = create_callback_response(invoice.bolt11, verify_url)?;
+    let verify_url = base_url.lnurlp_verify(username, &op_id.fmt_full().to_string());
+    let mut response = create_callback_response(invoice.bolt11.clone(), verify_url)?;
+    if let Some(template) = user.success_action.clone() {
+        response.success_action = Some(LnurlCallbackSuccessAction::render(template, &invoice)?);
+    }
+    if let (Some(currency), Some(multiplier)) = (invoice.fiat_currency, invoice.fiat_rate) {
+        response.converted = Some(LnurlConverted {
+            amount: invoice.amount as u64,
+            currency,
+            multiplier,
+        });
+    }
 
     info!(
         "Callback processed for user: {}, op_id: {:?}",
         username, op_id
     );
-    Ok(Json(response))
+    Ok(response)
 }
 
-pub fn create_verify_url(username: &str, op_id: &str) -> Result<Url, AppError> {
-    Url::parse(&format!(
-        "http://{}:{}/lnurlp/{}/verify/{}",
-        crate::config::CONFIG.domain,
-        crate::config::CONFIG.port,
-        username,
-        op_id
-    ))
-    .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, anyhow::anyhow!(e)))
+/// Applies to the converted amount too, so a fiat amount can't get around
+/// the advertised bounds.
+pub fn check_sendable(config: &Config, amount_msats: u64) -> Result<(), AppError> {
+    let (min, max) = (config.min_sendable_msats, config.max_sendable_msats);
+    if !(min..=max).contains(&amount_msats) {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Amount {amount_msats} msats is outside {min}..={max}"),
+        ));
+    }
+    Ok(())
 }
 
 pub fn create_callback_response(
@@ -95,5 +292,6 @@ pub fn create_callback_response(
         reason: None,
         verify: verify_url,
         routes: Some(vec![]),
+        converted: None,
     })
 }
@@ -0,0 +1,46 @@
+//! Parsing of the callback's `amount` parameter.
+
+use super::*;
+
+#[test]
+fn plain_amounts_are_msats() -> Result<()> {
+    assert_eq!(
+        CallbackAmount::from_str("21000")?,
+        CallbackAmount::Msats(21_000)
+    );
+    assert!(CallbackAmount::from_str("").is_err());
+    assert!(CallbackAmount::from_str("-5").is_err());
+    assert!(CallbackAmount::from_str("21k").is_err());
+    Ok(())
+}
+
+#[test]
+fn currency_amounts_are_in_the_smallest_unit() -> Result<()> {
+    let amount = CallbackAmount::from_str("500.usd")?;
+    assert_eq!(
+        amount,
+        CallbackAmount::Fiat {
+            amount: 500,
+            currency: "USD".to_string(),
+        }
+    );
+    assert_eq!(amount.to_string(), "500.USD");
+
+    assert!(CallbackAmount::from_str("500.").is_err());
+    assert!(CallbackAmount::from_str(".USD").is_err());
+    assert!(CallbackAmount::from_str("5.5.USD").is_err());
+    Ok(())
+}
+
+#[test]
+fn amounts_round_trip_through_serde() -> Result<()> {
+    let params: LnurlCallbackParams = serde_json::from_value(serde_json::json!({
+        "amount": "500.EUR",
+    }))?;
+    assert_eq!(serde_json::to_value(&params.amount)?, "500.EUR");
+    assert!(
+        serde_json::from_value::<LnurlCallbackParams>(serde_json::json!({ "amount": "x" }))
+            .is_err()
+    );
+    Ok(())
+}
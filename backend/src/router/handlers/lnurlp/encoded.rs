@@ -0,0 +1,46 @@
+use anyhow::anyhow;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::Response;
+use axum::Json;
+
+use crate::error::AppError;
+use crate::lnurl::EncodedLnurl;
+use crate::router::base_url::BaseUrl;
+use crate::state::AppState;
+
+async fn encode_pay_url(
+    state: &AppState,
+    base_url: &BaseUrl,
+    username: &str,
+) -> Result<EncodedLnurl, AppError> {
+    if state.users.get_by_name(username).await?.is_none() {
+        return Err(AppError::new(
+            StatusCode::NOT_FOUND,
+            anyhow!("User not found"),
+        ));
+    }
+    Ok(EncodedLnurl::new(base_url.lnurlp_well_known(username))?)
+}
+
+/// The user's pay endpoint as a bech32 LNURL, for wallets without lightning
+/// address support.
+#[axum_macros::debug_handler]
+pub async fn handle_lnurl(
+    Path(username): Path<String>,
+    State(state): State<AppState>,
+    base_url: BaseUrl,
+) -> Result<Json<EncodedLnurl>, AppError> {
+    Ok(Json(encode_pay_url(&state, &base_url, &username).await?))
+}
+
+#[axum_macros::debug_handler]
+pub async fn handle_qr(
+    Path(username): Path<String>,
+    State(state): State<AppState>,
+    base_url: BaseUrl,
+) -> Result<Response, AppError> {
+    Ok(encode_pay_url(&state, &base_url, &username)
+        .await?
+        .qr_svg()?)
+}
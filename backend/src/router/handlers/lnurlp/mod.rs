@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 
 pub mod callback;
+pub mod encoded;
 pub mod verify;
 pub mod well_known;
 
@@ -8,6 +9,7 @@ pub mod well_known;
 #[serde(rename_all = "camelCase")]
 pub enum LnurlType {
     PayRequest,
+    WithdrawRequest,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -16,3 +18,19 @@ pub enum LnurlStatus {
     Ok,
     Error,
 }
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LnurlErrorResponse {
+    pub status: LnurlStatus,
+    pub reason: String,
+}
+
+impl LnurlErrorResponse {
+    pub fn new(reason: impl Into<String>) -> Self {
+        Self {
+            status: LnurlStatus::Error,
+            reason: reason.into(),
+        }
+    }
+}
@@ -15,7 +15,8 @@ use crate::state::AppState;
 pub struct LnurlVerifyResponse {
     pub status: LnurlStatus,
     pub settled: bool,
-    pub preimage: String,
+    /// Hex encoded, only once the invoice is settled.
+    pub preimage: Option<String>,
     pub pr: String,
 }
 
@@ -29,12 +30,13 @@ pub async fn handle_verify(
         username, op_id
     );
 
-    match state.db.invoices().get_by_op_id(&op_id).await? {
+    match state.invoices.get_by_op_id(&op_id).await? {
         Some(invoice) => {
+            let settled = invoice.state == InvoiceState::Settled;
             let verify_response = LnurlVerifyResponse {
                 status: LnurlStatus::Ok,
-                settled: invoice.state == InvoiceState::Settled,
-                preimage: "".to_string(), // TODO: figure out how to get the preimage from fedimint client
+                settled,
+                preimage: settled.then_some(invoice.preimage).flatten(),
                 pr: invoice.bolt11,
             };
             info!("Verify response: {:?}", verify_response);
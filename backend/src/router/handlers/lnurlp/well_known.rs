@@ -12,8 +12,9 @@ use tracing::info;
 use url::Url;
 
 use super::{LnurlStatus, LnurlType};
-use crate::config::CONFIG;
 use crate::error::AppError;
+use crate::price_feed::CurrencyRate;
+use crate::router::base_url::BaseUrl;
 use crate::state::AppState;
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -57,28 +58,72 @@ pub struct LnurlWellKnownResponse {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub nostr_pubkey: Option<XOnlyPublicKey>,
     pub allows_nostr: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currencies: Option<Vec<LnurlCurrency>>,
+}
+
+/// A currency the payer may denominate the amount in, per LUD-21 currencies.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LnurlCurrency {
+    pub code: String,
+    pub name: String,
+    pub symbol: String,
+    pub decimals: u32,
+    /// Millisatoshis per smallest unit of the currency at the current rate.
+    pub multiplier: f64,
+    pub convertible: bool,
+}
+
+impl From<CurrencyRate> for LnurlCurrency {
+    fn from(rate: CurrencyRate) -> Self {
+        Self {
+            code: rate.code,
+            name: rate.name,
+            symbol: rate.symbol,
+            decimals: rate.decimals,
+            multiplier: rate.multiplier,
+            convertible: true,
+        }
+    }
 }
 
 #[axum_macros::debug_handler]
 pub async fn handle_well_known(
     Path(username): Path<String>,
     State(state): State<AppState>,
+    base_url: BaseUrl,
 ) -> Result<Json<LnurlWellKnownResponse>, AppError> {
     // see if username exists in nostr.json
     info!("well_known called with username: {}", username);
-    match state.db.users().get_by_name(&username).await? {
+    match state.users.get_by_name(&username).await? {
         Some(user) => {
+            let currencies = match &state.price_feed {
+                Some(price_feed) => Some(
+                    price_feed
+                        .currencies()
+                        .await?
+                        .into_iter()
+                        .map(LnurlCurrency::from)
+                        .collect(),
+                ),
+                None => None,
+            };
             let res = LnurlWellKnownResponse {
-                callback: format!("https://{}/lnurlp/{}/callback", CONFIG.domain, username)
-                    .parse()?,
-                max_sendable: Amount { msats: 100000 },
-                min_sendable: Amount { msats: 1000 },
+                callback: base_url.lnurlp_callback(&username),
+                max_sendable: Amount {
+                    msats: state.config.max_sendable_msats,
+                },
+                min_sendable: Amount {
+                    msats: state.config.min_sendable_msats,
+                },
                 metadata: "".to_string(),
                 comment_allowed: None,
                 tag: LnurlType::PayRequest,
                 status: LnurlStatus::Ok,
                 nostr_pubkey: Some(XOnlyPublicKey::from_str(&user.pubkey)?),
                 allows_nostr: true,
+                currencies,
             };
 
             Ok(Json(res))
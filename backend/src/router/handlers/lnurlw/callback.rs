@@ -0,0 +1,102 @@
+use std::str::FromStr;
+
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use multimint::fedimint_core::bitcoin::Network;
+use multimint::fedimint_ln_common::lightning_invoice::{Bolt11Invoice, Currency};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tracing::info;
+
+use super::{reject, reject_error};
+use crate::error::AppError;
+use crate::router::handlers::lnurlp::LnurlStatus;
+use crate::state::AppState;
+
+#[derive(Deserialize, Debug)]
+pub struct LnurlWithdrawCallbackParams {
+    pub k1: String,
+    pub pr: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LnurlWithdrawCallbackResponse {
+    pub status: LnurlStatus,
+}
+
+/// Pays the wallet's invoice if it fits the link's limits. Per LUD-03 the
+/// response only confirms the payment was started, and failures are LUD-03
+/// errors too.
+#[axum_macros::debug_handler]
+pub async fn handle_callback(
+    Path(id): Path<String>,
+    Query(params): Query<LnurlWithdrawCallbackParams>,
+    State(state): State<AppState>,
+) -> Response {
+    process_callback(&state, &id, &params)
+        .await
+        .unwrap_or_else(|e| reject_error(&format!("Withdraw callback for {id} failed"), e))
+}
+
+async fn process_callback(
+    state: &AppState,
+    id: &str,
+    params: &LnurlWithdrawCallbackParams,
+) -> Result<Response, AppError> {
+    let Some(withdrawal) = state.withdrawals()?.get(id).await? else {
+        return Ok(reject(StatusCode::NOT_FOUND, "Withdraw link not found"));
+    };
+    if !bool::from(withdrawal.k1.as_bytes().ct_eq(params.k1.as_bytes())) {
+        return Ok(reject(StatusCode::BAD_REQUEST, "Invalid k1"));
+    }
+
+    let Ok(invoice) = Bolt11Invoice::from_str(&params.pr) else {
+        return Ok(reject(StatusCode::BAD_REQUEST, "Invalid invoice"));
+    };
+    if invoice.is_expired() {
+        return Ok(reject(StatusCode::BAD_REQUEST, "Invoice expired"));
+    }
+    let network = state.federation_network(&withdrawal.federation_id).await?;
+    if !is_for_network(&invoice, network) {
+        return Ok(reject(
+            StatusCode::BAD_REQUEST,
+            "Invoice is for a different network",
+        ));
+    }
+    let amount = invoice.amount_milli_satoshis().unwrap_or(0) as i64;
+    if amount < withdrawal.min_withdrawable || amount > withdrawal.max_withdrawable {
+        return Ok(reject(
+            StatusCode::BAD_REQUEST,
+            "Invoice amount is outside the withdraw limits",
+        ));
+    }
+
+    let Some(withdrawal) = state
+        .withdrawals()?
+        .claim(&withdrawal.id, &params.k1, &params.pr)
+        .await?
+    else {
+        return Ok(reject(StatusCode::GONE, "Withdraw link already used"));
+    };
+    if state.pay_withdrawal(withdrawal, invoice).await.is_err() {
+        return Ok(reject(StatusCode::INTERNAL_SERVER_ERROR, "Payment failed"));
+    }
+
+    info!("Withdrawal {} payment started for {} msats", id, amount);
+    Ok(Json(LnurlWithdrawCallbackResponse {
+        status: LnurlStatus::Ok,
+    })
+    .into_response())
+}
+
+fn is_for_network(invoice: &Bolt11Invoice, network: Network) -> bool {
+    matches!(
+        (invoice.currency(), network),
+        (Currency::Bitcoin, Network::Bitcoin)
+            | (Currency::BitcoinTestnet, Network::Testnet)
+            | (Currency::Regtest, Network::Regtest)
+            | (Currency::Signet, Network::Signet)
+    )
+}
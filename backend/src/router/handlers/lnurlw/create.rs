@@ -0,0 +1,101 @@
+use anyhow::anyhow;
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use url::Url;
+
+use crate::error::AppError;
+use crate::lnurl;
+use crate::model::withdrawals::{Withdrawal, WithdrawalForCreate};
+use crate::router::base_url::BaseUrl;
+use crate::router::handlers::auth::NostrUser;
+use crate::state::AppState;
+
+const MAX_DESCRIPTION_LEN: usize = 144;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateWithdrawalRequest {
+    pub description: String,
+    pub min_withdrawable: u64,
+    pub max_withdrawable: u64,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateWithdrawalResponse {
+    pub withdrawal: Withdrawal,
+    /// The LUD-03 link to hand to the recipient's wallet.
+    pub url: Url,
+    /// `url` bech32 encoded, per LUD-01.
+    pub lnurl: String,
+}
+
+/// Issues a link for the signing user. Links pay out of the server's own
+/// ecash, so each one's amount and the number a user has open are capped.
+#[axum_macros::debug_handler]
+pub async fn handle_create(
+    State(state): State<AppState>,
+    base_url: BaseUrl,
+    NostrUser(user): NostrUser,
+    Json(request): Json<CreateWithdrawalRequest>,
+) -> Result<Json<CreateWithdrawalResponse>, AppError> {
+    let max_allowed = state.config.withdraw_max_msats;
+    if max_allowed == 0 {
+        return Err(AppError::new(
+            StatusCode::FORBIDDEN,
+            anyhow!("Withdraw links are disabled"),
+        ));
+    }
+    if request.min_withdrawable == 0
+        || request.min_withdrawable > request.max_withdrawable
+        || request.max_withdrawable > max_allowed
+    {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            anyhow!("Limits must satisfy 0 < minWithdrawable <= maxWithdrawable <= {max_allowed}"),
+        ));
+    }
+    if request.description.chars().count() > MAX_DESCRIPTION_LEN {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            anyhow!("description must be at most {MAX_DESCRIPTION_LEN} characters"),
+        ));
+    }
+
+    let (federation_id, _) = state.get_federation_and_client(&user).await?;
+    let max_open = state.config.withdraw_max_open_links;
+    let withdrawal = state
+        .withdrawals()?
+        .create(
+            WithdrawalForCreate::new(
+                user.id,
+                federation_id.to_string(),
+                request.description,
+                request.min_withdrawable as i64,
+                request.max_withdrawable as i64,
+            ),
+            max_open.into(),
+        )
+        .await?
+        .ok_or_else(|| {
+            AppError::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                anyhow!("At most {max_open} withdraw links can be open at once"),
+            )
+        })?;
+    info!(
+        "Created withdrawal {} for user {}",
+        withdrawal.id, user.name
+    );
+
+    let url = base_url.lnurlw(&withdrawal.id);
+    let lnurl = lnurl::encode(&url)?;
+    Ok(Json(CreateWithdrawalResponse {
+        withdrawal,
+        url,
+        lnurl,
+    }))
+}
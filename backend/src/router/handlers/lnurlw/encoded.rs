@@ -0,0 +1,44 @@
+use anyhow::anyhow;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::Response;
+use axum::Json;
+
+use crate::error::AppError;
+use crate::lnurl::EncodedLnurl;
+use crate::router::base_url::BaseUrl;
+use crate::state::AppState;
+
+async fn encode_withdraw_url(
+    state: &AppState,
+    base_url: &BaseUrl,
+    id: &str,
+) -> Result<EncodedLnurl, AppError> {
+    if state.withdrawals()?.get(id).await?.is_none() {
+        return Err(AppError::new(
+            StatusCode::NOT_FOUND,
+            anyhow!("Withdraw link not found"),
+        ));
+    }
+    Ok(EncodedLnurl::new(base_url.lnurlw(id))?)
+}
+
+#[axum_macros::debug_handler]
+pub async fn handle_lnurl(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    base_url: BaseUrl,
+) -> Result<Json<EncodedLnurl>, AppError> {
+    Ok(Json(encode_withdraw_url(&state, &base_url, &id).await?))
+}
+
+#[axum_macros::debug_handler]
+pub async fn handle_qr(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    base_url: BaseUrl,
+) -> Result<Response, AppError> {
+    Ok(encode_withdraw_url(&state, &base_url, &id)
+        .await?
+        .qr_svg()?)
+}
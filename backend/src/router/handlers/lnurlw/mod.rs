@@ -0,0 +1,27 @@
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use tracing::error;
+
+use super::lnurlp::LnurlErrorResponse;
+use crate::error::AppError;
+
+pub mod callback;
+pub mod create;
+pub mod encoded;
+pub mod withdraw_request;
+
+/// LUD-03 errors are reported to the wallet as `{"status": "ERROR", "reason": ...}`.
+fn reject(status: StatusCode, reason: &str) -> Response {
+    (status, Json(LnurlErrorResponse::new(reason))).into_response()
+}
+
+/// Reports a failed request as a LUD-03 error, logging internal errors
+/// instead of showing them to the wallet.
+fn reject_error(context: &str, e: AppError) -> Response {
+    if e.status.is_server_error() {
+        error!("{}: {:?}", context, e.error);
+        return reject(e.status, "Internal error");
+    }
+    reject(e.status, &e.error.to_string())
+}
@@ -0,0 +1,57 @@
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::Json;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use super::{reject, reject_error};
+use crate::error::AppError;
+use crate::router::base_url::BaseUrl;
+use crate::router::handlers::lnurlp::LnurlType;
+use crate::state::AppState;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct LnurlWithdrawResponse {
+    pub tag: LnurlType,
+    pub callback: Url,
+    pub k1: String,
+    pub default_description: String,
+    pub min_withdrawable: u64,
+    pub max_withdrawable: u64,
+}
+
+#[axum_macros::debug_handler]
+pub async fn handle_withdraw_request(
+    Path(id): Path<String>,
+    State(state): State<AppState>,
+    base_url: BaseUrl,
+) -> Response {
+    withdraw_request(&state, &base_url, &id)
+        .await
+        .unwrap_or_else(|e| reject_error(&format!("Withdraw request for {id} failed"), e))
+}
+
+async fn withdraw_request(
+    state: &AppState,
+    base_url: &BaseUrl,
+    id: &str,
+) -> Result<Response, AppError> {
+    let Some(withdrawal) = state.withdrawals()?.get(id).await? else {
+        return Ok(reject(StatusCode::NOT_FOUND, "Withdraw link not found"));
+    };
+    if !withdrawal.state.is_claimable() {
+        return Ok(reject(StatusCode::GONE, "Withdraw link already used"));
+    }
+
+    Ok(Json(LnurlWithdrawResponse {
+        tag: LnurlType::WithdrawRequest,
+        callback: base_url.lnurlw_callback(&withdrawal.id),
+        k1: withdrawal.k1,
+        default_description: withdrawal.description,
+        min_withdrawable: withdrawal.min_withdrawable as u64,
+        max_withdrawable: withdrawal.max_withdrawable as u64,
+    })
+    .into_response())
+}
@@ -0,0 +1,14 @@
+use axum::extract::State;
+use axum::http::header;
+use axum::response::IntoResponse;
+
+use crate::error::AppError;
+use crate::metrics;
+use crate::state::AppState;
+
+#[axum_macros::debug_handler]
+pub async fn handle_metrics(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let body = metrics::render(state.pool_status())?;
+
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], body))
+}
@@ -6,9 +6,15 @@ use axum::{
 
 use crate::state::AppState;
 
+pub mod admin;
 pub mod auth;
+pub mod health;
 pub mod invoices;
 pub mod lnurlp;
+pub mod lnurlw;
+pub mod metrics;
+pub mod nwc;
+pub mod users;
 
 #[axum_macros::debug_handler]
 pub async fn handle_home(
@@ -28,7 +34,7 @@ pub async fn handle_home(
     match (replit_user_id, replit_user_name, replit_profile_pic) {
         (Some(user_id), Some(user_name), Some(profile_pic)) => {
             // User is logged in
-            let user = match state.db.users().get_by_name(user_name).await {
+            let user = match state.users.get_by_name(user_name).await {
                 Ok(Some(user)) => user,
                 Ok(None) => return Ok(generate_registration_html(user_id, user_name, profile_pic)),
                 Err(e) => {
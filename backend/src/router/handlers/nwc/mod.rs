@@ -0,0 +1,74 @@
+use anyhow::anyhow;
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::Json;
+use nostr_sdk::Keys;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use url::Url;
+
+use super::auth::NostrUser;
+use crate::error::AppError;
+use crate::model::nwc_connections::NwcConnection;
+use crate::nostr::nwc;
+use crate::state::AppState;
+
+#[derive(Deserialize, Debug)]
+pub struct CreateConnectionRequest {
+    pub name: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CreateConnectionResponse {
+    pub connection: NwcConnection,
+    /// Contains the connection secret; it is not stored and can't be shown again.
+    pub uri: Url,
+}
+
+#[axum_macros::debug_handler]
+pub async fn handle_create_connection(
+    State(state): State<AppState>,
+    NostrUser(user): NostrUser,
+    Json(request): Json<CreateConnectionRequest>,
+) -> Result<Json<CreateConnectionResponse>, AppError> {
+    let secret = Keys::generate();
+    let connection = state
+        .nwc_connections()?
+        .create(user.id, &secret.public_key().to_hex(), &request.name)
+        .await?;
+    info!(
+        "Created NWC connection {} for user {}",
+        connection.id, user.name
+    );
+
+    let uri = nwc::connection_uri(
+        &state.nostr.public_key(),
+        state.nostr.pool.server_relays(),
+        secret.secret_key(),
+    );
+    Ok(Json(CreateConnectionResponse { connection, uri }))
+}
+
+#[axum_macros::debug_handler]
+pub async fn handle_list_connections(
+    State(state): State<AppState>,
+    NostrUser(user): NostrUser,
+) -> Result<Json<Vec<NwcConnection>>, AppError> {
+    Ok(Json(state.nwc_connections()?.get_by_user(user.id).await?))
+}
+
+#[axum_macros::debug_handler]
+pub async fn handle_revoke_connection(
+    Path(id): Path<i32>,
+    State(state): State<AppState>,
+    NostrUser(user): NostrUser,
+) -> Result<StatusCode, AppError> {
+    if !state.nwc_connections()?.revoke(user.id, id).await? {
+        return Err(AppError::new(
+            StatusCode::NOT_FOUND,
+            anyhow!("Connection not found"),
+        ));
+    }
+    info!("Revoked NWC connection {} for user {}", id, user.name);
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,31 @@
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::Json;
+
+use super::auth::NostrUser;
+use crate::error::AppError;
+use crate::model::users::success_action::SuccessActionTemplate;
+use crate::router::base_url::BaseUrl;
+use crate::state::AppState;
+
+/// Sets the success action shown to payers of the signing user, or clears
+/// it when the body is `null`.
+#[axum_macros::debug_handler]
+pub async fn handle_update_success_action(
+    State(state): State<AppState>,
+    base_url: BaseUrl,
+    NostrUser(user): NostrUser,
+    Json(success_action): Json<Option<SuccessActionTemplate>>,
+) -> Result<Json<Option<SuccessActionTemplate>>, AppError> {
+    if let Some(success_action) = &success_action {
+        success_action
+            .validate(base_url.url().host_str())
+            .map_err(|e| AppError::new(StatusCode::BAD_REQUEST, e))?;
+    }
+
+    let user = state
+        .users
+        .update_success_action(&user, success_action.as_ref())
+        .await?;
+    Ok(Json(user.success_action))
+}
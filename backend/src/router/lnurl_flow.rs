@@ -0,0 +1,446 @@
+//! The LNURL-pay flow over HTTP, end to end: the router runs on the fake
+//! lightning backend with in-memory storage, and payment notifications go to
+//! a relay stand-in served from the test process.
+
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, ensure, Context, Result};
+use axum::body::Body;
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+use axum::extract::State;
+use axum::http::{header, Method, Request, StatusCode};
+use axum::response::Response;
+use axum::routing::get;
+use axum::Router;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use multimint::fedimint_core::bitcoin_hashes::{sha256, Hash};
+use multimint::fedimint_ln_common::lightning_invoice::Bolt11Invoice;
+use multimint::MultiMint;
+use nostr_sdk::nips::nip59::UnwrappedGift;
+use nostr_sdk::{
+    ClientMessage, Event, EventBuilder, JsonUtil, Keys, Kind, RelayMessage, SecretKey, Tag,
+    ToBech32,
+};
+use serde_json::{json, Value};
+use tower::ServiceExt;
+
+use super::create_router;
+use crate::config::RawConfig;
+use crate::model::invoices::{Invoice, InvoiceState};
+use crate::model::users::{User, UserForCreate};
+use crate::model::Storage;
+use crate::nostr::notification::{PaymentNotification, PaymentNotificationType};
+use crate::secret::SecretString;
+use crate::state::AppState;
+
+const BASE_URL: &str = "https://replex.test";
+const ADMIN_TOKEN: &str = "test-admin-token";
+const WAIT_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// Acknowledges every event and ends every subscription right away, which
+/// is all the server asks of a relay.
+#[derive(Clone, Default)]
+struct TestRelay {
+    events: Arc<Mutex<Vec<Event>>>,
+    connections: Arc<Mutex<usize>>,
+}
+
+impl TestRelay {
+    async fn start() -> Result<(Self, String)> {
+        let relay = Self::default();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("ws://{}", listener.local_addr()?);
+        let app = Router::new()
+            .route("/", get(handle_relay))
+            .with_state(relay.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        Ok((relay, url))
+    }
+
+    fn events(&self) -> Vec<Event> {
+        self.events.lock().unwrap().clone()
+    }
+}
+
+async fn handle_relay(State(relay): State<TestRelay>, ws: WebSocketUpgrade) -> Response {
+    ws.on_upgrade(move |socket| serve_relay(relay, socket))
+}
+
+async fn serve_relay(relay: TestRelay, mut socket: WebSocket) {
+    *relay.connections.lock().unwrap() += 1;
+    while let Some(Ok(message)) = socket.recv().await {
+        let Message::Text(text) = message else {
+            continue;
+        };
+        let reply = match ClientMessage::from_json(&text) {
+            Ok(ClientMessage::Event(event)) => {
+                let reply = RelayMessage::ok(event.id, true, "");
+                relay.events.lock().unwrap().push(*event);
+                reply
+            }
+            Ok(ClientMessage::Req {
+                subscription_id, ..
+            }) => RelayMessage::eose(subscription_id),
+            _ => continue,
+        };
+        if socket.send(Message::Text(reply.as_json())).await.is_err() {
+            break;
+        }
+    }
+}
+
+struct Harness {
+    state: AppState,
+    router: Router,
+    relay: TestRelay,
+    fm_db_path: std::path::PathBuf,
+}
+
+impl Harness {
+    async fn start() -> Result<Self> {
+        let (relay, relay_url) = TestRelay::start().await?;
+        let fm_db_path = std::env::temp_dir().join(format!(
+            "replex-fm-{}",
+            hex::encode(rand::random::<[u8; 8]>())
+        ));
+        std::fs::create_dir_all(&fm_db_path)?;
+
+        let config = RawConfig {
+            public_base_url: Some(BASE_URL.to_string()),
+            admin_token: Some(SecretString::new(ADMIN_TOKEN.to_string())),
+            fm_db_path: Some(fm_db_path.clone()),
+            // never connected to, storage is in memory
+            database_url: Some(SecretString::new("postgres://localhost/unused".to_string())),
+            nostr_nsec: Some(SecretString::new(SecretKey::generate().to_bech32()?)),
+            nostr_relays: Some(vec![relay_url]),
+            dev: Some(true),
+            ..Default::default()
+        }
+        .validate()?;
+        let mm = MultiMint::new(fm_db_path.clone()).await?;
+        let state = AppState::with_storage(config, mm, Storage::memory()).await?;
+        let router = create_router(state.clone()).await?;
+
+        let harness = Self {
+            state,
+            router,
+            relay,
+            fm_db_path,
+        };
+        let connections = &harness.relay.connections;
+        harness
+            .wait_for("the server to connect to the relay", move || async move {
+                Ok((*connections.lock().unwrap() > 0).then_some(()))
+            })
+            .await?;
+        Ok(harness)
+    }
+
+    async fn register(&self, name: &str, keys: &Keys) -> Result<User> {
+        let user = UserForCreate::new(
+            name.to_string(),
+            rand::random::<u32>().to_string(),
+            "https://example.com/pic.png".to_string(),
+            keys.public_key().to_hex(),
+            vec![],
+            vec![hex::encode(rand::random::<[u8; 32]>())],
+        );
+        self.state.users.create(user).await
+    }
+
+    async fn request(&self, method: Method, uri: &str) -> Result<(StatusCode, Value)> {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+            .body(Body::empty())?;
+        let response = self.router.clone().oneshot(request).await?;
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        let json = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        Ok((status, json))
+    }
+
+    /// Sends `body` with a NIP-98 authorization signed by `keys`, whose
+    /// `payload` tag covers `signed_body` when given.
+    async fn nostr_request(
+        &self,
+        keys: &Keys,
+        method: Method,
+        uri: &str,
+        body: &Value,
+        signed_body: Option<&Value>,
+    ) -> Result<(StatusCode, Value)> {
+        let url = format!("{BASE_URL}{uri}");
+        let mut tags = vec![
+            Tag::parse(&["u", url.as_str()])?,
+            Tag::parse(&["method", method.as_str()])?,
+        ];
+        if let Some(signed_body) = signed_body {
+            let payload = sha256::Hash::hash(&serde_json::to_vec(signed_body)?).to_string();
+            tags.push(Tag::parse(&["payload", payload.as_str()])?);
+        }
+        let event = EventBuilder::new(Kind::HttpAuth, "", tags).to_event(keys)?;
+
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(
+                header::AUTHORIZATION,
+                format!("Nostr {}", BASE64.encode(event.as_json())),
+            )
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(body)?))?;
+        let response = self.router.clone().oneshot(request).await?;
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await?;
+        let json = serde_json::from_slice(&body).unwrap_or(Value::Null);
+        Ok((status, json))
+    }
+
+    async fn get(&self, uri: &str) -> Result<Value> {
+        let (status, json) = self.request(Method::GET, uri).await?;
+        ensure!(status == StatusCode::OK, "GET {uri}: {status}");
+        Ok(json)
+    }
+
+    async fn wait_for<T, F, Fut>(&self, what: &str, mut check: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = Result<Option<T>>>,
+    {
+        tokio::time::timeout(WAIT_TIMEOUT, async {
+            loop {
+                if let Some(value) = check().await? {
+                    return Ok(value);
+                }
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        })
+        .await
+        .map_err(|_| anyhow!("timed out waiting for {what}"))?
+    }
+
+    async fn wait_for_state(&self, op_id: &str, state: InvoiceState) -> Result<Invoice> {
+        let invoices = &self.state.invoices;
+        self.wait_for(
+            &format!("invoice {op_id} to be {state:?}"),
+            move || async move {
+                let invoice = invoices.get_by_op_id(op_id).await?;
+                Ok(invoice.filter(|invoice| invoice.state == state))
+            },
+        )
+        .await
+    }
+
+    async fn stop(self) {
+        self.state.shutdown.cancel();
+        self.state.tasks.close();
+        self.state.tasks.wait().await;
+        self.state.close().await;
+        let _ = std::fs::remove_dir_all(&self.fm_db_path);
+    }
+}
+
+/// Requests an invoice through the well-known endpoint and its callback,
+/// returning the callback response and the invoice's op id.
+async fn pay_request(harness: &Harness, username: &str, query: &str) -> Result<(Value, String)> {
+    let well_known = harness
+        .get(&format!("/.well-known/lnurlp/{username}"))
+        .await?;
+    let callback = well_known["callback"].as_str().context("callback")?;
+    let callback_path = callback
+        .strip_prefix(BASE_URL)
+        .context("callback under the public base url")?;
+    let response = harness.get(&format!("{callback_path}?{query}")).await?;
+    let verify = response["verify"].as_str().context("verify")?;
+    let op_id = verify.rsplit('/').next().context("op id")?.to_string();
+    Ok((response, op_id))
+}
+
+#[tokio::test]
+async fn pay_settle_and_notify() -> Result<()> {
+    let harness = Harness::start().await?;
+    let keys = Keys::generate();
+    let user = harness.register("alice", &keys).await?;
+
+    // LUD-06 pay request
+    let well_known = harness.get("/.well-known/lnurlp/alice").await?;
+    assert_eq!(well_known["tag"], "payRequest");
+    assert_eq!(
+        well_known["callback"],
+        format!("{BASE_URL}/lnurlp/alice/callback")
+    );
+    let min = well_known["minSendable"].as_u64().context("minSendable")?;
+    let max = well_known["maxSendable"].as_u64().context("maxSendable")?;
+    assert!(min <= 21_000 && 21_000 <= max);
+    assert!(well_known["metadata"].is_string());
+    assert_eq!(well_known["allowsNostr"], true);
+    assert_eq!(well_known["nostrPubkey"], user.pubkey);
+
+    // LUD-06 callback, with the LUD-21 verify url
+    let (callback, op_id) = pay_request(&harness, "alice", "amount=21000&comment=thanks").await?;
+    assert_eq!(callback["status"], "OK");
+    assert_eq!(callback["routes"], serde_json::json!([]));
+    assert_eq!(
+        callback["verify"],
+        format!("{BASE_URL}/lnurlp/alice/verify/{op_id}")
+    );
+    let pr = callback["pr"].as_str().context("pr")?;
+    let bolt11 = Bolt11Invoice::from_str(pr).map_err(|e| anyhow!("invalid pr: {e:?}"))?;
+    assert_eq!(bolt11.amount_milli_satoshis(), Some(21_000));
+
+    // LUD-21 verify before payment
+    let verify_path = format!("/lnurlp/alice/verify/{op_id}");
+    let verify = harness.get(&verify_path).await?;
+    assert_eq!(verify["status"], "OK");
+    assert_eq!(verify["settled"], false);
+    assert_eq!(verify["pr"], pr);
+    assert!(verify["preimage"].is_null());
+
+    let (status, _) = harness
+        .request(Method::POST, &format!("/admin/invoices/{op_id}/settle"))
+        .await?;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let invoice = harness
+        .wait_for_state(&op_id, InvoiceState::Settled)
+        .await?;
+    assert_eq!(invoice.tweak, 1);
+
+    let verify = harness.get(&verify_path).await?;
+    assert_eq!(verify["settled"], true);
+    assert_eq!(verify["pr"], pr);
+    let preimage = hex::decode(verify["preimage"].as_str().context("preimage")?)?;
+    assert_eq!(sha256::Hash::hash(&preimage), *bolt11.payment_hash());
+
+    let (relay, user_keys) = (&harness.relay, &keys);
+    let notification = harness
+        .wait_for("the payment notification", move || async move {
+            let notification = relay
+                .events()
+                .iter()
+                .filter(|event| event.kind == Kind::GiftWrap)
+                .find_map(|event| UnwrappedGift::from_gift_wrap(user_keys, event).ok())
+                .map(|gift| serde_json::from_str::<PaymentNotification>(&gift.rumor.content))
+                .transpose()?;
+            Ok(notification)
+        })
+        .await?;
+    assert_eq!(
+        notification.notification_type,
+        PaymentNotificationType::PaymentReceived
+    );
+    assert_eq!(notification.op_id, op_id);
+    assert_eq!(notification.amount_msats, 21_000);
+    assert_eq!(notification.tweak, invoice.tweak);
+    assert_eq!(notification.federation_id, user.federation_ids[0]);
+    assert_eq!(notification.comment.as_deref(), Some("thanks"));
+
+    // a settled invoice can't be paid twice
+    let (status, _) = harness
+        .request(Method::POST, &format!("/admin/invoices/{op_id}/settle"))
+        .await?;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    harness.stop().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn cancelled_invoice_stays_unsettled() -> Result<()> {
+    let harness = Harness::start().await?;
+    let keys = Keys::generate();
+    harness.register("bob", &keys).await?;
+
+    let (first, op_id) = pay_request(&harness, "bob", "amount=5000").await?;
+    let (status, _) = harness
+        .request(Method::POST, &format!("/admin/invoices/{op_id}/cancel"))
+        .await?;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    harness
+        .wait_for_state(&op_id, InvoiceState::Cancelled)
+        .await?;
+
+    let verify = harness.get(&format!("/lnurlp/bob/verify/{op_id}")).await?;
+    assert_eq!(verify["settled"], false);
+    assert!(verify["preimage"].is_null());
+
+    // the next request gets a fresh invoice on the next tweak
+    let (second, second_op_id) = pay_request(&harness, "bob", "amount=5000").await?;
+    assert_ne!(second_op_id, op_id);
+    assert_ne!(second["pr"], first["pr"]);
+    let invoice = harness
+        .wait_for_state(&second_op_id, InvoiceState::Pending)
+        .await?;
+    assert_eq!(invoice.tweak, 2);
+    assert!(harness
+        .relay
+        .events()
+        .iter()
+        .all(|e| e.kind != Kind::GiftWrap));
+
+    harness.stop().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn unknown_user_and_invoice() -> Result<()> {
+    let harness = Harness::start().await?;
+
+    let (status, _) = harness
+        .request(Method::GET, "/.well-known/lnurlp/nobody")
+        .await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = harness
+        .request(Method::GET, "/lnurlp/nobody/callback?amount=21000")
+        .await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let op_id = hex::encode([0u8; 32]);
+    let (status, _) = harness
+        .request(Method::GET, &format!("/lnurlp/nobody/verify/{op_id}"))
+        .await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = harness
+        .request(Method::POST, &format!("/admin/invoices/{op_id}/settle"))
+        .await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    harness.stop().await;
+    Ok(())
+}
+
+#[tokio::test]
+async fn nostr_auth_covers_the_body() -> Result<()> {
+    let harness = Harness::start().await?;
+    let keys = Keys::generate();
+    harness.register("carol", &keys).await?;
+    let path = "/users/me/success-action";
+
+    let action = json!({ "tag": "message", "message": "thanks" });
+    let (status, body) = harness
+        .nostr_request(&keys, Method::PUT, path, &action, Some(&action))
+        .await?;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, action);
+
+    // the same authorization replayed with another body
+    let other = json!({ "tag": "message", "message": "pay someone else" });
+    let (status, _) = harness
+        .nostr_request(&keys, Method::PUT, path, &other, Some(&action))
+        .await?;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    // a body needs a payload tag
+    let (status, _) = harness
+        .nostr_request(&keys, Method::PUT, path, &other, None)
+        .await?;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    harness.stop().await;
+    Ok(())
+}
@@ -1,29 +1,96 @@
 use anyhow::Result;
-use axum::routing::get;
+use axum::middleware;
+use axum::routing::{delete, get, post, put};
 use axum::Router;
+pub mod base_url;
 pub mod handlers;
+#[cfg(test)]
+mod lnurl_flow;
 
-use handlers::{handle_home, invoices, lnurlp};
+use handlers::{admin, auth, handle_home, health, invoices, lnurlp, lnurlw, metrics, nwc, users};
 
+use crate::rate_limit;
 use crate::state::AppState;
 
 pub async fn create_router(state: AppState) -> Result<Router> {
-    let app = Router::new()
-        .route("/", get(handle_home))
-        .route("/health", get(|| async { "OK" }))
-        .route("/invoices", get(invoices::handle_invoices))
+    let lnurl_routes = Router::new()
         .route(
             "/.well-known/lnurlp/:username",
             get(lnurlp::well_known::handle_well_known),
         )
         .route(
             "/lnurlp/:username/callback",
-            get(lnurlp::callback::handle_callback),
+            get(lnurlp::callback::handle_callback).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                rate_limit::limit_lnurl_callback,
+            )),
         )
         .route(
             "/lnurlp/:username/verify/:op_id",
             get(lnurlp::verify::handle_verify),
         )
+        .route(
+            "/lnurlp/:username/lnurl",
+            get(lnurlp::encoded::handle_lnurl),
+        )
+        .route("/lnurlp/:username/qr.svg", get(lnurlp::encoded::handle_qr))
+        .route(
+            "/lnurlw/:id",
+            get(lnurlw::withdraw_request::handle_withdraw_request),
+        )
+        .route(
+            "/lnurlw/:id/callback",
+            get(lnurlw::callback::handle_callback),
+        )
+        .route("/lnurlw/:id/lnurl", get(lnurlw::encoded::handle_lnurl))
+        .route("/lnurlw/:id/qr.svg", get(lnurlw::encoded::handle_qr))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            rate_limit::limit_lnurl,
+        ));
+
+    let app = Router::new()
+        .route("/", get(handle_home))
+        .route("/health", get(|| async { "OK" }))
+        .route("/health/ready", get(health::handle_ready))
+        .route("/metrics", get(metrics::handle_metrics))
+        .route("/invoices", get(invoices::handle_invoices))
+        .route(
+            "/invoices/unclaimed",
+            get(invoices::claim::handle_unclaimed),
+        )
+        .route("/invoices/claim", post(invoices::claim::handle_claim))
+        .route(
+            "/users/me/success-action",
+            put(users::handle_update_success_action),
+        )
+        .route("/lnurlw", post(lnurlw::create::handle_create))
+        .route(
+            "/nwc/connections",
+            get(nwc::handle_list_connections).post(nwc::handle_create_connection),
+        )
+        .route(
+            "/nwc/connections/:id",
+            delete(nwc::handle_revoke_connection),
+        )
+        .route(
+            "/admin/reconcile",
+            get(admin::handle_reconcile_report).post(admin::handle_reconcile),
+        )
+        .route(
+            "/admin/invoices/:op_id/timeline",
+            get(admin::handle_invoice_timeline),
+        )
+        .route(
+            "/admin/invoices/:op_id/settle",
+            post(admin::handle_settle_invoice),
+        )
+        .route(
+            "/admin/invoices/:op_id/cancel",
+            post(admin::handle_cancel_invoice),
+        )
+        .layer(middleware::from_fn(auth::hash_body))
+        .merge(lnurl_routes)
         .with_state(state);
 
     Ok(app)
@@ -0,0 +1,63 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout_at, Instant};
+use tracing::{error, info, warn};
+
+use crate::state::AppState;
+
+/// Resolves on ctrl-c or, on unix, SIGTERM.
+pub async fn wait_for_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for ctrl-c: {e}");
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {e}");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received ctrl-c"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
+
+/// Runs once `state.shutdown` is cancelled. The whole sequence shares one
+/// deadline so a stuck step can't hold up process exit past `timeout`.
+pub async fn drain(state: AppState, server: JoinHandle<()>, timeout: Duration) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+
+    info!("Draining in-flight requests");
+    if timeout_at(deadline, server).await.is_err() {
+        warn!("Timed out waiting for in-flight requests");
+    }
+
+    info!("Stopping background tasks");
+    state.tasks.close();
+    if timeout_at(deadline, state.tasks.wait()).await.is_err() {
+        warn!(
+            "Timed out waiting for {} background tasks",
+            state.tasks.len()
+        );
+    }
+
+    state.close().await;
+    info!("Shutdown complete");
+    Ok(())
+}
@@ -1,274 +1,896 @@
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 
 use anyhow::{Context, Result};
 use axum::http::StatusCode;
-use config::CONFIG;
-use futures::StreamExt;
+use chrono::Utc;
+use deadpool_postgres::Status;
+use futures::{stream::BoxStream, StreamExt};
 use multimint::{
-    fedimint_client::{oplog::UpdateStreamOrOutcome, ClientHandleArc},
-    fedimint_core::{config::FederationId, core::OperationId, secp256k1::PublicKey, Amount},
-    fedimint_ln_client::{LightningClientModule, LnReceiveState},
-    fedimint_ln_common::lightning_invoice::{Bolt11Invoice, Bolt11InvoiceDescription, Description},
+    fedimint_client::ClientHandleArc,
+    fedimint_core::{
+        bitcoin::Network, config::FederationId, core::OperationId, secp256k1::PublicKey, Amount,
+    },
+    fedimint_ln_client::{
+        InternalPayState, LightningClientModule, LnPayState, LnReceiveState, PayType,
+    },
+    fedimint_ln_common::lightning_invoice::Bolt11Invoice,
     MultiMint,
 };
 use nostr_sdk::secp256k1::{Parity, XOnlyPublicKey};
-use tokio::task::spawn;
-use tracing::{error, info};
+use tokio_postgres::error::SqlState;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{error, info, warn};
 
+#[cfg(feature = "sqlite")]
+use crate::model::sqlite::SqliteDb;
 use crate::{
-    config,
+    config::Config,
     error::AppError,
+    health::HealthMonitor,
+    lightning::{
+        CreatedInvoice, FakeLightning, FedimintLightning, InvoiceParams, LightningBackend,
+    },
+    metrics,
     model::{
+        invoice_events::InvoiceStage,
         invoices::{Invoice, InvoiceForCreate, InvoiceState},
+        repo::{InvoiceRepo, NwcConnectionRepo, UserRepo, WithdrawalRepo},
         users::User,
-        Db,
+        withdrawals::{Withdrawal, WithdrawalState},
+        Db, Storage,
     },
-    nostr::Nostr,
-    router::handlers::lnurlp::callback::LnurlCallbackParams,
+    nostr::{notification::PAYMENT_STATE_LIMIT, Nostr},
+    price_feed::{FiatQuote, PriceFeed, StaticPriceFeed},
+    rate_limit::RateLimiter,
+    reconcile::Reconciler,
 };
 
+/// Pending invoices read per query during startup recovery.
+const PENDING_BATCH_SIZE: i64 = 500;
+/// Subscriptions being set up at once during startup recovery.
+const PENDING_SUBSCRIBE_CONCURRENCY: usize = 32;
+
+/// Outcome of resubscribing pending invoices at startup.
+#[derive(Debug, Default)]
+pub struct PendingRecoveryReport {
+    pub total: usize,
+    pub resubscribed: usize,
+    /// Op ids that failed to resubscribe, with the error.
+    pub failed: Vec<(String, String)>,
+    /// Pending invoices per federation that has no registered client.
+    pub missing_federations: BTreeMap<String, usize>,
+    pub elapsed: Duration,
+}
+
+/// Everything needed to issue an invoice for a user, independent of how it was requested.
+#[derive(Debug, Clone)]
+pub struct InvoiceRequest {
+    pub amount_msats: u64,
+    pub comment: Option<String>,
+    /// Idempotency key; a pending invoice with the same key and amount is reused.
+    pub nonce: Option<String>,
+    pub fiat: Option<FiatQuote>,
+    /// Hex pubkey of the zap request author, passed on in notifications.
+    pub payer: Option<String>,
+}
+
+impl InvoiceRequest {
+    /// Fiat requests are matched on the fiat amount since the converted
+    /// msat amount moves with the rate.
+    fn matches(&self, invoice: &Invoice) -> bool {
+        match &self.fiat {
+            Some(quote) => {
+                invoice.fiat_amount == Some(quote.amount)
+                    && invoice.fiat_currency.as_deref() == Some(quote.currency.as_str())
+            }
+            None => {
+                invoice.fiat_amount.is_none()
+                    && u64::try_from(invoice.amount) == Ok(self.amount_msats)
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct AppState {
+    pub config: Arc<Config>,
     pub mm: MultiMint,
-    pub db: Db,
+    /// Only for health checks and pool stats; data goes through the repos.
+    /// `None` when users and invoices are kept in SQLite.
+    pub db: Option<Db>,
+    #[cfg(feature = "sqlite")]
+    pub sqlite: Option<SqliteDb>,
+    pub users: Arc<dyn UserRepo>,
+    pub invoices: Arc<dyn InvoiceRepo>,
+    /// Withdrawals and NWC connections are only stored in Postgres.
+    withdrawals: Option<Arc<dyn WithdrawalRepo>>,
+    nwc_connections: Option<Arc<dyn NwcConnectionRepo>>,
+    pub lightning: Arc<dyn LightningBackend>,
+    /// Set in dev mode, where the admin API settles and cancels its invoices.
+    pub fake_lightning: Option<FakeLightning>,
     pub nostr: Nostr,
+    pub health: HealthMonitor,
+    pub rate_limiter: RateLimiter,
+    /// Set when a price feed is configured; enables currency-denominated amounts.
+    pub price_feed: Option<Arc<dyn PriceFeed>>,
+    pub reconciler: Reconciler,
+    /// Op ids of invoices and withdrawals with a running subscription task.
+    monitored: Arc<Mutex<HashSet<String>>>,
+    /// Cancelled once on shutdown; long-running tasks stop at their next await point.
+    pub shutdown: CancellationToken,
+    /// Every background task, so shutdown can wait for them to finish.
+    pub tasks: TaskTracker,
 }
 
 impl AppState {
-    pub async fn new() -> Result<Self> {
-        let mut mm = MultiMint::new(CONFIG.fm_db_path.clone()).await?;
-        for invite_code in &CONFIG.federation_invite_codes {
-            match mm.register_new(invite_code.clone(), None).await {
-                Ok(_) => info!("Registered federation: {}", invite_code),
-                Err(e) => info!("federation already registered: {}", e),
+    pub async fn new(config: Config) -> Result<Self> {
+        let mut mm = MultiMint::new(config.fm_db_path.clone()).await?;
+        if config.dev {
+            warn!(
+                "Dev mode: invoices are issued by a fake lightning node, no federation is joined"
+            );
+        } else {
+            for invite_code in &config.federation_invite_codes {
+                // Clients joined with the mnemonic can be rebuilt with `--recover`;
+                // ones already in the DB keep the secret they were joined with.
+                match mm
+                    .register_new(
+                        invite_code.clone(),
+                        Some(config.mnemonic.expose().to_string()),
+                    )
+                    .await
+                {
+                    Ok(_) => info!("Registered federation: {}", invite_code),
+                    Err(e) => info!("federation already registered: {}", e),
+                }
             }
         }
-        let db = Db::new(CONFIG.pg_db.clone()).await?;
-        let nostr = Nostr::new(&CONFIG.nostr_nsec)?;
-
-        nostr.add_relays(&CONFIG.nostr_relays).await?;
-        nostr.client.connect().await;
-        db.setup_schema().await?;
-
-        Ok(Self { mm, db, nostr })
+        let storage = Storage::connect(config.database_url.expose()).await?;
+        Self::with_storage(config, mm, storage).await
     }
 
-    pub async fn handle_pending_invoices(&self) -> Result<()> {
-        let pending_invoices = self
-            .db
-            .invoices()
-            .get_by_state(InvoiceState::Pending)
-            .await?;
-        let pending_invoices_by_federation = self.group_invoices_by_federation(pending_invoices);
+    /// Everything after joining the federations and opening the database,
+    /// so tests can run on their own storage.
+    pub async fn with_storage(config: Config, mm: MultiMint, storage: Storage) -> Result<Self> {
+        let nostr = Nostr::new(
+            &config.nostr_signer,
+            config.nostr_relays.clone(),
+            config.nostr_max_user_relays,
+        )
+        .await?;
+        nostr.connect().await?;
 
-        for (federation_id, invoices) in pending_invoices_by_federation {
-            self.handle_federation_invoices(&federation_id, invoices)
-                .await?;
-        }
+        let rate_limiter = RateLimiter::new(config.rate_limit, storage.rate_limits);
+        let price_feed = match &config.price_feed_path {
+            Some(path) => Some(Arc::new(StaticPriceFeed::from_file(path)?) as Arc<dyn PriceFeed>),
+            None => None,
+        };
 
-        Ok(())
-    }
+        let fake_lightning = config.dev.then(FakeLightning::new);
+        let lightning: Arc<dyn LightningBackend> = match &fake_lightning {
+            Some(fake) => Arc::new(fake.clone()),
+            None => Arc::new(FedimintLightning::new(mm.clone())),
+        };
 
-    fn group_invoices_by_federation(
-        &self,
-        invoices: Vec<Invoice>,
-    ) -> HashMap<String, Vec<Invoice>> {
-        invoices
-            .into_iter()
-            .fold(HashMap::new(), |mut acc, invoice| {
-                acc.entry(invoice.federation_id.clone())
-                    .or_default()
-                    .push(invoice);
-                acc
-            })
+        Ok(Self {
+            config: Arc::new(config),
+            mm,
+            db: storage.postgres,
+            #[cfg(feature = "sqlite")]
+            sqlite: storage.sqlite,
+            users: storage.users,
+            invoices: storage.invoices,
+            withdrawals: storage.withdrawals,
+            nwc_connections: storage.nwc_connections,
+            lightning,
+            fake_lightning,
+            nostr,
+            health: HealthMonitor::new(),
+            rate_limiter,
+            price_feed,
+            reconciler: Reconciler::new(),
+            monitored: Default::default(),
+            shutdown: CancellationToken::new(),
+            tasks: TaskTracker::new(),
+        })
     }
 
-    async fn handle_federation_invoices(
-        &self,
-        federation_id: &str,
-        invoices: Vec<Invoice>,
-    ) -> Result<()> {
-        info!(
-            "Processing {} invoices for federation: {}",
-            invoices.len(),
-            federation_id
-        );
+    /// Releases external resources once background tasks have stopped.
+    pub async fn close(&self) {
+        if let Err(e) = self.nostr.client.disconnect().await {
+            error!("Failed to disconnect nostr client: {e}");
+        }
 
-        let federation_id =
-            FederationId::from_str(federation_id).context("Invalid federation ID")?;
+        let clients = std::mem::take(&mut *self.mm.clients.lock().await);
+        for (federation_id, client) in clients {
+            match Arc::try_unwrap(client) {
+                Ok(client) => {
+                    client.shutdown().await;
+                    info!("Shut down client for federation {}", federation_id);
+                }
+                // Remaining handles shut the client down when dropped.
+                Err(_) => info!(
+                    "Client for federation {} still in use, dropping handle",
+                    federation_id
+                ),
+            }
+        }
+    }
 
-        let client = self
-            .mm
-            .clients
-            .lock()
-            .await
-            .get(&federation_id)
-            .cloned()
-            .context("Client not found")?;
+    /// Resubscribes every pending invoice at startup. Rows are read in pages
+    /// and subscribed a bounded number at a time; a missing federation or a
+    /// failing invoice is recorded in the report without stopping the rest.
+    pub async fn handle_pending_invoices(&self) -> Result<PendingRecoveryReport> {
+        let started = Instant::now();
+        let invoice_db = &self.invoices;
+        let mut report = PendingRecoveryReport::default();
+        let mut federations: HashMap<String, bool> = HashMap::new();
+        let mut after_id = 0;
+
+        while !self.shutdown.is_cancelled() {
+            let batch = invoice_db
+                .get_page_by_state(InvoiceState::Pending, after_id, PENDING_BATCH_SIZE)
+                .await?;
+            let Some(last) = batch.last() else {
+                break;
+            };
+            after_id = last.id;
+            let batch_len = batch.len();
+            report.total += batch_len;
+
+            let mut subscriptions = Vec::with_capacity(batch_len);
+            for invoice in batch {
+                if !federations.contains_key(&invoice.federation_id) {
+                    let available = match FederationId::from_str(&invoice.federation_id) {
+                        Ok(federation_id) => self.lightning.has_federation(&federation_id).await,
+                        Err(_) => false,
+                    };
+                    if !available {
+                        warn!(
+                            "Skipping pending invoices of unavailable federation {}",
+                            invoice.federation_id
+                        );
+                    }
+                    federations.insert(invoice.federation_id.clone(), available);
+                }
+                if federations[&invoice.federation_id] {
+                    subscriptions.push(invoice);
+                } else {
+                    *report
+                        .missing_federations
+                        .entry(invoice.federation_id)
+                        .or_default() += 1;
+                }
+            }
 
-        let ln = client.get_first_module::<LightningClientModule>();
+            let results: Vec<(String, Result<()>)> = futures::stream::iter(subscriptions)
+                .map(|invoice| async move {
+                    let op_id = invoice.op_id.clone();
+                    (op_id, self.subscribe_to_invoice(invoice).await)
+                })
+                .buffer_unordered(PENDING_SUBSCRIBE_CONCURRENCY)
+                .collect()
+                .await;
+            for (op_id, result) in results {
+                match result {
+                    Ok(()) => report.resubscribed += 1,
+                    Err(e) => {
+                        warn!("Failed to resubscribe invoice {}: {:#}", op_id, e);
+                        report.failed.push((op_id, format!("{e:#}")));
+                    }
+                }
+            }
+            info!(
+                "Resubscribed {} of {} pending invoices so far",
+                report.resubscribed, report.total
+            );
 
-        for invoice in invoices {
-            self.subscribe_to_invoice(&ln, invoice).await?;
+            if (batch_len as i64) < PENDING_BATCH_SIZE {
+                break;
+            }
         }
 
-        Ok(())
+        report.elapsed = started.elapsed();
+        info!(
+            "Pending invoice recovery finished in {:.1}s: {} resubscribed, {} failed, {} in unregistered federations",
+            report.elapsed.as_secs_f64(),
+            report.resubscribed,
+            report.failed.len(),
+            report.missing_federations.values().sum::<usize>()
+        );
+        for (federation_id, count) in &report.missing_federations {
+            error!(
+                "{} pending invoices belong to unregistered federation {}",
+                count, federation_id
+            );
+        }
+        Ok(report)
     }
 
-    pub async fn subscribe_to_invoice(
-        &self,
-        ln: &LightningClientModule,
-        invoice: Invoice,
-    ) -> Result<()> {
+    pub async fn subscribe_to_invoice(&self, invoice: Invoice) -> Result<()> {
+        let federation_id =
+            FederationId::from_str(&invoice.federation_id).context("Invalid federation ID")?;
         let op_id = invoice.op_id.parse().context("Invalid op_id")?;
-        let subscription = ln
-            .subscribe_ln_receive(op_id)
-            .await
-            .context("Failed to subscribe to invoice")?;
+        if let Some(fake) = &self.fake_lightning {
+            fake.restore(op_id, &invoice.bolt11);
+        }
+        let updates = self
+            .lightning
+            .subscribe_receive(&federation_id, op_id)
+            .await?;
 
-        self.spawn_invoice_subscription(invoice, subscription).await
+        self.spawn_invoice_subscription(invoice, updates).await
     }
 
     async fn spawn_invoice_subscription(
         &self,
         invoice: Invoice,
-        subscription: UpdateStreamOrOutcome<LnReceiveState>,
+        mut stream: BoxStream<'static, LnReceiveState>,
     ) -> Result<()> {
-        let invoice_db = self.db.invoices().clone();
+        let users = self.users.clone();
+        let invoices = self.invoices.clone();
         let nostr = self.nostr.clone();
+        let publish_payment_state = self.config.publish_payment_state;
+        let shutdown = self.shutdown.clone();
+        let monitored = self.monitored.clone();
+        monitored.lock().unwrap().insert(invoice.op_id.clone());
 
-        spawn(async move {
+        self.tasks.spawn(async move {
             info!("Monitoring invoice: {}", invoice.op_id);
-            let mut stream = subscription.into_stream();
-            while let Some(op_state) = stream.next().await {
+            metrics::ACTIVE_SUBSCRIPTIONS.inc();
+            loop {
+                // Each update is persisted before the next one is awaited, so
+                // stopping here never interrupts a state write.
+                let op_state = tokio::select! {
+                    _ = shutdown.cancelled() => {
+                        info!("Stopped monitoring invoice {} for shutdown", invoice.op_id);
+                        break;
+                    }
+                    op_state = stream.next() => match op_state {
+                        Some(op_state) => op_state,
+                        None => break,
+                    },
+                };
+                // Only the task that recorded the outcome reports it, so a
+                // duplicate or resubscribed task doesn't notify twice.
+                let recorded = persist_receive_state(&*invoices, invoice.id, &op_state)
+                    .await
+                    .unwrap_or_else(|e| {
+                        error!("Failed to update invoice state: {}", e);
+                        false
+                    });
                 match op_state {
                     LnReceiveState::Canceled { reason } => {
                         error!("Invoice {} canceled: {:?}", invoice.op_id, reason);
-                        if let Err(e) = invoice_db
-                            .update_state(invoice.id, InvoiceState::Cancelled)
-                            .await
-                        {
-                            error!("Failed to update invoice state: {}", e);
-                        }
+                        metrics::INVOICES_CANCELLED
+                            .with_label_values(&[&invoice.federation_id])
+                            .inc();
                     }
                     LnReceiveState::Claimed => {
                         info!("Invoice {} claimed", invoice.op_id);
-                        if let Err(e) = invoice_db
-                            .update_state(invoice.id, InvoiceState::Settled)
-                            .await
+                        if !recorded {
+                            break;
+                        }
+                        metrics::INVOICES_SETTLED
+                            .with_label_values(&[&invoice.federation_id])
+                            .inc();
+                        // From creation, so resubscribed invoices count the
+                        // time before a restart too.
+                        if let Some(latency) = invoice
+                            .created_at
+                            .and_then(|created_at| (Utc::now() - created_at).to_std().ok())
                         {
-                            error!("Failed to update invoice state: {}", e);
+                            metrics::SETTLE_LATENCY
+                                .with_label_values(&[&invoice.federation_id])
+                                .observe(latency.as_secs_f64());
                         }
-                        if let Err(e) = nostr.notify_user_invoice_settled(invoice).await {
+                        if let Err(e) = notify_settled(
+                            &*users,
+                            &*invoices,
+                            &nostr,
+                            publish_payment_state,
+                            &invoice,
+                        )
+                        .await
+                        {
                             error!("Failed to notify user of settled invoice: {}", e);
+                            metrics::NOSTR_DM_FAILURES.inc();
                         }
                         break;
                     }
                     _ => {}
                 }
             }
+            metrics::ACTIVE_SUBSCRIPTIONS.dec();
+            monitored.lock().unwrap().remove(&invoice.op_id);
         });
 
         Ok(())
     }
 
+    pub fn is_monitored(&self, op_id: &str) -> bool {
+        self.monitored.lock().unwrap().contains(op_id)
+    }
+
+    /// Notifies the user of an invoice found settled outside its subscription.
+    pub async fn notify_settled(&self, invoice: &Invoice) {
+        if let Err(e) = notify_settled(
+            &*self.users,
+            &*self.invoices,
+            &self.nostr,
+            self.config.publish_payment_state,
+            invoice,
+        )
+        .await
+        {
+            error!("Failed to notify user of settled invoice: {}", e);
+            metrics::NOSTR_DM_FAILURES.inc();
+        }
+    }
+
     async fn create_invoice_for_user_tweaked(
-        ln: &LightningClientModule,
-        params: &LnurlCallbackParams,
+        &self,
+        federation_id: &FederationId,
+        request: &InvoiceRequest,
         user: &User,
         tweak: i64,
-    ) -> Result<(OperationId, Bolt11Invoice, [u8; 32])> {
-        let gateways = ln.list_gateways().await;
-        let gateway = gateways.first().unwrap();
+    ) -> Result<CreatedInvoice> {
         let xonly_pubkey = XOnlyPublicKey::from_str(&user.pubkey)?;
         let pubkey = PublicKey::from_str(&xonly_pubkey.public_key(Parity::Even).to_string())?;
-        ln.create_bolt11_invoice_for_user_tweaked(
-            Amount {
-                msats: params.amount,
+        let params = InvoiceParams {
+            amount: Amount {
+                msats: request.amount_msats,
             },
-            Bolt11InvoiceDescription::Direct(&Description::new(
-                params
-                    .comment
-                    .clone()
-                    .unwrap_or_else(|| "hermes address payment".to_string()),
-            )?),
-            None,
-            pubkey,
-            tweak as u64,
-            (),
-            Some(gateway.info.clone()),
-        )
-        .await
+            description: request
+                .comment
+                .clone()
+                .unwrap_or_else(|| "hermes address payment".to_string()),
+            user_key: pubkey,
+            tweak: tweak as u64,
+        };
+        self.lightning.create_invoice(federation_id, params).await
     }
 
     pub async fn create_invoice_store_and_notify(
         &self,
-        ln: &LightningClientModule,
         user: &User,
-        params: &LnurlCallbackParams,
+        request: &InvoiceRequest,
         federation_id: FederationId,
     ) -> Result<(OperationId, Invoice)> {
+        let nonce = &request.nonce;
+        if let Some(nonce) = nonce {
+            if let Some(existing) = self.get_pending_by_nonce(user, request, nonce).await? {
+                info!(
+                    "Reusing invoice {} for repeated callback nonce {}",
+                    existing.op_id, nonce
+                );
+                return Ok((existing.op_id.parse()?, existing));
+            }
+            self.invoices
+                .release_stale_nonce(
+                    user.id,
+                    nonce,
+                    self.config.callback_idempotency_window.as_secs_f64(),
+                )
+                .await?;
+        }
+
         let mut tweak = user.last_tweak + 1;
-        let (op_id, invoice, _) = loop {
-            match Self::create_invoice_for_user_tweaked(ln, params, user, tweak).await {
+        let CreatedInvoice {
+            op_id,
+            invoice,
+            preimage,
+        } = loop {
+            match self
+                .create_invoice_for_user_tweaked(&federation_id, request, user, tweak)
+                .await
+            {
                 Ok(result) => break result,
                 Err(e) if e.to_string().contains("already exists") => {
                     info!("Invoice already exists, trying next tweak");
+                    metrics::TWEAK_COLLISIONS.inc();
                     tweak += 1;
                     continue;
                 }
                 Err(e) => return Err(e),
             }
         };
-        self.db.users().update_tweak(user, tweak).await?;
-
-        let stored_invoice = self
-            .db
-            .invoices()
-            .create(InvoiceForCreate {
-                op_id: op_id.fmt_full().to_string(),
-                federation_id: federation_id.to_string(),
-                user_id: user.id,
-                user_pubkey: user.pubkey.clone(),
-                amount: params.amount as i64,
-                bolt11: invoice.to_string(),
-                tweak,
-                state: InvoiceState::Pending,
-            })
-            .await?;
+        let invoice_for_create = InvoiceForCreate {
+            op_id: op_id.fmt_full().to_string(),
+            federation_id: federation_id.to_string(),
+            user_id: user.id,
+            user_pubkey: user.pubkey.clone(),
+            amount: i64::try_from(request.amount_msats).context("Amount too large")?,
+            bolt11: invoice.to_string(),
+            tweak,
+            state: InvoiceState::Pending,
+            nonce: nonce.clone(),
+            fiat_amount: request.fiat.as_ref().map(|q| q.amount),
+            fiat_currency: request.fiat.as_ref().map(|q| q.currency.clone()),
+            fiat_rate: request.fiat.as_ref().map(|q| q.multiplier),
+            preimage: Some(hex::encode(preimage)),
+            comment: request.comment.clone(),
+            payer: request.payer.clone(),
+            payment_hash: Some(invoice.payment_hash().to_string()),
+        };
+        let created = self.invoices.create_with_tweak(invoice_for_create).await;
+        let stored_invoice = match (created, nonce) {
+            (Ok(stored_invoice), _) => stored_invoice,
+            // A concurrent retry stored its invoice first; the operation
+            // created above is left to expire.
+            (Err(e), Some(nonce)) if is_unique_violation(&e) => {
+                let existing = self
+                    .get_pending_by_nonce(user, request, nonce)
+                    .await?
+                    .context("Invoice for nonce disappeared")?;
+                info!(
+                    "Reusing invoice {} for concurrent callback nonce {}",
+                    existing.op_id, nonce
+                );
+                return Ok((existing.op_id.parse()?, existing));
+            }
+            (Err(e), _) => return Err(e),
+        };
+        self.health.record_invoice_created(&federation_id).await;
+        metrics::INVOICES_CREATED
+            .with_label_values(&[&stored_invoice.federation_id])
+            .inc();
 
-        self.subscribe_to_invoice(ln, stored_invoice.clone())
-            .await?;
+        self.subscribe_to_invoice(stored_invoice.clone()).await?;
 
         Ok((op_id, stored_invoice))
     }
 
-    pub async fn get_federation_and_client(
+    async fn get_pending_by_nonce(
         &self,
         user: &User,
-    ) -> Result<(FederationId, ClientHandleArc), AppError> {
-        info!("Getting federation and client for user: {}", user.name);
+        request: &InvoiceRequest,
+        nonce: &str,
+    ) -> Result<Option<Invoice>> {
+        let candidates = self
+            .invoices
+            .get_pending_by_nonce(
+                user.id,
+                nonce,
+                self.config.callback_idempotency_window.as_secs_f64(),
+            )
+            .await?;
+        Ok(candidates
+            .into_iter()
+            .find(|invoice| request.matches(invoice)))
+    }
+
+    /// Usage of the pool users and invoices are stored through.
+    pub fn pool_status(&self) -> Option<Status> {
+        #[cfg(feature = "sqlite")]
+        if let Some(db) = &self.sqlite {
+            return Some(db.pool_status());
+        }
+        self.db.as_ref().map(Db::pool_status)
+    }
+
+    /// Withdrawal storage, for features with no SQLite storage.
+    pub fn withdrawals(&self) -> Result<Arc<dyn WithdrawalRepo>, AppError> {
+        self.withdrawals.clone().ok_or_else(needs_postgres)
+    }
+
+    /// NWC connection storage, for features with no SQLite storage.
+    pub fn nwc_connections(&self) -> Result<Arc<dyn NwcConnectionRepo>, AppError> {
+        self.nwc_connections.clone().ok_or_else(needs_postgres)
+    }
+
+    /// Resumes tracking withdrawals whose payment was in flight at shutdown.
+    /// A withdrawal that can't be resumed is logged and left for the
+    /// reconciler without holding up the others.
+    pub async fn handle_pending_withdrawals(&self) -> Result<()> {
+        let Some(withdrawals) = &self.withdrawals else {
+            return Ok(());
+        };
+        let pending = withdrawals.get_by_state(WithdrawalState::Pending).await?;
+        for withdrawal in pending {
+            let id = withdrawal.id.clone();
+            if let Err(e) = self.resume_withdrawal(&**withdrawals, withdrawal).await {
+                error!("Failed to resume withdrawal {}: {}", id, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn resume_withdrawal(
+        &self,
+        withdrawals: &dyn WithdrawalRepo,
+        withdrawal: Withdrawal,
+    ) -> Result<()> {
+        if withdrawal.op_id.is_none() {
+            // The payment may or may not have been made, so it is never
+            // retried; the reconciler looks for it in the operation log.
+            warn!(
+                "Withdrawal {} has no payment operation, leaving it to the reconciler",
+                withdrawal.id
+            );
+            return withdrawals
+                .update_payment_state(&withdrawal.id, WithdrawalState::PaymentUnknown)
+                .await;
+        }
+        self.subscribe_to_withdrawal(withdrawal).await
+    }
+
+    /// Pays a claimed withdrawal's invoice from the server's ecash and tracks
+    /// the payment in the background. Only if the payment couldn't be made is
+    /// the link released for another attempt; once it was made, the link
+    /// stays used up whatever happens to it.
+    pub async fn pay_withdrawal(
+        &self,
+        withdrawal: Withdrawal,
+        invoice: Bolt11Invoice,
+    ) -> Result<()> {
+        let withdrawals = self.withdrawals().map_err(|e| e.error)?;
+        let payment = async {
+            let client = self.get_client(&withdrawal.federation_id).await?;
+            let ln = client.get_first_module::<LightningClientModule>();
+            let gateway = ln.list_gateways().await.first().map(|g| g.info.clone());
+            ln.pay_bolt11_invoice(gateway, invoice, ()).await
+        }
+        .await;
+        let payment = match payment {
+            Ok(payment) => payment,
+            Err(e) => {
+                error!("Withdrawal {} payment failed: {}", withdrawal.id, e);
+                withdrawals.release(&withdrawal.id).await?;
+                return Err(e);
+            }
+        };
+
+        let (op_id, internal) = match payment.payment_type {
+            PayType::Lightning(op_id) => (op_id, false),
+            PayType::Internal(op_id) => (op_id, true),
+        };
+        let op_id = op_id.fmt_full().to_string();
+        let withdrawal = Withdrawal {
+            op_id: Some(op_id.clone()),
+            internal,
+            ..withdrawal
+        };
+        let tracked = async {
+            withdrawals
+                .set_operation(&withdrawal.id, &op_id, internal)
+                .await?;
+            self.subscribe_to_withdrawal(withdrawal.clone()).await
+        }
+        .await;
+        if let Err(e) = tracked {
+            error!(
+                "Withdrawal {} was paid with operation {} but can't be tracked: {}",
+                withdrawal.id, op_id, e
+            );
+            if let Err(e) = withdrawals
+                .update_payment_state(&withdrawal.id, WithdrawalState::PaymentUnknown)
+                .await
+            {
+                error!("Failed to update withdrawal state: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Follows a withdrawal's payment operation until it is final, unless it
+    /// is followed already.
+    pub async fn subscribe_to_withdrawal(&self, withdrawal: Withdrawal) -> Result<()> {
+        let op_id_str = withdrawal
+            .op_id
+            .clone()
+            .context("Withdrawal has no operation")?;
+        if self.is_monitored(&op_id_str) {
+            return Ok(());
+        }
+        let op_id = op_id_str.parse().context("Invalid op_id")?;
+        let client = self.get_client(&withdrawal.federation_id).await?;
+        let ln = client.get_first_module::<LightningClientModule>();
+        // Only final updates are of interest, mapped to the state they settle on.
+        let mut outcomes: BoxStream<'static, WithdrawalState> = if withdrawal.internal {
+            ln.subscribe_internal_pay(op_id)
+                .await?
+                .into_stream()
+                .filter_map(|update| async move { internal_pay_outcome(&update) })
+                .boxed()
+        } else {
+            ln.subscribe_ln_pay(op_id)
+                .await?
+                .into_stream()
+                .filter_map(|update| async move { ln_pay_outcome(&update) })
+                .boxed()
+        };
+
+        let withdrawal_db = self.withdrawals().map_err(|e| e.error)?;
+        let monitored = self.monitored.clone();
+        if !monitored.lock().unwrap().insert(op_id_str.clone()) {
+            return Ok(());
+        }
+        let shutdown = self.shutdown.clone();
+        self.tasks.spawn(async move {
+            info!("Monitoring withdrawal: {}", withdrawal.id);
+            let outcome = tokio::select! {
+                _ = shutdown.cancelled() => {
+                    info!("Stopped monitoring withdrawal {} for shutdown", withdrawal.id);
+                    None
+                }
+                outcome = outcomes.next() => outcome,
+            };
+            if let Some(state) = outcome {
+                info!("Withdrawal {} is {:?}", withdrawal.id, state);
+                if let Err(e) = withdrawal_db
+                    .update_payment_state(&withdrawal.id, state)
+                    .await
+                {
+                    error!("Failed to update withdrawal state: {}", e);
+                }
+            }
+            monitored.lock().unwrap().remove(&op_id_str);
+        });
+
+        Ok(())
+    }
+
+    /// The network invoices paid from `federation_id` must be for.
+    pub async fn federation_network(&self, federation_id: &str) -> Result<Network> {
+        let client = self.get_client(federation_id).await?;
+        let ln = client.get_first_module::<LightningClientModule>();
+        Ok(ln.cfg.network)
+    }
 
-        let federation_id = FederationId::from_str(&user.federation_ids[0]).map_err(|e| {
-            let error_msg = format!("Invalid federation_id for user {}: {}", user.name, e);
-            tracing::error!("{}", error_msg);
-            AppError::new(StatusCode::BAD_REQUEST, anyhow::anyhow!(error_msg))
-        })?;
+    pub async fn get_client(&self, federation_id: &str) -> Result<ClientHandleArc> {
+        let federation_id =
+            FederationId::from_str(federation_id).context("Invalid federation ID")?;
+        self.mm
+            .clients
+            .lock()
+            .await
+            .get(&federation_id)
+            .cloned()
+            .context("Client not found")
+    }
 
-        info!("Federation ID parsed: {:?}", federation_id);
+    /// Picks the first of the user's federations the lightning backend can
+    /// receive in and the health monitor doesn't report unhealthy.
+    pub async fn get_federation(&self, user: &User) -> Result<FederationId, AppError> {
+        info!("Getting federation for user: {}", user.name);
+
+        for federation_id in &user.federation_ids {
+            let federation_id = FederationId::from_str(federation_id).map_err(|e| {
+                let error_msg = format!("Invalid federation_id for user {}: {}", user.name, e);
+                tracing::error!("{}", error_msg);
+                AppError::new(StatusCode::BAD_REQUEST, anyhow::anyhow!(error_msg))
+            })?;
+
+            if !self.health.is_federation_usable(&federation_id).await {
+                info!("Skipping unhealthy federation: {:?}", federation_id);
+                continue;
+            }
 
-        let locked_clients = self.mm.clients.lock().await;
-        let client = locked_clients.get(&federation_id).cloned().ok_or_else(|| {
-            let error_msg = format!(
-                "FederationId {:?} not found in multimint map",
+            if self.lightning.has_federation(&federation_id).await {
+                info!("Using federation ID: {:?}", federation_id);
+                return Ok(federation_id);
+            }
+            tracing::error!(
+                "FederationId {:?} not available from the lightning backend",
                 federation_id
             );
-            tracing::error!("{}", error_msg);
-            AppError::new(StatusCode::BAD_REQUEST, anyhow::anyhow!(error_msg))
-        })?;
+        }
 
-        info!("Client found for federation ID: {:?}", federation_id);
+        let error_msg = format!("No healthy federation available for user {}", user.name);
+        tracing::error!("{}", error_msg);
+        Err(AppError::new(
+            StatusCode::SERVICE_UNAVAILABLE,
+            anyhow::anyhow!(error_msg),
+        ))
+    }
 
+    /// Like [`Self::get_federation`], for paying out of the server's ecash,
+    /// which needs the federation's client.
+    pub async fn get_federation_and_client(
+        &self,
+        user: &User,
+    ) -> Result<(FederationId, ClientHandleArc), AppError> {
+        let federation_id = self.get_federation(user).await?;
+        let client = self
+            .get_client(&federation_id.to_string())
+            .await
+            .map_err(|e| AppError::new(StatusCode::SERVICE_UNAVAILABLE, e))?;
         Ok((federation_id, client))
     }
 }
+
+/// Sends the payment DM to the user's and the server's relays, then refreshes
+/// the user's resync event when enabled.
+async fn notify_settled(
+    users: &dyn UserRepo,
+    invoices: &dyn InvoiceRepo,
+    nostr: &Nostr,
+    publish_payment_state: bool,
+    invoice: &Invoice,
+) -> Result<()> {
+    let relays = users
+        .get(invoice.user_id)
+        .await?
+        .map(|user| user.relays)
+        .unwrap_or_default();
+    nostr.notify_payment(invoice, &relays).await?;
+
+    if publish_payment_state {
+        let recent = invoices
+            .get_recent_settled(&invoice.user_pubkey, PAYMENT_STATE_LIMIT)
+            .await?;
+        nostr
+            .publish_payment_state(&invoice.user_pubkey, &recent, &relays)
+            .await?;
+    }
+    Ok(())
+}
+
+/// Records a receive update as an invoice event, and final ones as the
+/// invoice's state along with it.
+async fn persist_receive_state(
+    invoices: &dyn InvoiceRepo,
+    invoice_id: i32,
+    op_state: &LnReceiveState,
+) -> Result<bool> {
+    let stage = InvoiceStage::from_receive_state(op_state);
+    let reason = format!("{op_state:?}");
+    let state = match op_state {
+        LnReceiveState::Claimed => Some(InvoiceState::Settled),
+        LnReceiveState::Canceled { .. } => Some(InvoiceState::Cancelled),
+        _ => None,
+    };
+    invoices
+        .record_event(invoice_id, stage, Some(&reason), state)
+        .await
+}
+
+fn needs_postgres() -> AppError {
+    AppError::new(
+        StatusCode::NOT_IMPLEMENTED,
+        anyhow::anyhow!("Not available without a Postgres database"),
+    )
+}
+
+fn is_unique_violation(error: &anyhow::Error) -> bool {
+    #[cfg(feature = "sqlite")]
+    if let Some(rusqlite::Error::SqliteFailure(e, _)) = error.downcast_ref::<rusqlite::Error>() {
+        return e.extended_code == rusqlite::ffi::SQLITE_CONSTRAINT_UNIQUE;
+    }
+    error
+        .downcast_ref::<tokio_postgres::Error>()
+        .and_then(|e| e.code())
+        .is_some_and(|code| *code == SqlState::UNIQUE_VIOLATION)
+}
+
+/// The state a withdrawal settles on after a gateway payment update, if it
+/// is final.
+pub fn ln_pay_outcome(update: &LnPayState) -> Option<WithdrawalState> {
+    match update {
+        LnPayState::Success { .. } => Some(WithdrawalState::Paid),
+        LnPayState::Canceled | LnPayState::Refunded { .. } => Some(WithdrawalState::PaymentFailed),
+        LnPayState::UnexpectedError { .. } => Some(WithdrawalState::PaymentUnknown),
+        _ => None,
+    }
+}
+
+/// The state a withdrawal settles on after an in-federation payment update,
+/// if it is final.
+pub fn internal_pay_outcome(update: &InternalPayState) -> Option<WithdrawalState> {
+    match update {
+        InternalPayState::Preimage(_) => Some(WithdrawalState::Paid),
+        InternalPayState::RefundSuccess { .. } | InternalPayState::FundingFailed { .. } => {
+            Some(WithdrawalState::PaymentFailed)
+        }
+        InternalPayState::RefundError { .. } | InternalPayState::UnexpectedError(_) => {
+            Some(WithdrawalState::PaymentUnknown)
+        }
+        InternalPayState::Funding => None,
+    }
+}